@@ -25,19 +25,19 @@ use std::env::args;
 use std::path::PathBuf;
 use subql_proxy_utils::{
     p2p::{libp2p::identity::Keypair, server::server, P2pHandler, Request, Response},
-    payg::{convert_sign_to_bytes, default_sign, OpenState, QueryState},
+    payg::{
+        build_challenge_tx, build_checkpoint_tx, build_claim_tx, build_respond_tx, default_sign, GasPrice, OpenState,
+        QueryState,
+    },
     request::{jsonrpc_request, proxy_request},
 };
 use web3::{
     api::Eth,
-    contract::{
-        tokens::{Tokenizable, Tokenize},
-        Contract, Options,
-    },
-    ethabi::{encode, Token},
-    signing::{keccak256, Key, SecretKeyRef, Signature},
+    contract::{Contract, Options},
+    ethabi::Token,
+    signing::{Key, SecretKeyRef, Signature},
     transports::Http,
-    types::{Address, Bytes, TransactionParameters, U256},
+    types::{Address, CallRequest, TransactionParameters, U256},
     Web3,
 };
 
@@ -107,6 +107,22 @@ fn build_contracts(eth: Eth<Http>, list: Value) -> HashMap<&'static str, Contrac
     contracts
 }
 
+/// The query state of channel, to build the state channel contract transactions.
+fn query_state(state: &StateChannel) -> QueryState {
+    let copy = |s: &Signature| Signature { v: s.v, r: s.r, s: s.s };
+    QueryState {
+        channel_id: state.id,
+        indexer: state.indexer,
+        consumer: state.consumer,
+        count: state.count,
+        price: state.last_price,
+        is_final: state.last_final,
+        indexer_sign: copy(&state.last_indexer_sign),
+        consumer_sign: copy(&state.last_consumer_sign),
+        next_price: state.last_price,
+    }
+}
+
 async fn send_state(
     web3: &Web3<Http>,
     cotract: &Contract<Http>,
@@ -114,49 +130,36 @@ async fn send_state(
     method: &str,
     secret: &SecretKey,
 ) {
-    let msg = encode(&[
-        state.id.into_token(),
-        state.count.into_token(),
-        state.last_price.into_token(),
-        state.last_final.into_token(),
-    ]);
-    let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
-    bytes.extend(keccak256(&msg));
-    let _payload = keccak256(&bytes);
-
-    // TODO check sign.
-    //let (i_sign, i_id) = convert_recovery_sign(&indexer_sign);
-    //let address = recover(&payload, &i_sign, i_id);
-    //println!("Recover {:?}", address);
-
-    let call_params = Token::Tuple(vec![
-        state.id.into_token(),
-        state.last_final.into_token(),
-        state.count.into_token(),
-        state.last_price.into_token(),
-        convert_sign_to_bytes(&state.last_indexer_sign).into_token(),
-        convert_sign_to_bytes(&state.last_consumer_sign).into_token(),
-    ]);
-    let call_tokens = (call_params.clone(),).into_tokens();
-    let fn_data = cotract
-        .abi()
-        .function(method)
-        .and_then(|function| function.encode_input(&call_tokens))
-        .unwrap();
-    let gas = cotract
-        .estimate_gas(method, (call_params,), state.consumer, Default::default())
-        .await
-        .unwrap();
-
-    let tx = TransactionParameters {
-        to: Some(cotract.address()),
-        data: Bytes(fn_data),
-        gas: gas,
+    let query = query_state(state);
+    let tx = match method {
+        "checkpoint" => build_checkpoint_tx(&query, cotract.address()),
+        "challenge" => build_challenge_tx(&query, cotract.address()),
+        _ => build_respond_tx(&query, cotract.address()),
+    };
+    if let Err(err) = send_tx(web3, tx, state.consumer, secret).await {
+        println!("\x1b[91m>>> {} failure: {}\x1b[00m", method, err);
+    }
+}
+
+/// Estimate the gas of the unsigned transaction, sign and send it.
+async fn send_tx(
+    web3: &Web3<Http>,
+    mut tx: TransactionParameters,
+    from: Address,
+    secret: &SecretKey,
+) -> web3::Result<()> {
+    let call = CallRequest {
+        from: Some(from),
+        to: tx.to,
+        data: Some(tx.data.clone()),
         ..Default::default()
     };
-    let signed = web3.accounts().sign_transaction(tx, secret).await.unwrap();
-    let tx_hash = web3.eth().send_raw_transaction(signed.raw_transaction).await.unwrap();
+    tx.gas = web3.eth().estimate_gas(call, None).await?;
+    GasPrice::default().apply(web3, &mut tx).await?;
+    let signed = web3.accounts().sign_transaction(tx, secret).await?;
+    let tx_hash = web3.eth().send_raw_transaction(signed.raw_transaction).await?;
     println!("\x1b[94m>>> TxHash: {:?}\x1b[00m", tx_hash);
+    Ok(())
 }
 
 const PROXY_URL: &'static str = "http://127.0.0.1:8003";
@@ -229,7 +232,9 @@ async fn main() {
                 "127.0.0.1:7777".parse().unwrap(),
                 None,
                 None,
+                None,
                 p2p_key,
+                vec![],
             )
             .await
             .unwrap();
@@ -378,7 +383,10 @@ async fn main() {
                         let indexer: Address = next_params.next().unwrap().parse().unwrap();
                         let amount = U256::from_dec_str(next_params.next().unwrap()).unwrap();
                         let expiration = U256::from_dec_str(next_params.next().unwrap()).unwrap();
-                        let deployment_id = bs58::decode(default_project).into_vec().unwrap();
+                        // the sha2-256 digest of CIDv0, without the multihash prefix.
+                        let multihash = bs58::decode(default_project).into_vec().unwrap();
+                        let mut deployment_id = [0u8; 32];
+                        deployment_id.copy_from_slice(&multihash[2..]);
 
                         let state = OpenState::consumer_generate(
                             None,
@@ -462,28 +470,10 @@ async fn main() {
                     }
                     "claim" => {
                         let channel_id = channels[cid].id;
-                        let fn_data = contracts["StateChannel"]
-                            .abi()
-                            .function("claim")
-                            .and_then(|function| function.encode_input(&(channel_id,).into_tokens()))
-                            .unwrap();
-                        let gas = contracts["StateChannel"]
-                            .estimate_gas("claim", (channel_id,), channels[cid].consumer, Default::default())
-                            .await;
-                        if gas.is_err() {
+                        let tx = build_claim_tx(channel_id, contracts["StateChannel"].address());
+                        if send_tx(&web3, tx, channels[cid].consumer, &consumer_sk).await.is_err() {
                             println!("Channel not expired");
-                            continue;
                         }
-                        let gas = gas.unwrap();
-                        let tx = TransactionParameters {
-                            to: Some(contracts["StateChannel"].address()),
-                            data: Bytes(fn_data),
-                            gas: gas,
-                            ..Default::default()
-                        };
-                        let signed = web3.accounts().sign_transaction(tx, &consumer_sk).await.unwrap();
-                        let tx_hash = web3.eth().send_raw_transaction(signed.raw_transaction).await.unwrap();
-                        println!("\x1b[94m>>> TxHash: {:?}\x1b[00m", tx_hash);
                     }
                     "show" => {
                         let result: (Token,) = contracts["StateChannel"]
@@ -592,6 +582,10 @@ async fn main() {
                     Ok(fulldata) => {
                         let (query, data) = (&fulldata[0], &fulldata[1]);
                         println!("\x1b[94m>>> Result: {}\x1b[00m", query);
+                        // the p2p `PaidData` response appends the charged and next price.
+                        if let (Some(price), Some(next_price)) = (fulldata.get(2), fulldata.get(3)) {
+                            println!(">>> Price: {}, next price: {}", price, next_price);
+                        }
                         let state = QueryState::from_json(&data).unwrap();
 
                         channels[cid].count = state.count;
@@ -26,7 +26,11 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use subql_proxy_utils::{
-    payg::convert_sign_to_bytes,
+    coordinator::Coordinator,
+    payg::{
+        build_challenge_tx, build_claim_tx, convert_sign_to_string, derive_channel_id, GasPrice, OpenState, QueryState,
+    },
+    query::METADATA_QUERY,
     request::{graphql_request, proxy_request},
 };
 use web3::{
@@ -37,8 +41,8 @@ use web3::{
     ethabi::{encode, Token},
     signing::{keccak256, Key, SecretKeyRef},
     transports::Http,
-    types::{Address, Bytes, TransactionParameters, U256},
-    Web3,
+    types::{Address, BlockId, BlockNumber, Bytes, CallRequest, TransactionParameters, U256},
+    Transport, Web3,
 };
 
 //const LOCAL_ENDPOINT: &'static str = "http://127.0.0.1:8545";
@@ -46,6 +50,7 @@ use web3::{
 const SLEEP: u64 = 2;
 const COORDINATOR_URL: &'static str = "http://127.0.0.1:8000/graphql";
 const CONSUMER_PROXY: &'static str = "http://127.0.0.1:8010";
+const INDEXER_PROXY: &'static str = "http://127.0.0.1:8003";
 
 // Init mnemonic: test test test test test test test test test test test junk
 const MINER: &'static str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
@@ -87,12 +92,38 @@ enum Cli {
     },
     /// Open a state channel for consumer proxy.
     ConsumerOpen {
+        #[structopt(short, long, parse(try_from_str = parse_u256))]
+        amount: U256,
+        #[structopt(short, long, parse(try_from_str = parse_u256))]
+        expiration: U256,
         #[structopt(short, long)]
-        amount: u128,
+        deployment: String,
+        /// Derive the channel id from consumer and nonce, random if not set.
+        #[structopt(short, long, parse(try_from_str = parse_u256))]
+        nonce: Option<U256>,
+    },
+    /// Simulate the dispute lifecycle: open, query, stale challenge, respond and claim.
+    ChannelDispute {
         #[structopt(short, long)]
-        expiration: u128,
+        endpoint: String,
         #[structopt(short, long)]
+        deploy: String,
+        #[structopt(short, long)]
+        contracts: String,
+        #[structopt(short, long, parse(try_from_str = parse_u256))]
+        amount: U256,
+        #[structopt(short = "x", long, parse(try_from_str = parse_u256))]
+        expiration: U256,
+        #[structopt(short = "p", long)]
         deployment: String,
+        #[structopt(short, long, default_value = "3")]
+        queries: u64,
+    },
+    /// Decode the signed open or query state JSON, and check the signatures.
+    StateDecode {
+        /// The state JSON, or the file of it.
+        #[structopt(short, long)]
+        state: String,
     },
     /// Channel show on-chain info.
     ChannelShow {
@@ -105,6 +136,15 @@ enum Cli {
         #[structopt(short, long)]
         id: String,
     },
+    /// Check the coordinator projects are reachable, print the latest block of each.
+    ProjectsCheck {
+        /// The coordinator service url.
+        #[structopt(short, long, default_value = COORDINATOR_URL)]
+        service_url: String,
+        /// The deployments must be reachable, all projects if not set.
+        #[structopt(short, long)]
+        required: Vec<String>,
+    },
 }
 
 #[tokio::main]
@@ -167,12 +207,36 @@ async fn main() {
             amount,
             expiration,
             deployment,
+            nonce,
         } => {
             let consumer = SecretKey::from_slice(&hex::decode(CONSUMER).unwrap()).unwrap();
             let indexer = SecretKey::from_slice(&hex::decode(INDEXER).unwrap()).unwrap();
             let indexer_addr = SecretKeyRef::new(&indexer).address();
-            open_channel_with_consumer(&consumer, indexer_addr, amount, expiration, deployment).await;
+            open_channel_with_consumer(&consumer, indexer_addr, amount, expiration, deployment, nonce).await;
+        }
+        Cli::ChannelDispute {
+            endpoint,
+            deploy,
+            contracts,
+            amount,
+            expiration,
+            deployment,
+            queries,
+        } => {
+            let (web3, contracts, _miner, _indexer, _controller, consumer) =
+                init(endpoint, deploy, contracts, false).await.unwrap();
+            dispute_lifecycle(
+                &web3,
+                &contracts["StateChannel"],
+                &consumer,
+                amount,
+                expiration,
+                deployment,
+                queries,
+            )
+            .await;
         }
+        Cli::StateDecode { state } => decode_state(&state),
         Cli::ChannelShow {
             endpoint,
             deploy,
@@ -182,25 +246,143 @@ async fn main() {
             let id: U256 = id.parse().unwrap();
             let (_web3, contracts, _miner, _indexer, _controller, _consumer) =
                 init(endpoint, deploy, contracts, false).await.unwrap();
-            let result: (Token,) = contracts["StateChannel"]
-                .query("channel", (id,), None, Options::default(), None)
-                .await
-                .unwrap();
-            match result.0 {
-                Token::Tuple(data) => {
-                    let count: U256 = data[3].clone().into_uint().unwrap().into();
-                    let amount: U256 = data[4].clone().into_uint().unwrap().into();
-                    let expiration: U256 = data[5].clone().into_uint().unwrap().into();
-                    println!("State Channel Status: {}", data[0]);
-                    println!(" Indexer:  0x{}", data[1]);
-                    println!(" Consumer: 0x{}", data[2]);
-                    println!(" Count On-chain: {:?}", count);
-                    println!(" Amount:         {:?}", amount);
-                    println!(" Expiration:     {:?}", expiration);
-                }
-                _ => {}
+            show_channel(&contracts["StateChannel"], id).await;
+        }
+        Cli::ProjectsCheck { service_url, required } => {
+            if !check_projects(&service_url, &required).await {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Query the metadata of every alive project, return false if any required one is unreachable.
+async fn check_projects(service_url: &str, required: &[String]) -> bool {
+    let projects = match Coordinator::new(service_url).alive_projects().await {
+        Ok(projects) => projects,
+        Err(err) => {
+            println!("Load projects from {} failed: {}", service_url, err);
+            return false;
+        }
+    };
+
+    let metadata = json!({ "query": METADATA_QUERY });
+    let mut ok = true;
+    let mut found = vec![];
+    println!("{:<48} {:<40} {:<10} {}", "DEPLOYMENT", "URL", "REACHABLE", "BLOCK");
+    for project in projects {
+        let (id, url) = (project.id.as_str(), project.query_endpoint.as_str());
+        let height = match graphql_request(url, &metadata).await {
+            Ok(value) => value.pointer("/data/_metadata/lastProcessedHeight").cloned(),
+            Err(_) => None,
+        };
+        let is_required = required.is_empty() || required.iter().any(|r| r == id);
+        if height.is_none() && is_required {
+            ok = false;
+        }
+        found.push(id.to_owned());
+
+        let (reachable, block) = match height {
+            Some(h) => ("yes", h.to_string()),
+            None => ("no", "-".to_owned()),
+        };
+        println!("{:<48} {:<40} {:<10} {}", id, url, reachable, block);
+    }
+
+    for id in required.iter().filter(|r| !found.contains(r)) {
+        println!("{:<48} {:<40} {:<10} {}", id, "-", "missing", "-");
+        ok = false;
+    }
+    ok
+}
+
+/// Print the recovered signers of state, the open state has `amount`, the query state has `count`.
+fn decode_state(raw: &str) {
+    let raw = std::fs::read_to_string(raw).unwrap_or(raw.to_owned());
+    let value: serde_json::Value = match serde_json::from_str(&raw) {
+        Ok(value) => value,
+        Err(err) => {
+            println!("Invalid state JSON: {}", err);
+            return;
+        }
+    };
+
+    let (kind, channel_id, indexer, consumer, payload, recover_i, recover_c) = if value.get("amount").is_some() {
+        match OpenState::from_json(&value) {
+            Ok(s) => (
+                "Open",
+                s.channel_id,
+                s.indexer,
+                s.consumer,
+                s.payload(),
+                s.recover_indexer(),
+                s.recover_consumer(),
+            ),
+            Err(err) => {
+                println!("Invalid open state: {}", err);
+                return;
             }
         }
+    } else if value.get("count").is_some() {
+        match QueryState::from_json(&value) {
+            Ok(s) => (
+                "Query",
+                s.channel_id,
+                s.indexer,
+                s.consumer,
+                s.payload(),
+                s.recover_indexer(),
+                s.recover_consumer(),
+            ),
+            Err(err) => {
+                println!("Invalid query state: {}", err);
+                return;
+            }
+        }
+    } else {
+        println!("Unknown state, neither open (amount) nor query (count) state!");
+        return;
+    };
+
+    println!("{} State Channel: {:#X}", kind, channel_id);
+    println!(" Message Hash: 0x{}", hex::encode(payload));
+    for (name, expected, recovered) in [("Indexer", indexer, recover_i), ("Consumer", consumer, recover_c)] {
+        match recovered {
+            Ok(signer) => println!(
+                " {}: {:?}, Signer: {:?}, Valid: {}",
+                name,
+                expected,
+                signer,
+                signer == expected
+            ),
+            Err(err) => println!(" {}: {:?}, Signer: {}, Valid: false", name, expected, err),
+        }
+    }
+}
+
+/// Parse the decimal string to U256, amounts in wei may exceed u128.
+fn parse_u256(s: &str) -> Result<U256, String> {
+    U256::from_dec_str(s).map_err(|_| format!("invalid decimal number: {}", s))
+}
+
+async fn show_channel(contract: &Contract<Http>, id: U256) {
+    let result: (Token,) = contract
+        .query("channel", (id,), None, Options::default(), None)
+        .await
+        .unwrap();
+    match result.0 {
+        Token::Tuple(data) => {
+            let count: U256 = data[3].clone().into_uint().unwrap().into();
+            let amount: U256 = data[4].clone().into_uint().unwrap().into();
+            let expiration: U256 = data[5].clone().into_uint().unwrap().into();
+            println!("State Channel Status: {}", data[0]);
+            println!(" Indexer:  0x{}", data[1]);
+            println!(" Consumer: 0x{}", data[2]);
+            println!(" Count On-chain: {:?}", count);
+            println!(" Amount:         {:?}", amount);
+            println!(" Expiration:     {:?}", expiration);
+        }
+        _ => {}
     }
 }
 
@@ -404,18 +586,8 @@ async fn register_indexer(
 
     println!("Save Indexer to coordinator...");
 
-    let mdata = format!(
-        r#"mutation {{
-  addIndexer(indexer:"{:?}") {{
-    indexer
-  }}
-}}
-"#,
-        address
-    );
-    let query = json!({ "query": mdata });
-    let res = graphql_request(COORDINATOR_URL, &query).await.unwrap();
-    println!("Coordinator result: {}", res);
+    let res = Coordinator::new(COORDINATOR_URL).add_indexer(address).await;
+    println!("Coordinator result: {:?}", res);
     println!("Register Indexer OK");
 
     let controller_addr = SecretKeyRef::new(controller).address();
@@ -454,17 +626,10 @@ async fn register_indexer(
         println!("On-chain Controller: {}", result);
     }
 
-    let mdata = format!(
-        r#"mutation {{
-  updateController(controller:"0x{}") {{
-    controller
-  }}
-}}
-"#,
-        format!("{}", controller.display_secret())
-    );
-    let query = json!({ "query": mdata });
-    graphql_request(COORDINATOR_URL, &query).await.unwrap();
+    Coordinator::new(COORDINATOR_URL)
+        .update_controller(&controller.display_secret().to_string())
+        .await
+        .unwrap();
     println!("Register Controller OK");
 }
 
@@ -556,19 +721,22 @@ async fn register_consumer_proxy(
 async fn open_channel_with_consumer(
     sk: &SecretKey,
     indexer: Address,
-    amount: u128,
-    expiration: u128,
+    amount: U256,
+    expiration: U256,
     deployment: String,
+    nonce: Option<U256>,
 ) {
     let consumer = SecretKeyRef::new(sk).address();
-    let mut rng = ChaChaRng::from_entropy();
-    let mut id = [0u64; 4]; // u256
-    for i in 0..4 {
-        id[i] = rng.next_u64();
-    }
-    let channel = U256(id);
-    let amount = U256::from(amount);
-    let expiration = U256::from(expiration);
+    let channel = if let Some(nonce) = nonce {
+        derive_channel_id(consumer, nonce)
+    } else {
+        let mut rng = ChaChaRng::from_entropy();
+        let mut id = [0u64; 4]; // u256
+        for i in 0..4 {
+            id[i] = rng.next_u64();
+        }
+        U256(id)
+    };
 
     let deployment_id = if deployment.starts_with("0x") {
         hex::decode(&deployment[2..]).unwrap()
@@ -586,7 +754,7 @@ async fn open_channel_with_consumer(
     bytes.extend(keccak256(&msg));
     let payload = keccak256(&bytes);
     let sign = sk.sign_message(&payload).unwrap();
-    let callback = hex::encode(convert_sign_to_bytes(&sign));
+    let callback = convert_sign_to_string(&sign);
 
     let query = json!({
         "channelId": format!("{:#X}", channel),
@@ -604,3 +772,169 @@ async fn open_channel_with_consumer(
         Err(res) => println!("Failure: {}", res),
     }
 }
+
+async fn dispute_lifecycle(
+    web3: &Web3<Http>,
+    contract: &Contract<Http>,
+    sk: &SecretKey,
+    amount: U256,
+    expiration: U256,
+    deployment: String,
+    queries: u64,
+) {
+    let consumer = SecretKeyRef::new(sk).address();
+    let indexer_sk = SecretKey::from_slice(&hex::decode(INDEXER).unwrap()).unwrap();
+    let indexer = SecretKeyRef::new(&indexer_sk).address();
+    let deployment_bytes = if deployment.starts_with("0x") {
+        hex::decode(&deployment[2..]).unwrap()
+    } else {
+        // default is bs58
+        bs58::decode(&deployment).into_vec().unwrap()
+    };
+    if deployment_bytes.len() != 32 {
+        println!("Invalid deployment(project) id!");
+        return;
+    }
+    let mut deployment_id = [0u8; 32];
+    deployment_id.copy_from_slice(&deployment_bytes);
+
+    println!("Open channel with indexer: {:?} ...", indexer);
+    let state = OpenState::consumer_generate(
+        None,
+        indexer,
+        consumer,
+        amount,
+        expiration,
+        deployment_id,
+        vec![],
+        SecretKeyRef::new(sk),
+    )
+    .unwrap();
+    let raw_state = serde_json::to_string(&state.to_json()).unwrap();
+    let data = match proxy_request("post", INDEXER_PROXY, "open", "", raw_state, vec![]).await {
+        Ok(data) => data,
+        Err(err) => {
+            println!("Open Failure: {}", err);
+            return;
+        }
+    };
+    let state = OpenState::from_json(&data).unwrap();
+    let channel_id = state.channel_id;
+    let mut price = state.next_price;
+    tokio::time::sleep(std::time::Duration::from_secs(SLEEP)).await;
+    show_channel(contract, channel_id).await;
+
+    println!("\x1b[92m------------------------------------\x1b[00m");
+    let mut states = vec![];
+    for i in 1..=queries {
+        let count = U256::from(i);
+        let is_final = count * price >= amount;
+        let state = QueryState::consumer_generate(
+            channel_id,
+            indexer,
+            consumer,
+            count,
+            price,
+            is_final,
+            SecretKeyRef::new(sk),
+        )
+        .unwrap();
+        let raw_state = serde_json::to_string(&state.to_json()).unwrap();
+        let raw_query =
+            serde_json::to_string(&json!({ "query": "query { _metadata { indexerHealthy chain } }" })).unwrap();
+        let res = proxy_request(
+            "post",
+            INDEXER_PROXY,
+            &format!("payg/{}", deployment),
+            "",
+            raw_query,
+            vec![("Authorization".to_owned(), raw_state)],
+        )
+        .await;
+        match res {
+            Ok(fulldata) => {
+                let state = QueryState::from_json(&fulldata[1]).unwrap();
+                println!("Query {} Result: {}", state.count, fulldata[0]);
+                price = state.next_price;
+                states.push(state);
+            }
+            Err(err) => {
+                println!("Query Failure: {}", err);
+                return;
+            }
+        }
+    }
+    if states.len() < 2 {
+        println!("Need at least 2 queries to submit a stale state!");
+        return;
+    }
+
+    println!("\x1b[92m------------------------------------\x1b[00m");
+    println!("Challenge with stale state count: {} ...", states[0].count);
+    let tx = build_challenge_tx(&states[0], contract.address());
+    send_tx(web3, tx, consumer, sk, "challenge").await;
+    tokio::time::sleep(std::time::Duration::from_secs(SLEEP)).await;
+    show_channel(contract, channel_id).await;
+
+    println!("\x1b[92m------------------------------------\x1b[00m");
+    println!(
+        "Waiting indexer respond with latest count: {} ...",
+        states[states.len() - 1].count
+    );
+    tokio::time::sleep(std::time::Duration::from_secs(SLEEP * 5)).await;
+    show_channel(contract, channel_id).await;
+
+    println!("\x1b[92m------------------------------------\x1b[00m");
+    match advance_past(web3, expiration).await {
+        Ok(seconds) => println!("Advance time: {} seconds", seconds),
+        Err(err) => {
+            println!("Advance time failure: {}", err);
+            return;
+        }
+    }
+
+    println!("Claim channel: {:#X} ...", channel_id);
+    let tx = build_claim_tx(channel_id, contract.address());
+    send_tx(web3, tx, consumer, sk, "claim").await;
+    tokio::time::sleep(std::time::Duration::from_secs(SLEEP)).await;
+    show_channel(contract, channel_id).await;
+}
+
+/// Estimate the gas of the unsigned transaction, sign and send it.
+async fn send_tx(web3: &Web3<Http>, mut tx: TransactionParameters, from: Address, sk: &SecretKey, method: &str) {
+    let call = CallRequest {
+        from: Some(from),
+        to: tx.to,
+        data: Some(tx.data.clone()),
+        ..Default::default()
+    };
+    tx.gas = match web3.eth().estimate_gas(call, None).await {
+        Ok(gas) => gas,
+        Err(err) => {
+            println!("{} Failure: {}", method, err);
+            return;
+        }
+    };
+    if let Err(err) = GasPrice::default().apply(web3, &mut tx).await {
+        println!("{} Failure: {}", method, err);
+        return;
+    }
+    let signed = web3.accounts().sign_transaction(tx, sk).await.unwrap();
+    let tx_hash = web3.eth().send_raw_transaction(signed.raw_transaction).await.unwrap();
+    println!("TxHash: {:?}", tx_hash);
+}
+
+/// Advance the time of local chain past the timestamp, from the latest block.
+async fn advance_past(web3: &Web3<Http>, timestamp: U256) -> web3::Result<U256> {
+    let block = web3
+        .eth()
+        .block(BlockId::Number(BlockNumber::Latest))
+        .await?
+        .ok_or_else(|| web3::Error::InvalidResponse("no latest block".to_owned()))?;
+    let seconds = timestamp.saturating_sub(block.timestamp) + 1;
+    web3.transport()
+        .execute("evm_increaseTime", vec![json!(seconds.as_u64())])
+        .await?;
+    web3.transport().execute("evm_mine", vec![]).await?;
+    Ok(seconds)
+}
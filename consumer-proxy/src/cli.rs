@@ -19,9 +19,17 @@
 use once_cell::sync::Lazy;
 use secp256k1::SecretKey;
 use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-use subql_proxy_utils::request::{jsonrpc_request, proxy_request};
-use web3::{signing::SecretKeyRef, types::Address};
+use subql_proxy_utils::{
+    payg::GasPrice,
+    request::{jsonrpc_request_with_token, proxy_request},
+};
+use web3::{
+    signing::SecretKeyRef,
+    types::{Address, U256},
+};
 
 #[cfg(feature = "p2p")]
 use subql_proxy_utils::p2p::libp2p::Multiaddr;
@@ -29,8 +37,13 @@ use subql_proxy_utils::p2p::libp2p::Multiaddr;
 const SEED_ADDR: &'static str = "/ip4/0.0.0.0/tcp/7000";
 const P2P_ADDR: &'static str = "/ip4/0.0.0.0/tcp/0";
 
+#[cfg(not(test))]
 pub static COMMAND: Lazy<CommandArgs> = Lazy::new(|| CommandLineArgs::from_args().parse());
 
+/// The unit tests share one command line, the indexer and chain are the local mocks of tests.
+#[cfg(test)]
+pub static COMMAND: Lazy<CommandArgs> = Lazy::new(|| CommandLineArgs::from_iter(crate::mock::args()).parse());
+
 pub enum IndexerNetwork {
     Url(String),
     P2p(String),
@@ -42,7 +55,7 @@ impl IndexerNetwork {
             IndexerNetwork::Url(url) => proxy_request("post", url, "open", "", state, vec![]).await,
             IndexerNetwork::P2p(pid) => {
                 let query = vec![Value::from(format!("{}", pid)), Value::from(state)];
-                jsonrpc_request(0, "http://127.0.0.1:8011", "state-channel", query).await
+                jsonrpc_request_with_token(0, &COMMAND.rpc_url(), COMMAND.rpc_token(), "state-channel", query).await
             }
         }
     }
@@ -67,7 +80,7 @@ impl IndexerNetwork {
                     Value::from(query),
                     Value::from(state),
                 ];
-                jsonrpc_request(0, "http://127.0.0.1:8011", "payg-sync", query).await
+                jsonrpc_request_with_token(0, &COMMAND.rpc_url(), COMMAND.rpc_token(), "payg-sync", query).await
             }
         }
     }
@@ -76,7 +89,7 @@ impl IndexerNetwork {
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Consumer Proxy", about = "Command line for starting consumer proxy server")]
 pub struct CommandLineArgs {
-    /// IP address for the server
+    /// IP address for the server, IPv4 or IPv6
     #[structopt(long = "host", short = "h", default_value = "0.0.0.0")]
     pub host: String,
     /// Port the service will listen on
@@ -91,9 +104,27 @@ pub struct CommandLineArgs {
     /// Check if running p2p as relay.
     #[structopt(long = "relay")]
     pub relay: bool,
+    /// P2P identity key file, the P2P_KEY env is preferred if set.
+    #[structopt(long = "p2p-key", default_value = "indexer.key", parse(from_os_str))]
+    pub p2p_key: PathBuf,
+    /// P2P addresses dialed on start and redialed when disconnected.
+    #[structopt(long = "p2p-bootstrap")]
+    pub p2p_bootstrap: Vec<String>,
+    /// P2P JSON-RPC binding socket address, it controls the node, never bind it publicly.
+    #[structopt(long = "p2p-rpc", default_value = "127.0.0.1:8011")]
+    pub p2p_rpc: SocketAddr,
+    /// P2P JSON-RPC websocket binding socket address, never bind it publicly.
+    #[structopt(long = "p2p-ws")]
+    pub p2p_ws: Option<SocketAddr>,
+    /// Bearer token of the P2P JSON-RPC, required if it binds on non-loopback.
+    #[structopt(long = "p2p-rpc-token", env = "P2P_RPC_TOKEN", hide_env_values = true)]
+    pub p2p_rpc_token: Option<String>,
     /// Enable debug mode
     #[structopt(long = "debug")]
     pub debug: bool,
+    /// Log output format
+    #[structopt(long = "log-format", default_value = "text", possible_values = &["text", "json"])]
+    pub log_format: String,
     /// Enable dev mode
     #[structopt(long = "dev")]
     pub dev: bool,
@@ -103,6 +134,39 @@ pub struct CommandLineArgs {
     /// Signer secret key
     #[structopt(long = "signer")]
     pub signer: String,
+    /// Web3 endpoint for sending transactions
+    #[structopt(long = "web3", default_value = "http://127.0.0.1:8545")]
+    pub web3: String,
+    /// State channel contract, auto checkpoint needs it
+    #[structopt(long = "state-channel")]
+    pub state_channel: Option<String>,
+    /// Auto checkpoint after every N queries, 0 is disabled
+    #[structopt(long = "checkpoint-interval", default_value = "0")]
+    pub checkpoint_interval: u64,
+    /// Auto checkpoint when spent crosses this percentage of the channel amount, 0 is disabled
+    #[structopt(long = "checkpoint-threshold", default_value = "0")]
+    pub checkpoint_threshold: u64,
+    /// Max attempts of sending the checkpoint transaction
+    #[structopt(long = "checkpoint-retry", default_value = "3")]
+    pub checkpoint_retry: u64,
+    /// Seconds waiting before the checkpoint retry, multiplied by the attempt
+    #[structopt(long = "checkpoint-retry-interval", default_value = "5")]
+    pub checkpoint_retry_interval: u64,
+    /// Min amount of opening state channel
+    #[structopt(long = "min-channel-amount", default_value = "1")]
+    pub min_channel_amount: String,
+    /// Max expiration seconds from now of opening state channel
+    #[structopt(long = "max-channel-expiration", default_value = "2592000")]
+    pub max_channel_expiration: u64,
+    /// Legacy gas price (wei) of the checkpoint transactions, the EIP-1559 fees are used if unset
+    #[structopt(long = "gas-price")]
+    pub gas_price: Option<String>,
+    /// Max fee per gas (wei) of the checkpoint transactions, estimated from the fee history if unset
+    #[structopt(long = "max-fee-per-gas")]
+    pub max_fee_per_gas: Option<String>,
+    /// Max priority fee per gas (wei) of the checkpoint transactions, estimated from the fee history if unset
+    #[structopt(long = "max-priority-fee-per-gas")]
+    pub max_priority_fee_per_gas: Option<String>,
 }
 
 impl CommandLineArgs {
@@ -124,10 +188,29 @@ impl CommandLineArgs {
             port: self.port,
             dev: self.dev,
             debug: self.debug,
+            log_json: self.log_format == "json",
             indexer: indexer,
             p2p: p2p,
+            p2p_key: self.p2p_key,
+            p2p_bootstrap: self.p2p_bootstrap.iter().map(|a| a.parse().unwrap()).collect(),
+            p2p_rpc: self.p2p_rpc,
+            p2p_ws: self.p2p_ws,
+            p2p_rpc_token: self.p2p_rpc_token,
             contract: self.contract.parse().unwrap(),
             signer: SecretKey::from_slice(&hex::decode(&self.signer).unwrap()).unwrap(),
+            web3: self.web3,
+            state_channel: self.state_channel.map(|c| c.parse().unwrap()),
+            checkpoint_interval: self.checkpoint_interval,
+            checkpoint_threshold: self.checkpoint_threshold,
+            checkpoint_retry: self.checkpoint_retry,
+            checkpoint_retry_interval: self.checkpoint_retry_interval,
+            min_channel_amount: U256::from_dec_str(&self.min_channel_amount).unwrap(),
+            max_channel_expiration: self.max_channel_expiration,
+            gas_price: GasPrice {
+                gas_price: self.gas_price.map(|p| U256::from_dec_str(&p).unwrap()),
+                max_fee_per_gas: self.max_fee_per_gas.map(|p| U256::from_dec_str(&p).unwrap()),
+                max_priority_fee_per_gas: self.max_priority_fee_per_gas.map(|p| U256::from_dec_str(&p).unwrap()),
+            },
         }
     }
 }
@@ -136,11 +219,26 @@ pub struct CommandArgs {
     pub host: String,
     pub port: u16,
     pub debug: bool,
+    pub log_json: bool,
     pub dev: bool,
     pub p2p: Multiaddr,
+    pub p2p_key: PathBuf,
+    pub p2p_bootstrap: Vec<Multiaddr>,
+    pub p2p_rpc: SocketAddr,
+    pub p2p_ws: Option<SocketAddr>,
+    pub p2p_rpc_token: Option<String>,
     pub indexer: IndexerNetwork,
     pub contract: Address,
     pub signer: SecretKey,
+    pub web3: String,
+    pub state_channel: Option<Address>,
+    pub checkpoint_interval: u64,
+    pub checkpoint_threshold: u64,
+    pub checkpoint_retry: u64,
+    pub checkpoint_retry_interval: u64,
+    pub min_channel_amount: U256,
+    pub max_channel_expiration: u64,
+    pub gas_price: GasPrice,
 }
 
 #[allow(dead_code)]
@@ -161,6 +259,10 @@ impl CommandArgs {
         self.debug
     }
 
+    pub fn log_json(&self) -> bool {
+        self.log_json
+    }
+
     pub fn dev(&self) -> bool {
         self.dev
     }
@@ -169,6 +271,37 @@ impl CommandArgs {
         self.p2p.clone()
     }
 
+    pub fn p2p_key(&self) -> &Path {
+        &self.p2p_key
+    }
+
+    pub fn p2p_bootstrap(&self) -> Vec<Multiaddr> {
+        self.p2p_bootstrap.clone()
+    }
+
+    pub fn rpc(&self) -> SocketAddr {
+        self.p2p_rpc
+    }
+
+    pub fn ws(&self) -> Option<SocketAddr> {
+        self.p2p_ws
+    }
+
+    pub fn rpc_token(&self) -> Option<&str> {
+        self.p2p_rpc_token.as_deref()
+    }
+
+    /// The url of local P2P JSON-RPC, the unspecified binding address is reached by loopback.
+    pub fn rpc_url(&self) -> String {
+        let mut addr = self.p2p_rpc;
+        match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            _ => {}
+        }
+        format!("http://{}", addr)
+    }
+
     pub fn contract(&self) -> Address {
         self.contract
     }
@@ -176,4 +309,40 @@ impl CommandArgs {
     pub fn signer(&self) -> SecretKeyRef {
         SecretKeyRef::new(&self.signer)
     }
+
+    pub fn web3(&self) -> &str {
+        &self.web3
+    }
+
+    pub fn state_channel(&self) -> Option<Address> {
+        self.state_channel
+    }
+
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.checkpoint_interval
+    }
+
+    pub fn checkpoint_threshold(&self) -> u64 {
+        self.checkpoint_threshold
+    }
+
+    pub fn checkpoint_retry(&self) -> u64 {
+        self.checkpoint_retry
+    }
+
+    pub fn checkpoint_retry_interval(&self) -> u64 {
+        self.checkpoint_retry_interval
+    }
+
+    pub fn min_channel_amount(&self) -> U256 {
+        self.min_channel_amount
+    }
+
+    pub fn max_channel_expiration(&self) -> u64 {
+        self.max_channel_expiration
+    }
+
+    pub fn gas_price(&self) -> GasPrice {
+        self.gas_price
+    }
 }
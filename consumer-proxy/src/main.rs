@@ -26,33 +26,39 @@ mod server;
 #[cfg(feature = "p2p")]
 mod p2p;
 
+#[cfg(test)]
+mod mock;
+
 use cli::COMMAND;
 use tracing::Level;
 
 #[cfg(feature = "p2p")]
-use subql_proxy_utils::p2p::{libp2p::identity::Keypair, server::server as p2p_server};
+use subql_proxy_utils::p2p::{load_key, rpc::check_bind as check_rpc_bind, server::server as p2p_server};
 
 #[tokio::main]
 async fn main() {
     let log_filter = if COMMAND.debug() { Level::DEBUG } else { Level::INFO };
-    tracing_subscriber::fmt().with_max_level(log_filter).init();
+    let subscriber = tracing_subscriber::fmt().with_max_level(log_filter);
+    if COMMAND.log_json() {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
 
     #[cfg(feature = "p2p")]
     {
         let p2p_bind = COMMAND.p2p();
         info!("P2P bind: {}", p2p_bind);
 
-        let key_path = std::path::PathBuf::from("indexer.key");
-        let key = if key_path.exists() {
-            let key_bytes = tokio::fs::read(&key_path).await.unwrap_or(vec![]); // safe.
-            Keypair::from_protobuf_encoding(&key_bytes).unwrap()
-        } else {
-            let key = Keypair::generate_ed25519();
-            let _ = tokio::fs::write(key_path, key.to_protobuf_encoding().unwrap()).await;
-            key
-        };
+        let key = load_key(COMMAND.p2p_key()).await;
+        let bootstrap = COMMAND.p2p_bootstrap();
+        let rpc_addr = COMMAND.rpc();
+        let ws_addr = COMMAND.ws();
+        let rpc_token = COMMAND.rpc_token().map(|t| t.to_owned());
+        // refuse to start before spawning, the P2P task failure not stops the proxy.
+        check_rpc_bind(rpc_addr, ws_addr, rpc_token.as_deref()).expect("Insecure P2P JSON-RPC bind");
         tokio::spawn(async move {
-            p2p_server::<p2p::ConsumerP2p>(p2p_bind, "127.0.0.1:8011".parse().unwrap(), None, None, key)
+            p2p_server::<p2p::ConsumerP2p>(p2p_bind, rpc_addr, ws_addr, rpc_token, None, key, bootstrap)
                 .await
                 .unwrap();
         });
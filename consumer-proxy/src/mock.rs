@@ -0,0 +1,212 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+//! Local mocks of the indexer and chain, and the channels of the unit tests.
+
+use once_cell::sync::Lazy;
+use secp256k1::SecretKey;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use subql_proxy_utils::payg::{OpenState, QueryState};
+use warp::Filter;
+use web3::{
+    signing::{keccak256, Key, SecretKeyRef},
+    types::U256,
+};
+
+use crate::payg::StateChannel;
+
+/// The command line of tests, the consumer key is `CONSUMER_KEY`, the indexer and chain are the mocks.
+pub fn args() -> Vec<String> {
+    let mocks = &*MOCKS;
+    let args = [
+        "consumer-proxy",
+        "--indexer-url",
+        &format!("http://{}", mocks.indexer),
+        "--contract",
+        "0x00000000000000000000000000000000000000c0",
+        "--signer",
+        "2222222222222222222222222222222222222222222222222222222222222222",
+        "--web3",
+        &format!("http://{}", mocks.chain),
+        "--state-channel",
+        "0x00000000000000000000000000000000000000c1",
+        "--checkpoint-retry-interval",
+        "0",
+    ];
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// The controller key of the test indexer.
+const INDEXER_KEY: [u8; 32] = [0x11; 32];
+
+/// The key of the test consumer, same as `--signer`.
+const CONSUMER_KEY: [u8; 32] = [0x22; 32];
+
+/// Seed of the deployment ids, every channel of tests is a new deployment.
+static DEPLOYMENTS: AtomicU64 = AtomicU64::new(0);
+
+/// The mocks shared by all the tests as the command line is, served on their own runtime (the
+/// runtime of a test ends with it) at the ports picked by the OS.
+static MOCKS: Lazy<Mocks> = Lazy::new(Mocks::start);
+
+struct Mocks {
+    indexer: SocketAddr,
+    chain: SocketAddr,
+    received: Arc<Mutex<Received>>,
+}
+
+/// The requests received by the mocks, told apart by the deployment or channel of tests.
+#[derive(Default)]
+struct Received {
+    /// deployment id of the query path => counts of the query states.
+    counts: HashMap<String, Vec<U256>>,
+    /// the JSON-RPC requests to the chain.
+    requests: Vec<Value>,
+    /// channel id (hex word in the transaction) => the raw transactions still to reject.
+    failures: HashMap<String, usize>,
+}
+
+impl Mocks {
+    fn start() -> Self {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let (indexer, chain) = (indexer(received.clone()), chain(received.clone()));
+        let (send, recv) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let (indexer_addr, indexer) = warp::serve(indexer).bind_ephemeral(([127, 0, 0, 1], 0));
+                let (chain_addr, chain) = warp::serve(chain).bind_ephemeral(([127, 0, 0, 1], 0));
+                send.send((indexer_addr, chain_addr)).unwrap();
+                tokio::join!(indexer, chain);
+            });
+        });
+        let (indexer, chain) = recv.recv().unwrap();
+        Mocks {
+            indexer,
+            chain,
+            received,
+        }
+    }
+}
+
+/// Open a new channel of price 10 to the test indexer, return the deployment id as in the query path.
+pub async fn channel(amount: u64) -> String {
+    let seed = DEPLOYMENTS.fetch_add(1, Ordering::Relaxed);
+    let deployment_id = keccak256(format!("deployment-{}", seed).as_bytes());
+    let indexer = SecretKey::from_slice(&INDEXER_KEY).unwrap();
+    let consumer = SecretKey::from_slice(&CONSUMER_KEY).unwrap();
+    let mut state = OpenState::consumer_generate(
+        None,
+        SecretKeyRef::new(&indexer).address(),
+        SecretKeyRef::new(&consumer).address(),
+        U256::from(amount),
+        U256::from(u64::MAX),
+        deployment_id,
+        vec![],
+        SecretKeyRef::new(&consumer),
+    )
+    .unwrap();
+    state.next_price = U256::from(10u64);
+    StateChannel::add(state).await;
+    format!("0x{}", hex::encode(deployment_id))
+}
+
+/// The mocked indexer (`/payg/:id`), countersigns the query states.
+fn indexer(received: Arc<Mutex<Received>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("payg" / String)
+        .and(warp::header::<String>("authorization"))
+        .map(move |id: String, raw_state: String| {
+            let value: Value = serde_json::from_str(&raw_state).unwrap();
+            let mut state = QueryState::from_json(&value).unwrap();
+            received.lock().unwrap().counts.entry(id).or_default().push(state.count);
+            let indexer = SecretKey::from_slice(&INDEXER_KEY).unwrap();
+            state.sign(SecretKeyRef::new(&indexer), false).unwrap();
+            state.next_price = state.price;
+            let data = json!({ "data": { "_metadata": { "chain": "test" } } });
+            warp::reply::json(&json!([data, state.to_json()]))
+        })
+}
+
+/// The counts of the query states the indexer received for the deployment.
+pub fn indexer_counts(deployment: &str) -> Vec<U256> {
+    let received = MOCKS.received.lock().unwrap();
+    received.counts.get(deployment).cloned().unwrap_or_default()
+}
+
+/// The mocked JSON-RPC chain, the nonce of signer is 5 and the gas price is 1 gwei.
+fn chain(received: Arc<Mutex<Received>>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::body::json().map(move |body: Value| {
+        let mut received = received.lock().unwrap();
+        received.requests.push(body.clone());
+        let result = match body["method"].as_str().unwrap_or_default() {
+            "eth_estimateGas" => json!("0x186a0"),
+            "eth_gasPrice" => json!("0x3b9aca00"),
+            "eth_getTransactionCount" => json!("0x5"),
+            "eth_chainId" => json!("0x1"),
+            "eth_sendRawTransaction" => {
+                let raw = body["params"][0].as_str().unwrap_or_default();
+                let failure = received
+                    .failures
+                    .iter_mut()
+                    .find(|(channel, left)| **left > 0 && raw.contains(channel.as_str()));
+                if let Some((_, left)) = failure {
+                    *left -= 1;
+                    let error = json!({ "code": -32000, "message": "replacement transaction underpriced" });
+                    return warp::reply::json(&json!({ "jsonrpc": "2.0", "id": body["id"], "error": error }));
+                }
+                json!(format!("0x{}", hex::encode([0x7a; 32])))
+            }
+            // no fee history, the legacy gas price is used.
+            _ => {
+                let error = json!({ "code": -32601, "message": "method not found" });
+                return warp::reply::json(&json!({ "jsonrpc": "2.0", "id": body["id"], "error": error }));
+            }
+        };
+        warp::reply::json(&json!({ "jsonrpc": "2.0", "id": body["id"], "result": result }))
+    })
+}
+
+/// The hex word of channel id, as in the checkpoint transactions of the channel.
+fn channel_word(channel_id: U256) -> String {
+    let mut word = [0u8; 32];
+    channel_id.to_big_endian(&mut word);
+    hex::encode(word)
+}
+
+/// Reject the first `failures` raw transactions of the channel sent to the chain.
+pub fn reject_transactions(channel_id: U256, failures: usize) {
+    let mut received = MOCKS.received.lock().unwrap();
+    received.failures.insert(channel_word(channel_id), failures);
+}
+
+/// The raw transactions of the channel sent to the chain.
+pub fn raw_transactions(channel_id: U256) -> Vec<String> {
+    let word = channel_word(channel_id);
+    let received = MOCKS.received.lock().unwrap();
+    received
+        .requests
+        .iter()
+        .filter(|body| body["method"] == "eth_sendRawTransaction")
+        .filter_map(|body| body["params"][0].as_str())
+        .filter(|raw| raw.contains(&word))
+        .map(|raw| raw.to_owned())
+        .collect()
+}
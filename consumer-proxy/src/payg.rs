@@ -1,15 +1,26 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::time::Duration;
 use subql_proxy_utils::{
     error::Error,
-    payg::{convert_sign_to_string, convert_string_to_sign, default_sign, OpenState, QueryState},
+    payg::{build_checkpoint_tx, convert_sign_to_string, convert_string_to_sign, default_sign, OpenState, QueryState},
 };
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use web3::{
-    signing::{SecretKeyRef, Signature},
-    types::{Address, U256},
+    signing::{Key, SecretKeyRef, Signature},
+    transports::Http,
+    types::{Address, BlockNumber, CallRequest, H256, U256},
+    Web3,
 };
 
+use crate::cli::COMMAND;
+
+/// Percentage of gas price bumped at every retry, replace the pending one needs at least 10%.
+const GAS_PRICE_BUMP: u64 = 20;
+
+/// Next nonce of the signer, managed locally to avoid conflicts of concurrent transactions.
+static NONCE: Lazy<Mutex<Option<U256>>> = Lazy::new(|| Mutex::new(None));
+
 pub static CHANNELS: Lazy<RwLock<HashMap<String, StateChannel>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
 #[allow(dead_code)]
@@ -36,6 +47,10 @@ pub struct StateChannel {
     last_price: U256,
     last_indexer_sign: Signature,
     last_consumer_sign: Signature,
+    /// the count checkpointed on chain successfully.
+    checkpoint_count: U256,
+    /// a checkpoint is running in background, not spawn another one.
+    checkpointing: bool,
 }
 
 impl StateChannel {
@@ -70,14 +85,17 @@ impl StateChannel {
             last_final: false,
             last_indexer_sign: default_sign(),
             last_consumer_sign: default_sign(),
+            checkpoint_count: U256::from(0u64),
+            checkpointing: false,
         };
 
         CHANNELS.write().await.insert(id, channel);
     }
 
     pub fn next_query(self, sk: SecretKeyRef) -> Result<QueryState, Error> {
-        let is_final = false; // TODO more
         let count = self.current_count + 1;
+        // zero price is unmetered, only terminated by expiration.
+        let is_final = !self.last_price.is_zero() && count.saturating_mul(self.last_price) >= self.balance;
 
         QueryState::consumer_generate(
             self.id,
@@ -91,7 +109,7 @@ impl StateChannel {
     }
 
     pub async fn renew(cid: U256, state: QueryState) {
-        let channels = CHANNELS.write().await;
+        let channels = CHANNELS.read().await;
         let mut id = String::new();
         for (k, v) in channels.iter() {
             if v.id == cid {
@@ -104,14 +122,157 @@ impl StateChannel {
             // TODO if next_price != last_price, checkpoint chain.
             // TODO adjust the count number if current_count != remote_count.
 
+            // concurrent queries may return out of order, keep the latest one.
+            if state.count <= channel.current_count {
+                return;
+            }
+
             channel.current_count = state.count;
             channel.remote_count = state.count;
             channel.last_price = state.next_price;
             channel.last_final = state.is_final;
             channel.last_indexer_sign = state.indexer_sign;
             channel.last_consumer_sign = state.consumer_sign;
+
+            if !channel.checkpointing && channel.need_checkpoint() {
+                channel.checkpointing = true;
+                tokio::spawn(checkpoint(channel.clone()));
+            }
+        }
+    }
+
+    /// The latest counter-signed state of the channel.
+    fn latest_state(&self) -> QueryState {
+        QueryState {
+            channel_id: self.id,
+            indexer: self.indexer,
+            consumer: self.consumer,
+            count: self.current_count,
+            price: self.last_price,
+            is_final: self.last_final,
+            indexer_sign: convert_string_to_sign(&convert_sign_to_string(&self.last_indexer_sign)),
+            consumer_sign: convert_string_to_sign(&convert_sign_to_string(&self.last_consumer_sign)),
+            next_price: self.last_price,
+        }
+    }
+
+    /// Check if the latest state should be checkpointed on-chain,
+    /// by the queries interval or the spent threshold from command line.
+    fn need_checkpoint(&self) -> bool {
+        if COMMAND.state_channel().is_none() || self.current_count <= self.checkpoint_count {
+            return false;
+        }
+
+        let interval = COMMAND.checkpoint_interval();
+        if interval > 0 && self.current_count - self.checkpoint_count >= U256::from(interval) {
+            return true;
+        }
+
+        let threshold = COMMAND.checkpoint_threshold();
+        if threshold > 0 {
+            let limit = self.balance.saturating_mul(U256::from(threshold));
+            let last_spent = self.checkpoint_count.saturating_mul(self.last_price);
+            let spent = self.current_count.saturating_mul(self.last_price);
+            let hundred = U256::from(100u64);
+            return last_spent.saturating_mul(hundred) < limit && spent.saturating_mul(hundred) >= limit;
+        }
+
+        false
+    }
+}
+
+/// Checkpoint the channel state on-chain in background, retry when failure.
+async fn checkpoint(channel: StateChannel) {
+    let contract = match COMMAND.state_channel() {
+        Some(contract) => contract,
+        None => return,
+    };
+
+    let retry = COMMAND.checkpoint_retry();
+    let mut checkpointed = false;
+    for i in 1..=retry {
+        match send_checkpoint(&channel, contract, i).await {
+            Ok(tx_hash) => {
+                info!(
+                    "Channel {:#X} checkpoint count {}, TxHash: {:?}",
+                    channel.id, channel.current_count, tx_hash
+                );
+                checkpointed = true;
+                break;
+            }
+            Err(err) => {
+                warn!(
+                    "Channel {:#X} checkpoint failure ({}/{}): {}",
+                    channel.id, i, retry, err
+                );
+                if i < retry {
+                    tokio::time::sleep(Duration::from_secs(COMMAND.checkpoint_retry_interval() * i)).await;
+                }
+            }
         }
     }
+    if !checkpointed {
+        error!(
+            "Channel {:#X} checkpoint failure, count: {}",
+            channel.id, channel.current_count
+        );
+    }
+
+    // the failed count is checkpointed again by the next query.
+    let id = hex::encode(&channel.deployment_id);
+    if let Some(c) = CHANNELS.write().await.get_mut(&id) {
+        if c.id == channel.id {
+            c.checkpointing = false;
+            if checkpointed && c.checkpoint_count < channel.current_count {
+                c.checkpoint_count = channel.current_count;
+                c.onchain_count = channel.current_count;
+            }
+        }
+    }
+}
+
+/// Send the checkpoint transaction, the retry (attempt > 1) reuses the nonce and bumps the gas price.
+async fn send_checkpoint(channel: &StateChannel, contract: Address, attempt: u64) -> web3::Result<H256> {
+    let web3 = Web3::new(Http::new(COMMAND.web3())?);
+    let signer = COMMAND.signer().address();
+
+    let mut tx = build_checkpoint_tx(&channel.latest_state(), contract);
+    tx.gas = web3
+        .eth()
+        .estimate_gas(
+            CallRequest {
+                from: Some(signer),
+                to: tx.to,
+                data: Some(tx.data.clone()),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+    COMMAND.gas_price().apply(&web3, &mut tx).await?;
+    let bump = |price: U256| price * U256::from(100 + GAS_PRICE_BUMP * (attempt - 1)) / U256::from(100u64);
+    tx.gas_price = tx.gas_price.map(bump);
+    tx.max_fee_per_gas = tx.max_fee_per_gas.map(bump);
+    tx.max_priority_fee_per_gas = tx.max_priority_fee_per_gas.map(bump);
+    let gas_price = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+
+    // hold the nonce until the transaction sent.
+    let mut next_nonce = NONCE.lock().await;
+    let pending = web3.eth().transaction_count(signer, Some(BlockNumber::Pending)).await?;
+    let nonce = match *next_nonce {
+        Some(n) if n > pending => n,
+        _ => pending,
+    };
+    tx.nonce = Some(nonce);
+    let signed = web3.accounts().sign_transaction(tx, &COMMAND.signer).await?;
+    let tx_hash = web3.eth().send_raw_transaction(signed.raw_transaction).await?;
+    *next_nonce = Some(nonce + 1);
+
+    debug!(
+        "Checkpoint sent, attempt: {}, nonce: {}, gas price: {}",
+        attempt, nonce, gas_price
+    );
+    Ok(tx_hash)
 }
 
 impl Clone for StateChannel {
@@ -132,6 +293,37 @@ impl Clone for StateChannel {
             last_price: self.last_price,
             last_indexer_sign: convert_string_to_sign(&convert_sign_to_string(&self.last_indexer_sign)),
             last_consumer_sign: convert_string_to_sign(&convert_sign_to_string(&self.last_consumer_sign)),
+            checkpoint_count: self.checkpoint_count,
+            checkpointing: self.checkpointing,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{channel, raw_transactions, reject_transactions};
+
+    #[tokio::test]
+    async fn retry_the_failed_checkpoint() {
+        let id = channel(100).await;
+        let opened = StateChannel::get(&id).await.unwrap();
+        reject_transactions(opened.id, 1);
+        let state = opened.clone().next_query(COMMAND.signer()).unwrap();
+        StateChannel::renew(opened.id, state).await;
+
+        checkpoint(StateChannel::get(&id).await.unwrap()).await;
+
+        let sent = raw_transactions(opened.id);
+        assert_eq!(sent.len(), 2);
+        // the legacy transaction starts with the nonce (5) and gas price, the retry reuses
+        // the nonce and bumps the price from 1 gwei to 1.2 gwei.
+        assert!(sent[0].contains("05843b9aca00"), "{}", sent[0]);
+        assert!(sent[1].contains("058447868c00"), "{}", sent[1]);
+        assert_eq!(*NONCE.lock().await, Some(U256::from(6u64)));
+        let channel = StateChannel::get(&id).await.unwrap();
+        assert_eq!(channel.onchain_count, U256::one());
+        assert_eq!(channel.checkpoint_count, U256::one());
+        assert!(!channel.checkpointing);
+    }
+}
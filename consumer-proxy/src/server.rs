@@ -16,11 +16,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::prelude::Utc;
 use serde_json::Value;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use subql_proxy_utils::{
     constants::HEADERS,
-    error::{handle_rejection, Error},
+    error::{handle_rejection, method_fallback, Error},
     payg::{convert_recovery_sign, convert_sign_to_bytes, convert_string_to_sign, OpenState, QueryState},
     types::WebResult,
 };
@@ -32,9 +33,17 @@ use web3::{
     types::{Address, U256},
 };
 
-use crate::cli::COMMAND;
+use crate::cli::{IndexerNetwork, COMMAND};
 use crate::payg::StateChannel;
 
+/// The known paths and their methods, for the 405 and `OPTIONS` fallback.
+const ROUTES: [(&str, &str); 4] = [
+    ("query/*", "POST"),
+    ("open", "POST"),
+    ("p2p/*/payg/*", "POST"),
+    ("graphql", "GET"),
+];
+
 pub async fn start_server(host: &str, port: u16) {
     // query with agreement.
     let query_route = warp::path!("query" / String)
@@ -48,20 +57,34 @@ pub async fn start_server(host: &str, port: u16) {
         .and(warp::body::json())
         .and_then(open_payg);
 
+    // relay the payg query to the P2P indexer, wait until the P2P response returns.
+    let p2p_payg_route = warp::path!("p2p" / String / "payg" / String)
+        .and(warp::post())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::body::json())
+        .and_then(p2p_payg_handler);
+
     // graphql playground page.
-    let pg_route = warp::path!("graphql").map(|| reply::html(include_str!("./playground.html")));
+    let pg_route = warp::path!("graphql")
+        .and(warp::get())
+        .map(|| reply::html(include_str!("./playground.html")));
 
     // chain the routes
     let routes = query_route
         .or(open_route)
+        .or(p2p_payg_route)
         .or(pg_route)
+        .or(method_fallback(&ROUTES))
         .recover(|err| handle_rejection(err, COMMAND.dev()));
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(HEADERS)
         .allow_methods(vec!["GET", "POST"]);
 
-    let ip_address: Ipv4Addr = host.parse().unwrap_or(Ipv4Addr::LOCALHOST);
+    let ip_address: IpAddr = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
     warp::serve(routes.with(cors)).run((ip_address, port)).await;
 }
 
@@ -69,6 +92,7 @@ pub async fn query_handler(id: String, query: Value) -> WebResult<impl Reply> {
     let channel = StateChannel::get(&id).await?;
     let channel_id = channel.id;
     let state = channel.next_query(COMMAND.signer())?;
+    let count = state.count;
 
     let raw_state = serde_json::to_string(&state.to_json()).unwrap();
     let raw_query = serde_json::to_string(&query).unwrap();
@@ -76,16 +100,35 @@ pub async fn query_handler(id: String, query: Value) -> WebResult<impl Reply> {
 
     match res {
         Ok(fulldata) => {
-            let (query, raw_data) = (&fulldata[0], &fulldata[1]);
+            let (query, raw_state) = match (fulldata.get(0), fulldata.get(1)) {
+                (Some(query), Some(raw_state)) => (query, raw_state),
+                _ => return Err(reject::custom(Error::ServiceException)),
+            };
 
-            // save state to db.
-            let state = QueryState::from_json(&raw_data).unwrap();
+            // save the signed state, next query will start from this count.
+            let state = QueryState::from_json(raw_state).map_err(|e| reject::custom(e))?;
+            if state.channel_id != channel_id || state.count != count {
+                warn!("Indexer returned mismatch state: {} {}", state.channel_id, state.count);
+                return Err(reject::custom(Error::ServiceException));
+            }
             StateChannel::renew(channel_id, state).await;
 
-            Ok(reply::json(&query))
+            Ok(reply::json(query))
         }
         Err(err) => {
-            info!("Open Error: {}", err);
+            info!("Query Error: {}", err);
+            Err(reject::custom(Error::ServiceException))
+        }
+    }
+}
+
+pub async fn p2p_payg_handler(peer: String, id: String, state: String, query: Value) -> WebResult<impl Reply> {
+    let raw_query = serde_json::to_string(&query).unwrap();
+    let indexer = IndexerNetwork::P2p(peer);
+    match indexer.query(id, raw_query, state).await {
+        Ok(fulldata) => Ok(reply::json(&fulldata)),
+        Err(err) => {
+            info!("P2P Query Error: {}", err);
             Err(reject::custom(Error::ServiceException))
         }
     }
@@ -97,7 +140,7 @@ pub async fn open_payg(payload: Value) -> WebResult<impl Reply> {
         .and_then(|v| v.as_str())
         .and_then(|v| v.parse().ok())
         .ok_or(reject::custom(Error::InvalidRequest))?;
-    let indexer = payload
+    let indexer: Address = payload
         .get("indexer")
         .and_then(|v| v.as_str())
         .and_then(|v| v.parse().ok())
@@ -130,6 +173,30 @@ pub async fn open_payg(payload: Value) -> WebResult<impl Reply> {
         .ok_or(reject::custom(Error::InvalidRequest))?;
     let sign = convert_string_to_sign(callback);
 
+    // check the params, avoid opening a dead channel.
+    if amount < COMMAND.min_channel_amount() {
+        let msg = format!("amount must be at least {}", COMMAND.min_channel_amount());
+        return Err(reject::custom(Error::InvalidParams(msg)));
+    }
+    let now = U256::from(Utc::now().timestamp().max(0) as u64);
+    if expiration <= now {
+        return Err(reject::custom(Error::InvalidParams(
+            "expiration is in the past".to_owned(),
+        )));
+    }
+    if expiration > now + COMMAND.max_channel_expiration() {
+        let msg = format!(
+            "expiration exceeds {} seconds from now",
+            COMMAND.max_channel_expiration()
+        );
+        return Err(reject::custom(Error::InvalidParams(msg)));
+    }
+    if indexer == consumer {
+        return Err(reject::custom(Error::InvalidParams(
+            "indexer is the consumer".to_owned(),
+        )));
+    }
+
     // check the sign.
     let msg = encode(&[channel_id.into_token(), amount.into_token()]);
     let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
@@ -167,3 +234,28 @@ pub async fn open_payg(payload: Value) -> WebResult<impl Reply> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{channel, indexer_counts};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn sequential_queries_advance_the_count() {
+        let id = channel(100).await;
+        let query = json!({ "query": "query { _metadata { chain } }" });
+
+        for count in 1..=2u64 {
+            let res = query_handler(id.clone(), query.clone()).await;
+            assert!(res.is_ok(), "query {}", count);
+            let next = StateChannel::get(&id)
+                .await
+                .unwrap()
+                .next_query(COMMAND.signer())
+                .unwrap();
+            assert_eq!(next.count, U256::from(count + 1));
+        }
+        assert_eq!(indexer_counts(&id), vec![U256::from(1u64), U256::from(2u64)]);
+    }
+}
@@ -0,0 +1,67 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+//! Per-deployment allow/deny lists of consumer addresses.
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use subql_proxy_utils::error::Error;
+use web3::types::Address;
+
+use crate::cli::COMMAND;
+use crate::project::{deployment_key, deployment_keys};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConsumerAccess {
+    /// empty allowlist means open access.
+    pub allow: Vec<Address>,
+    /// always takes precedence over the allowlist.
+    pub deny: Vec<Address>,
+}
+
+/// The consumer access of deployments, deployment id => access.
+pub static ACCESS: Lazy<HashMap<String, ConsumerAccess>> = Lazy::new(|| {
+    if let Some(path) = COMMAND.consumer_access() {
+        let file = std::fs::File::open(path).expect("Read consumer access failed");
+        let access: HashMap<String, ConsumerAccess> =
+            serde_json::from_reader(std::io::BufReader::new(file)).expect("Invalid consumer access");
+        access
+            .into_iter()
+            .map(|(k, v)| (deployment_key(&k).unwrap_or(k), v))
+            .collect()
+    } else {
+        HashMap::new()
+    }
+});
+
+/// Check the consumer is allowed to open channel or query the deployment.
+pub fn check_consumer(deployment: &str, consumer: &Address) -> Result<(), Error> {
+    let key = deployment_key(deployment).unwrap_or_else(|_| deployment.to_owned());
+    match ACCESS.get(&key) {
+        Some(access) if access.deny.contains(consumer) => Err(Error::ConsumerNotAllowed),
+        Some(access) if !access.allow.is_empty() && !access.allow.contains(consumer) => Err(Error::ConsumerNotAllowed),
+        _ => Ok(()),
+    }
+}
+
+/// Same as `check_consumer`, with deployment id bytes.
+pub fn check_consumer_by_bytes(deployment_id: &[u8; 32], consumer: &Address) -> Result<(), Error> {
+    let [key, ..] = deployment_keys(deployment_id);
+    check_consumer(&key, consumer)
+}
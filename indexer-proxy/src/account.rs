@@ -18,20 +18,37 @@
 
 use once_cell::sync::Lazy;
 use secp256k1::{SecretKey, ONE_KEY};
-use serde_json::json;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use subql_proxy_utils::{error::Error, request::graphql_request, types::Result};
 use tokio::sync::RwLock;
 use web3::{
-    signing::{Key, SecretKeyRef},
-    types::Address,
+    contract::tokens::Tokenizable,
+    ethabi::{decode, encode, ParamType},
+    signing::{keccak256, Key, SecretKeyRef},
+    transports::Http,
+    types::{Address, Bytes, CallRequest},
+    Web3,
 };
 
 use crate::cli::COMMAND;
 
+/// IndexerRegistry contract function of the indexer's controller.
+const CONTROLLER_FN: &str = "indexerToController(address)";
+
+/// Max number of controller keys kept for rotation.
+const MAX_CONTROLLERS: usize = 8;
+
 pub struct Account {
     pub indexer: Address,
+    /// The active controller.
     pub controller: Address,
     pub controller_sk: SecretKey,
+    /// All the known controller keys, the latest first.
+    pub controllers: Vec<SecretKey>,
+    /// If the active controller is the controller on chain.
+    pub matched: bool,
 }
 
 impl Default for Account {
@@ -42,12 +59,80 @@ impl Default for Account {
             indexer: Address::default(),
             controller,
             controller_sk,
+            controllers: vec![],
+            matched: false,
         }
     }
 }
 
+impl Account {
+    /// The key of active controller, failure if none of the keys is the controller on chain.
+    pub fn controller_key(&self) -> Result<SecretKeyRef<'_>> {
+        if self.matched {
+            Ok(SecretKeyRef::new(&self.controller_sk))
+        } else {
+            warn!("Controller mismatch, none of the controller keys is the controller on chain");
+            Err(Error::ServiceException)
+        }
+    }
+
+    fn activate(&mut self, sk: SecretKey) {
+        let controller = SecretKeyRef::new(&sk).address();
+        if controller != self.controller {
+            info!("Active controller: {:?}", controller);
+        }
+        self.controller = controller;
+        self.controller_sk = sk;
+        self.matched = true;
+    }
+}
+
 pub static ACCOUNT: Lazy<RwLock<Account>> = Lazy::new(|| RwLock::new(Account::default()));
 
+/// If the account metadata fetched, the default account is not able to sign.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Fetch the account metadata at startup, retry with backoff.
+pub async fn init_account() -> Result<()> {
+    let attempts = COMMAND.account_retry().max(1);
+    let mut last_err = Error::ServiceException;
+    for i in 1..=attempts {
+        match fetch_account_metadata().await {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                warn!("Fetch account metadata failure ({}/{}): {}", i, attempts, err);
+                last_err = err;
+            }
+        }
+        if i < attempts {
+            tokio::time::sleep(Duration::from_secs(COMMAND.account_retry_interval() * i)).await;
+        }
+    }
+    Err(last_err)
+}
+
+/// Keep fetching the account metadata in background until it is available.
+pub fn fetch_in_background() {
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(Duration::from_secs(COMMAND.account_retry_interval())).await;
+            match fetch_account_metadata().await {
+                Ok(()) => break,
+                Err(err) => warn!("Fetch account metadata failure: {}", err),
+            }
+        }
+    });
+}
+
+/// The routes need the account should fail when the account metadata not available.
+pub fn ensure_ready() -> Result<()> {
+    if READY.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        Err(Error::AccountUnavailable)
+    }
+}
+
 pub async fn fetch_account_metadata() -> Result<()> {
     let url = COMMAND.service_url();
     let query = json!({"query": "query { accountMetadata { indexer controller } }" });
@@ -62,43 +147,159 @@ pub async fn fetch_account_metadata() -> Result<()> {
         .parse()
         .map_err(|_e| Error::InvalidServiceEndpoint)?;
 
-    let sk = value
+    let controller = value
         .pointer("/data/accountMetadata/controller")
         .ok_or(Error::InvalidController)?
         .as_str()
         .unwrap_or("")
         .trim();
-    let sk_values = serde_json::from_str::<serde_json::Value>(&sk).map_err(|_e| Error::InvalidController)?;
-    if sk_values.get("iv").is_none() || sk_values.get("content").is_none() {
+    // one encrypted key, or the encrypted keys (latest first) when rotating.
+    let encrypted = match serde_json::from_str::<Value>(controller).map_err(|_e| Error::InvalidController)? {
+        Value::Array(keys) => keys,
+        key => vec![key],
+    };
+    let mut keys = encrypted
+        .iter()
+        .map(decrypt_controller)
+        .collect::<Result<Vec<SecretKey>>>()?;
+    if keys.is_empty() {
         return Err(Error::InvalidController);
     }
+    info!(
+        "indexer: {:?}, controller: {:?}",
+        indexer,
+        SecretKeyRef::new(&keys[0]).address()
+    );
+
+    let mut account = ACCOUNT.write().await;
+    // keep the previous keys, the chain maybe not switched to the latest one yet.
+    if account.indexer == indexer {
+        for sk in account.controllers.iter() {
+            if !keys.contains(sk) {
+                keys.push(*sk);
+            }
+        }
+    }
+    keys.truncate(MAX_CONTROLLERS);
+    if COMMAND.indexer_registry().is_none() {
+        account.activate(keys[0]);
+    } else if account.indexer != indexer || !keys.contains(&account.controller_sk) {
+        // the active key is gone, not sign until the controller on chain selected.
+        account.matched = false;
+    }
+    account.indexer = indexer;
+    account.controllers = keys;
+    drop(account);
+    READY.store(true, Ordering::Relaxed);
+
+    // keep the current choice of chain, until the new one selected.
+    if let Err(err) = select_controller().await {
+        warn!("Select the controller on chain failure: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Decrypt the controller key `{ iv, content }` from coordinator.
+fn decrypt_controller(value: &Value) -> Result<SecretKey> {
     let sk = COMMAND.decrypt(
-        sk_values["iv"].as_str().ok_or(Error::InvalidController)?,
-        sk_values["content"].as_str().ok_or(Error::InvalidController)?,
+        value["iv"].as_str().ok_or(Error::InvalidController)?,
+        value["content"].as_str().ok_or(Error::InvalidController)?,
     )?; // with 0x...
+    let bytes = hex::decode(sk.trim_start_matches("0x")).map_err(|_e| Error::InvalidController)?;
+    SecretKey::from_slice(&bytes).map_err(|_e| Error::InvalidController)
+}
 
-    let controller_sk = SecretKey::from_slice(&hex::decode(&sk[2..]).map_err(|_e| Error::InvalidController)?)
-        .map_err(|_e| Error::InvalidController)?;
-
-    let controller = SecretKeyRef::new(&controller_sk).address();
-    info!("indexer: {:?}, controller: {:?}", indexer, controller);
+/// The controller of indexer on chain.
+async fn chain_controller(registry: Address, indexer: Address) -> Result<Address> {
+    let web3 = Web3::new(Http::new(COMMAND.web3()).map_err(|_| Error::ServiceException)?);
+    let mut data = keccak256(CONTROLLER_FN.as_bytes())[..4].to_vec();
+    data.extend(encode(&[indexer.into_token()]));
+    let result = web3
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(registry),
+                data: Some(Bytes(data)),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Controller on-chain query failure: {}", e);
+            Error::ServiceException
+        })?;
+    decode(&[ParamType::Address], &result.0)
+        .ok()
+        .and_then(|tokens| tokens.into_iter().next())
+        .and_then(|token| token.into_address())
+        .ok_or(Error::ServiceException)
+}
 
-    let new_account = Account {
-        indexer,
-        controller,
-        controller_sk,
+/// Activate the controller key which is the controller on chain, signing is rejected if none matches.
+async fn select_controller() -> Result<()> {
+    let registry = match COMMAND.indexer_registry() {
+        Some(registry) => registry,
+        None => return Ok(()),
     };
-    let mut account = ACCOUNT.write().await;
-    *account = new_account;
+    let indexer = ACCOUNT.read().await.indexer;
+    let controller = chain_controller(registry, indexer).await?;
 
+    let mut account = ACCOUNT.write().await;
+    let active = account
+        .controllers
+        .iter()
+        .find(|sk| SecretKeyRef::new(*sk).address() == controller)
+        .copied();
+    match active {
+        Some(sk) => account.activate(sk),
+        None => {
+            if account.matched {
+                warn!("Controller mismatch, the controller on chain is {:?}", controller);
+            }
+            account.matched = false;
+        }
+    }
     Ok(())
 }
 
-pub async fn get_indexer() -> String {
-    format!("{:?}", ACCOUNT.read().await.indexer)
+/// Keep the active controller same as the chain, fetch the keys again if none matches.
+pub fn refresh_controller() {
+    let interval = COMMAND.controller_refresh();
+    if interval == 0 || COMMAND.indexer_registry().is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            if ensure_ready().is_err() {
+                continue;
+            }
+            if let Err(err) = select_controller().await {
+                warn!("Refresh the controller failure: {}", err);
+                continue;
+            }
+            if !ACCOUNT.read().await.matched {
+                if let Err(err) = fetch_account_metadata().await {
+                    warn!("Fetch account metadata failure: {}", err);
+                }
+            }
+        }
+    });
+}
+
+/// Set the account of tests, ready to sign with the controller key.
+#[cfg(test)]
+pub async fn init_test_account(indexer: Address, sk: SecretKey) {
+    let mut account = ACCOUNT.write().await;
+    account.indexer = indexer;
+    account.controllers = vec![sk];
+    account.activate(sk);
+    READY.store(true, Ordering::Relaxed);
 }
 
-pub fn sign_message(_msg: &[u8]) -> String {
-    // TODO sign message to prove the result.
-    "".to_owned()
+pub async fn get_indexer() -> String {
+    format!("{:?}", ACCOUNT.read().await.indexer)
 }
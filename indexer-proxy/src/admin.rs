@@ -0,0 +1,108 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::{json, Value};
+use subql_proxy_utils::{
+    error::Error,
+    payg::{build_checkpoint_tx, QueryState},
+};
+use warp::{reject, Filter, Rejection};
+use web3::types::U256;
+
+use crate::claim::{send_tx, wait_confirmed};
+use crate::cli::COMMAND;
+use crate::event::{emit_tx, ChannelEvent};
+use crate::payg::ChannelEntry;
+
+/// Only the requests with `Authorization: Bearer <admin-token>`, all forbidden if no admin token.
+pub fn with_admin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|token: Option<String>| async move {
+            match (COMMAND.admin_token(), token) {
+                (Some(admin), Some(token)) if token == format!("Bearer {}", admin) => Ok(()),
+                _ => Err(reject::custom(Error::AdminForbidden)),
+            }
+        })
+        .untuple_one()
+}
+
+/// All the channels with their latest state.
+pub async fn list_channels() -> Value {
+    let channels: Vec<Value> = ChannelEntry::list()
+        .await
+        .into_iter()
+        .map(|(id, channel)| {
+            json!({
+                "channelId": format!("{:#X}", id),
+                "consumer": format!("{:?}", channel.consumer),
+                "deployment": channel.deployment,
+                "amount": channel.amount.to_string(),
+                "expiration": channel.expiration.to_string(),
+                "count": channel.count.to_string(),
+                "closed": channel.closed,
+                "state": channel.state,
+            })
+        })
+        .collect();
+    Value::from(channels)
+}
+
+/// Operator override of the stuck or abandoned channel: no more queries accepted,
+/// and the latest counter-signed state is checkpointed on chain.
+pub async fn force_close(channel_id: &str) -> Result<Value, Error> {
+    let id: U256 = channel_id.parse().map_err(|_| Error::InvalidRequest)?;
+    let channel = ChannelEntry::get(&id).await.ok_or(Error::ChannelNotFound)?;
+    let state = match &channel.state {
+        Some(state) => Some(QueryState::from_json(state)?),
+        None => None,
+    };
+    let contract = match (&state, COMMAND.state_channel()) {
+        (Some(_), None) => return Err(Error::InvalidParams("missing state channel contract".to_owned())),
+        (_, contract) => contract,
+    };
+
+    ChannelEntry::close(&id).await.ok_or(Error::ChannelNotFound)?;
+    info!("Channel {:#X} closed by admin", id);
+
+    // nothing to checkpoint if never queried.
+    let tx = match (state, contract) {
+        (Some(state), Some(contract)) => {
+            let tx_hash = send_tx(build_checkpoint_tx(&state, contract)).await.map_err(|e| {
+                warn!("Channel {:#X} checkpoint failure: {}", id, e);
+                Error::ServiceException
+            })?;
+            info!("Channel {:#X} checkpoint sent, tx: {:?}", id, tx_hash);
+            // not block the operator, the event emitted once confirmed.
+            let count = state.count;
+            tokio::spawn(async move {
+                match wait_confirmed(tx_hash).await {
+                    Ok(_) => emit_tx(ChannelEvent::Checkpointed, id, &channel, count, tx_hash),
+                    Err(e) => warn!("Channel {:#X} checkpoint failure: {}", id, e),
+                }
+            });
+            Some(format!("{:?}", tx_hash))
+        }
+        _ => None,
+    };
+
+    Ok(json!({
+        "channelId": format!("{:#X}", id),
+        "closed": true,
+        "tx": tx,
+    }))
+}
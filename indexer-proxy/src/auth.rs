@@ -31,6 +31,7 @@ use warp::{
 };
 
 use crate::cli::COMMAND;
+use crate::scope::agreement_scope;
 
 const BEARER: &str = "Bearer ";
 // FIXME: use `secret_key` from commandline args
@@ -64,6 +65,9 @@ struct Claims {
     pub iat: i64,
     /// token expiration
     exp: i64,
+    /// access scope of the agreement
+    #[serde(default)]
+    pub scope: Option<String>,
 }
 
 type RequestHeader = HeaderMap<HeaderValue>;
@@ -85,20 +89,22 @@ pub fn create_jwt(payload: Payload) -> Result<String> {
         deployment_id: payload.deployment_id,
         iat: payload.timestamp,
         exp: expiration,
+        scope: agreement_scope(&payload.agreement),
     };
 
     encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET)).map_err(|_| Error::JWTTokenCreationError)
 }
 
-pub fn with_auth() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+pub fn with_auth() -> impl Filter<Extract = (String, Option<String>), Error = Rejection> + Clone {
     headers_cloned()
         .map(move |headers: RequestHeader| (headers))
         .and_then(authorize)
+        .untuple_one()
 }
 
-async fn authorize(headers: RequestHeader) -> WebResult<String> {
+async fn authorize(headers: RequestHeader) -> WebResult<(String, Option<String>)> {
     if !COMMAND.auth() {
-        return Ok(String::from(""));
+        return Ok((String::from(""), None));
     }
 
     match jwt_from_header(&headers) {
@@ -114,7 +120,7 @@ async fn authorize(headers: RequestHeader) -> WebResult<String> {
                 return Err(reject::custom(Error::JWTTokenExpiredError));
             }
 
-            Ok(decoded.claims.deployment_id)
+            Ok((decoded.claims.deployment_id, decoded.claims.scope))
         }
         Err(e) => return Err(reject::custom(e)),
     }
@@ -0,0 +1,134 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+//! Bounded in-memory cache of identical GraphQL queries, only for the authenticated queries.
+//! The concurrent identical queries also share one in-flight upstream request.
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use subql_proxy_utils::error::GraphQLServerError;
+use tokio::sync::Mutex;
+
+use crate::cli::COMMAND;
+
+struct Entry {
+    created: Instant,
+    accessed: Instant,
+    result: Value,
+}
+
+/// (deployment_id, normalized query) => cached upstream result.
+static CACHE: Lazy<Mutex<HashMap<(String, String), Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+type Flight = Shared<BoxFuture<'static, Result<Value, GraphQLServerError>>>;
+
+/// (deployment_id, normalized query) => (flight id, in-flight upstream request).
+/// A std mutex, never held across await, the removal after the flight must not be cancelled.
+static INFLIGHT: Lazy<std::sync::Mutex<HashMap<(String, String), (u64, Flight)>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// The id of next flight, tells the flight from the later one of the same key.
+static NEXT_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+/// Build the cache key, collapse the whitespaces of the query document.
+fn cache_key(deployment: &str, query: &Value) -> (String, String) {
+    let mut query = query.clone();
+    if let Some(document) = query.get("query").and_then(|q| q.as_str()) {
+        let normalized = document.split_whitespace().collect::<Vec<_>>().join(" ");
+        query["query"] = Value::String(normalized);
+    }
+    (deployment.to_owned(), query.to_string())
+}
+
+/// Get the cached result of the query if not expired.
+pub async fn get(deployment: &str, query: &Value) -> Option<Value> {
+    let ttl = COMMAND.query_cache_ttl();
+    if ttl == 0 {
+        return None;
+    }
+
+    let key = cache_key(deployment, query);
+    let mut cache = CACHE.lock().await;
+    let entry = cache.get_mut(&key)?;
+    if entry.created.elapsed() > Duration::from_secs(ttl) {
+        cache.remove(&key);
+        return None;
+    }
+    entry.accessed = Instant::now();
+    Some(entry.result.clone())
+}
+
+/// Cache the result of the query, evict the least recently used one when full.
+pub async fn insert(deployment: &str, query: &Value, result: &Value) {
+    let ttl = COMMAND.query_cache_ttl();
+    let size = COMMAND.query_cache_size();
+    if ttl == 0 || size == 0 {
+        return;
+    }
+
+    let key = cache_key(deployment, query);
+    let mut cache = CACHE.lock().await;
+    if !cache.contains_key(&key) && cache.len() >= size {
+        let ttl = Duration::from_secs(ttl);
+        cache.retain(|_, e| e.created.elapsed() <= ttl);
+        if cache.len() >= size {
+            let lru = cache.iter().min_by_key(|(_, e)| e.accessed).map(|(k, _)| k.clone());
+            if let Some(lru) = lru {
+                cache.remove(&lru);
+            }
+        }
+    }
+
+    let now = Instant::now();
+    cache.insert(
+        key,
+        Entry {
+            created: now,
+            accessed: now,
+            result: result.clone(),
+        },
+    );
+}
+
+/// Run the upstream request of the query, the concurrent identical queries wait the same request
+/// and receive the same result, rather than requesting upstream again.
+pub async fn single_flight<F>(deployment: &str, query: &Value, request: F) -> Result<Value, GraphQLServerError>
+where
+    F: Future<Output = Result<Value, GraphQLServerError>> + Send + 'static,
+{
+    let key = cache_key(deployment, query);
+    let (id, flight) = INFLIGHT
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_insert_with(|| (NEXT_FLIGHT.fetch_add(1, Ordering::Relaxed), request.boxed().shared()))
+        .clone();
+
+    // any waiter drives the request, even if the first one is cancelled.
+    let result = flight.await;
+
+    let mut inflight = INFLIGHT.lock().unwrap();
+    if inflight.get(&key).map(|(i, _)| *i == id).unwrap_or(false) {
+        inflight.remove(&key);
+    }
+    result
+}
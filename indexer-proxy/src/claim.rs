@@ -0,0 +1,181 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use chrono::prelude::Utc;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use subql_proxy_utils::payg::{build_checkpoint_tx, build_claim_tx, QueryState};
+use web3::{
+    transports::Http,
+    types::{Address, CallRequest, TransactionParameters, H256, U256, U64},
+    Web3,
+};
+
+use crate::account::{ensure_ready, ACCOUNT};
+use crate::cli::COMMAND;
+use crate::event::{emit_tx, ChannelEvent};
+use crate::payg::ChannelEntry;
+
+/// Max seconds to wait before retry the failed claim.
+const CLAIM_BACKOFF_MAX: u64 = 3600;
+
+/// Seconds between the polls of transaction receipt.
+const RECEIPT_POLL_INTERVAL: u64 = 5;
+
+/// Max polls of transaction receipt, give up (and retry later) if not mined.
+const RECEIPT_POLLS: u32 = 60;
+
+/// Start the background task to claim the expired channels, disabled if the interval is zero.
+pub fn start() {
+    let interval = COMMAND.claim_interval();
+    if interval == 0 {
+        return;
+    }
+    let contract = match COMMAND.state_channel() {
+        Some(contract) => contract,
+        None => {
+            warn!("Auto claim disabled, the state channel contract is missing");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        // channel id => (failed times, next retry time).
+        let mut failures: HashMap<U256, (u32, Instant)> = HashMap::new();
+        // the channels checkpointed with the latest state, not again when retry the claim.
+        let mut checkpointed: HashSet<U256> = HashSet::new();
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            if ensure_ready().is_err() {
+                continue;
+            }
+
+            let now = U256::from(Utc::now().timestamp().max(0));
+            for (id, channel) in ChannelEntry::list().await {
+                if channel.expiration > now {
+                    continue;
+                }
+                if let Some((_, next)) = failures.get(&id) {
+                    if *next > Instant::now() {
+                        continue;
+                    }
+                }
+
+                if !checkpointed.contains(&id) {
+                    if let Some(state) = &channel.state {
+                        match checkpoint(contract, state).await {
+                            Ok((count, tx_hash)) => {
+                                info!("Channel {:#X} checkpointed, tx: {:?}", id, tx_hash);
+                                emit_tx(ChannelEvent::Checkpointed, id, &channel, count, tx_hash);
+                                checkpointed.insert(id);
+                            }
+                            Err(e) => {
+                                let backoff = backoff(&mut failures, id, interval);
+                                warn!("Channel {:#X} checkpoint failure: {}, retry after {}s", id, e, backoff);
+                                continue;
+                            }
+                        }
+                    }
+                }
+
+                match claim(contract, id).await {
+                    Ok(tx_hash) => {
+                        info!("Channel {:#X} claimed, tx: {:?}", id, tx_hash);
+                        emit_tx(ChannelEvent::Claimed, id, &channel, channel.count, tx_hash);
+                        ChannelEntry::remove(&id).await;
+                        failures.remove(&id);
+                        checkpointed.remove(&id);
+                    }
+                    Err(e) => {
+                        let backoff = backoff(&mut failures, id, interval);
+                        warn!("Channel {:#X} claim failure: {}, retry after {}s", id, e, backoff);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Count the failure of channel, return the seconds to wait before retry.
+fn backoff(failures: &mut HashMap<U256, (u32, Instant)>, id: U256, interval: u64) -> u64 {
+    let times = failures.get(&id).map(|(t, _)| t + 1).unwrap_or(1);
+    let backoff = interval.saturating_mul(1 << times.min(16)).min(CLAIM_BACKOFF_MAX);
+    failures.insert(id, (times, Instant::now() + Duration::from_secs(backoff)));
+    backoff
+}
+
+/// Checkpoint the latest state on chain before claim, otherwise the claim settles the older one.
+/// Return the checkpointed count and the tx hash.
+async fn checkpoint(contract: Address, state: &serde_json::Value) -> web3::Result<(U256, H256)> {
+    let state = QueryState::from_json(state).map_err(|e| web3::Error::Decoder(e.to_string()))?;
+    let tx_hash = send_tx_confirmed(build_checkpoint_tx(&state, contract)).await?;
+    Ok((state.count, tx_hash))
+}
+
+/// Send the claim transaction signed by controller, return the tx hash.
+async fn claim(contract: Address, channel_id: U256) -> web3::Result<H256> {
+    send_tx_confirmed(build_claim_tx(channel_id, contract)).await
+}
+
+/// Send the transaction and wait for its receipt, failure if reverted or not mined in time.
+pub async fn send_tx_confirmed(tx: TransactionParameters) -> web3::Result<H256> {
+    let tx_hash = send_tx(tx).await?;
+    wait_confirmed(tx_hash).await
+}
+
+/// Wait for the receipt of the sent transaction, failure if reverted or not mined in time.
+pub async fn wait_confirmed(tx_hash: H256) -> web3::Result<H256> {
+    let web3 = Web3::new(Http::new(COMMAND.web3())?);
+    for _ in 0..RECEIPT_POLLS {
+        if let Some(receipt) = web3.eth().transaction_receipt(tx_hash).await? {
+            if receipt.status != Some(U64::one()) {
+                return Err(web3::Error::InvalidResponse(format!("tx {:?} reverted", tx_hash)));
+            }
+            return Ok(tx_hash);
+        }
+        tokio::time::sleep(Duration::from_secs(RECEIPT_POLL_INTERVAL)).await;
+    }
+    Err(web3::Error::InvalidResponse(format!("tx {:?} not mined", tx_hash)))
+}
+
+/// Sign the transaction by controller and send it, return the tx hash.
+pub async fn send_tx(mut tx: TransactionParameters) -> web3::Result<H256> {
+    let web3 = Web3::new(Http::new(COMMAND.web3())?);
+
+    let account = ACCOUNT.read().await;
+    // logged as the controller mismatch.
+    account.controller_key().map_err(|_| web3::Error::Internal)?;
+    let (controller, sk) = (account.controller, account.controller_sk);
+    drop(account);
+
+    tx.gas = web3
+        .eth()
+        .estimate_gas(
+            CallRequest {
+                from: Some(controller),
+                to: tx.to,
+                data: Some(tx.data.clone()),
+                ..Default::default()
+            },
+            None,
+        )
+        .await?;
+    COMMAND.gas_price().apply(&web3, &mut tx).await?;
+    let signed = web3.accounts().sign_transaction(tx, &sk).await?;
+    web3.eth().send_raw_transaction(signed.raw_transaction).await
+}
@@ -19,8 +19,10 @@
 use once_cell::sync::Lazy;
 use openssl::symm::{decrypt, Cipher};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-use subql_proxy_utils::error::Error;
+use subql_proxy_utils::{constants::BLOCKED_PASSTHROUGH_HEADERS, error::Error, payg::GasPrice, query::QueryLimits};
+use web3::types::{Address, U256};
 
 #[cfg(feature = "p2p")]
 use subql_proxy_utils::p2p::libp2p::Multiaddr;
@@ -30,8 +32,13 @@ const SEED_ADDR: &'static str = "/ip4/0.0.0.0/tcp/7000";
 #[cfg(feature = "p2p")]
 const P2P_ADDR: &'static str = "/ip4/0.0.0.0/tcp/0";
 
+#[cfg(not(test))]
 pub static COMMAND: Lazy<CommandLineArgs> = Lazy::new(|| CommandLineArgs::from_args());
 
+/// The unit tests share one command line, the services are the local mocks of tests.
+#[cfg(test)]
+pub static COMMAND: Lazy<CommandLineArgs> = Lazy::new(|| CommandLineArgs::from_iter(crate::mock::args()));
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Indexer Proxy", about = "Command line for starting indexer proxy server")]
 pub struct CommandLineArgs {
@@ -41,10 +48,16 @@ pub struct CommandLineArgs {
     /// Coordinator service endpoint
     #[structopt(long = "service-url")]
     pub service_url: String,
+    /// Other coordinator service endpoints, serve their projects too
+    #[structopt(long = "extra-service-url")]
+    pub extra_service_urls: Vec<String>,
+    /// Coordinator service endpoints per deployment (JSON file), preferred over the registered ones.
+    #[structopt(long = "project-coordinators")]
+    pub project_coordinators: Option<String>,
     /// Secret key for generating auth token
     #[structopt(long = "secret-key")]
     pub secret_key: String,
-    /// IP address for the server
+    /// IP address for the server, IPv4 or IPv6
     #[structopt(long = "host", default_value = "127.0.0.1")]
     pub host: String,
     /// enable auth
@@ -56,18 +69,144 @@ pub struct CommandLineArgs {
     /// enable debug mode
     #[structopt(short = "d", long = "debug")]
     pub debug: bool,
+    /// log output format
+    #[structopt(long = "log-format", default_value = "text", possible_values = &["text", "json"])]
+    pub log_format: String,
     /// enable dev mode
     #[structopt(long = "dev")]
     pub dev: bool,
-    /// Rpc binding socket address.
+    /// P2P JSON-RPC binding socket address, it controls the node, never bind it publicly.
     #[structopt(short = "r", long = "p2p-rpc", default_value = "127.0.0.1:7001")]
     pub p2p_rpc: SocketAddr,
-    /// Rpc binding socket address.
+    /// P2P JSON-RPC websocket binding socket address, never bind it publicly.
     #[structopt(short = "w", long = "p2p-ws")]
     pub p2p_ws: Option<SocketAddr>,
+    /// Bearer token of the P2P JSON-RPC, required if it binds on non-loopback.
+    #[structopt(long = "p2p-rpc-token", env = "P2P_RPC_TOKEN", hide_env_values = true)]
+    pub p2p_rpc_token: Option<String>,
     /// Check if running as relay.
     #[structopt(short = "e", long = "p2p-relay")]
     pub p2p_relay: bool,
+    /// Max number of concurrent inbound p2p requests per peer.
+    #[structopt(long = "p2p-max-inflight", default_value = "8")]
+    pub p2p_max_inflight: usize,
+    /// Capacity of the P2P JSON-RPC queues, the ws pushes over it are dropped and requests answered busy.
+    #[structopt(long = "p2p-rpc-capacity", default_value = "128")]
+    pub p2p_rpc_capacity: usize,
+    /// P2P identity key file, the P2P_KEY env is preferred if set.
+    #[structopt(long = "p2p-key", default_value = "indexer.key", parse(from_os_str))]
+    pub p2p_key: PathBuf,
+    /// P2P addresses dialed on start and redialed when disconnected.
+    #[structopt(long = "p2p-bootstrap")]
+    pub p2p_bootstrap: Vec<String>,
+    /// Fallback price when coordinator not return a usable lastPrice.
+    #[structopt(long = "fallback-price", parse(try_from_str = U256::from_dec_str))]
+    pub fallback_price: Option<U256>,
+    /// Pinned CA certificate (PEM file) of upstream query services.
+    #[structopt(long = "upstream-ca")]
+    pub upstream_ca: Option<String>,
+    /// Allowed response fields of JWT scopes per deployment (JSON file).
+    #[structopt(long = "scope-fields")]
+    pub scope_fields: Option<String>,
+    /// Scope of the agreements, agreement address => scope (JSON file).
+    #[structopt(long = "agreement-scopes")]
+    pub agreement_scopes: Option<String>,
+    /// Allow/deny lists of consumer addresses per deployment (JSON file).
+    #[structopt(long = "consumer-access")]
+    pub consumer_access: Option<String>,
+    /// Persisted queries (JSON file of hash => query), only they are allowed on query route if set.
+    #[structopt(long = "persisted-queries")]
+    pub persisted_queries: Option<String>,
+    /// Request headers copied to upstream, hop-by-hop and auth headers are always blocked.
+    #[structopt(long = "passthrough-header")]
+    pub passthrough_headers: Vec<String>,
+    /// Max number of aliases in a GraphQL query, 0 is no limit.
+    #[structopt(long = "max-aliases", default_value = "0")]
+    pub max_aliases: usize,
+    /// Max size of the query body in bytes, 0 is no limit.
+    #[structopt(long = "max-query-bytes", default_value = "0")]
+    pub max_query_bytes: usize,
+    /// Max nesting depth of the query, 0 is no limit.
+    #[structopt(long = "max-query-depth", default_value = "0")]
+    pub max_query_depth: usize,
+    /// Max attempts of fetching the account metadata at startup.
+    #[structopt(long = "account-retry", default_value = "5")]
+    pub account_retry: u64,
+    /// Seconds between the attempts of fetching the account metadata, grows with attempts.
+    #[structopt(long = "account-retry-interval", default_value = "5")]
+    pub account_retry_interval: u64,
+    /// Total upstream attempts of one request, shared by all its retries.
+    #[structopt(long = "upstream-retry-budget", default_value = "1")]
+    pub upstream_retry_budget: u32,
+    /// Seconds deadline of all upstream attempts of one request, 0 is no deadline.
+    #[structopt(long = "upstream-deadline", default_value = "0")]
+    pub upstream_deadline: u64,
+    /// Disable the PAYG and state channel routes, only serve the agreement queries.
+    #[structopt(long = "disable-payg", alias = "read-only")]
+    pub disable_payg: bool,
+    /// Start without account metadata and keep fetching it in background.
+    #[structopt(long = "start-degraded")]
+    pub start_degraded: bool,
+    /// Verify the state channel is open on chain before the first query.
+    #[structopt(long = "verify-onchain")]
+    pub verify_onchain: bool,
+    /// Accept the EIP-1271 signatures of smart contract wallets when open channel.
+    #[structopt(long = "eip1271")]
+    pub eip1271: bool,
+    /// Web3 endpoint for the on-chain verification.
+    #[structopt(long = "web3", default_value = "http://127.0.0.1:8545")]
+    pub web3: String,
+    /// State channel contract, the on-chain verification needs it.
+    #[structopt(long = "state-channel")]
+    pub state_channel: Option<Address>,
+    /// IndexerRegistry contract, the controller on chain is selected from the controller keys.
+    #[structopt(long = "indexer-registry")]
+    pub indexer_registry: Option<Address>,
+    /// Seconds between checking the controller on chain, 0 is disabled.
+    #[structopt(long = "controller-refresh", default_value = "60")]
+    pub controller_refresh: u64,
+    /// Max number of concurrent queries per state channel.
+    #[structopt(long = "channel-max-inflight", default_value = "1")]
+    pub channel_max_inflight: usize,
+    /// Max number of open state channels per consumer, 0 is unlimited.
+    #[structopt(long = "max-channels-per-consumer", default_value = "0")]
+    pub max_channels_per_consumer: usize,
+    /// Charge the PAYG queries rejected by upstream as bad request (4xx).
+    #[structopt(long = "charge-bad-request")]
+    pub charge_bad_request: bool,
+    /// Seconds to cache identical authenticated queries, 0 is disabled.
+    #[structopt(long = "query-cache-ttl", default_value = "0")]
+    pub query_cache_ttl: u64,
+    /// Max number of cached queries.
+    #[structopt(long = "query-cache-size", default_value = "1000")]
+    pub query_cache_size: usize,
+    /// Stream the upstream responses to client without buffering, the cache not apply, the scoped deployments are buffered.
+    #[structopt(long = "stream-response")]
+    pub stream_response: bool,
+    /// Webhook to post the state channel lifecycle events.
+    #[structopt(long = "event-webhook")]
+    pub event_webhook: Option<String>,
+    /// Seconds between the scans to claim the expired channels on chain (checkpoint the latest state first), 0 is disabled.
+    #[structopt(long = "claim-interval", default_value = "0")]
+    pub claim_interval: u64,
+    /// Log the query bodies at DEBUG, otherwise only the query hashes.
+    #[structopt(long = "log-queries")]
+    pub log_queries: bool,
+    /// Variable keys redacted in the logged query bodies.
+    #[structopt(long = "redact-variable")]
+    pub redact_variables: Vec<String>,
+    /// Token of the admin routes (`Authorization: Bearer <token>`), the admin routes are forbidden if not set.
+    #[structopt(long = "admin-token", env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// Legacy gas price (wei) of the transactions, the EIP-1559 fees are used if unset
+    #[structopt(long = "gas-price", parse(try_from_str = U256::from_dec_str))]
+    pub gas_price: Option<U256>,
+    /// Max fee per gas (wei) of the transactions, estimated from the fee history if unset
+    #[structopt(long = "max-fee-per-gas", parse(try_from_str = U256::from_dec_str))]
+    pub max_fee_per_gas: Option<U256>,
+    /// Max priority fee per gas (wei) of the transactions, estimated from the fee history if unset
+    #[structopt(long = "max-priority-fee-per-gas", parse(try_from_str = U256::from_dec_str))]
+    pub max_priority_fee_per_gas: Option<U256>,
 }
 
 impl CommandLineArgs {
@@ -79,6 +218,16 @@ impl CommandLineArgs {
         &self.service_url
     }
 
+    pub fn service_urls(&self) -> Vec<&str> {
+        let mut urls = vec![self.service_url.as_str()];
+        urls.extend(self.extra_service_urls.iter().map(|u| u.as_str()));
+        urls
+    }
+
+    pub fn project_coordinators(&self) -> Option<&str> {
+        self.project_coordinators.as_deref()
+    }
+
     pub fn decrypt(&self, iv: &str, ciphertext: &str) -> Result<String, Error> {
         let iv = hex::decode(iv).map_err(|_| Error::InvalidEncrypt)?;
         let ctext = hex::decode(ciphertext).map_err(|_| Error::InvalidEncrypt)?;
@@ -97,6 +246,10 @@ impl CommandLineArgs {
         self.debug
     }
 
+    pub fn log_json(&self) -> bool {
+        self.log_format == "json"
+    }
+
     pub fn auth(&self) -> bool {
         self.auth
     }
@@ -113,10 +266,168 @@ impl CommandLineArgs {
         self.p2p_ws
     }
 
+    pub fn rpc_token(&self) -> Option<String> {
+        self.p2p_rpc_token.clone()
+    }
+
+    #[cfg(feature = "p2p")]
+    pub fn p2p_max_inflight(&self) -> usize {
+        self.p2p_max_inflight
+    }
+
+    #[cfg(feature = "p2p")]
+    pub fn p2p_rpc_capacity(&self) -> usize {
+        self.p2p_rpc_capacity
+    }
+
+    pub fn p2p_key(&self) -> &Path {
+        &self.p2p_key
+    }
+
     pub fn token_duration(&self) -> i64 {
         self.token_duration
     }
 
+    pub fn fallback_price(&self) -> Option<U256> {
+        self.fallback_price
+    }
+
+    pub fn upstream_ca(&self) -> Option<&str> {
+        self.upstream_ca.as_deref()
+    }
+
+    pub fn scope_fields(&self) -> Option<&str> {
+        self.scope_fields.as_deref()
+    }
+
+    pub fn agreement_scopes(&self) -> Option<&str> {
+        self.agreement_scopes.as_deref()
+    }
+
+    pub fn consumer_access(&self) -> Option<&str> {
+        self.consumer_access.as_deref()
+    }
+
+    pub fn persisted_queries(&self) -> Option<&str> {
+        self.persisted_queries.as_deref()
+    }
+
+    pub fn passthrough_headers(&self) -> Vec<String> {
+        self.passthrough_headers
+            .iter()
+            .map(|h| h.to_lowercase())
+            .filter(|h| !BLOCKED_PASSTHROUGH_HEADERS.contains(&h.as_str()))
+            .collect()
+    }
+
+    pub fn query_limits(&self) -> QueryLimits {
+        QueryLimits {
+            max_aliases: self.max_aliases,
+            max_bytes: self.max_query_bytes,
+            max_depth: self.max_query_depth,
+        }
+    }
+
+    pub fn account_retry(&self) -> u64 {
+        self.account_retry
+    }
+
+    pub fn account_retry_interval(&self) -> u64 {
+        self.account_retry_interval
+    }
+
+    pub fn upstream_retry_budget(&self) -> u32 {
+        self.upstream_retry_budget
+    }
+
+    pub fn upstream_deadline(&self) -> u64 {
+        self.upstream_deadline
+    }
+
+    pub fn disable_payg(&self) -> bool {
+        self.disable_payg
+    }
+
+    pub fn start_degraded(&self) -> bool {
+        self.start_degraded
+    }
+
+    pub fn verify_onchain(&self) -> bool {
+        self.verify_onchain
+    }
+
+    pub fn eip1271(&self) -> bool {
+        self.eip1271
+    }
+
+    pub fn web3(&self) -> &str {
+        &self.web3
+    }
+
+    pub fn state_channel(&self) -> Option<Address> {
+        self.state_channel
+    }
+
+    pub fn indexer_registry(&self) -> Option<Address> {
+        self.indexer_registry
+    }
+
+    pub fn controller_refresh(&self) -> u64 {
+        self.controller_refresh
+    }
+
+    pub fn channel_max_inflight(&self) -> usize {
+        self.channel_max_inflight
+    }
+
+    pub fn max_channels_per_consumer(&self) -> usize {
+        self.max_channels_per_consumer
+    }
+
+    pub fn charge_bad_request(&self) -> bool {
+        self.charge_bad_request
+    }
+
+    pub fn query_cache_ttl(&self) -> u64 {
+        self.query_cache_ttl
+    }
+
+    pub fn query_cache_size(&self) -> usize {
+        self.query_cache_size
+    }
+
+    pub fn stream_response(&self) -> bool {
+        self.stream_response
+    }
+
+    pub fn event_webhook(&self) -> Option<&str> {
+        self.event_webhook.as_deref()
+    }
+
+    pub fn claim_interval(&self) -> u64 {
+        self.claim_interval
+    }
+
+    pub fn log_queries(&self) -> bool {
+        self.log_queries
+    }
+
+    pub fn redact_variables(&self) -> &[String] {
+        &self.redact_variables
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+
+    pub fn gas_price(&self) -> GasPrice {
+        GasPrice {
+            gas_price: self.gas_price,
+            max_fee_per_gas: self.max_fee_per_gas,
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas,
+        }
+    }
+
     #[cfg(feature = "p2p")]
     pub fn p2p(&self) -> Multiaddr {
         if self.p2p_relay {
@@ -125,4 +436,9 @@ impl CommandLineArgs {
             P2P_ADDR.parse().unwrap()
         }
     }
+
+    #[cfg(feature = "p2p")]
+    pub fn p2p_bootstrap(&self) -> Vec<Multiaddr> {
+        self.p2p_bootstrap.iter().map(|a| a.parse().unwrap()).collect()
+    }
 }
@@ -0,0 +1,185 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Prepaid credit of consumers, an alternative of state channel for Pay-As-You-Go.
+
+use chrono::prelude::*;
+use serde_json::Value;
+use subql_proxy_utils::{
+    eip712::eth_message,
+    error::Error,
+    payg::{convert_recovery_sign, convert_string_to_sign},
+    types::WebResult,
+};
+use warp::{
+    filters::header::headers_cloned,
+    http::header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    reject, Filter, Rejection,
+};
+use web3::{
+    signing::recover,
+    types::{Address, U256},
+};
+
+use crate::payg::STORE;
+
+/// Get the credit balance of the consumer.
+pub async fn balance(consumer: &Address) -> U256 {
+    STORE.read().await.credits.get(consumer).cloned().unwrap_or_default()
+}
+
+/// Top up the consumer's credit, returns the new balance.
+pub async fn top_up(consumer: Address, amount: U256) -> U256 {
+    let mut store = STORE.write().await;
+    let balance = store.credits.entry(consumer).or_default();
+    *balance = balance.saturating_add(amount);
+    *balance
+}
+
+/// Debit the consumer's credit, returns the remaining balance.
+pub async fn debit(consumer: &Address, amount: U256) -> Result<U256, Error> {
+    let mut store = STORE.write().await;
+    let balance = store.credits.get_mut(consumer).ok_or(Error::InsufficientCredit)?;
+    if *balance < amount {
+        return Err(Error::InsufficientCredit);
+    }
+    *balance -= amount;
+    Ok(*balance)
+}
+
+/// Handle the top up signal from coordinator or operator, authorized by the admin token (`with_admin`).
+pub async fn top_up_signal(body: &Value) -> Result<Value, Error> {
+    let consumer: Address = body["consumer"]
+        .as_str()
+        .ok_or(Error::InvalidRequest)?
+        .parse()
+        .map_err(|_| Error::InvalidRequest)?;
+    let amount =
+        U256::from_dec_str(body["amount"].as_str().ok_or(Error::InvalidRequest)?).map_err(|_| Error::InvalidRequest)?;
+    let balance = top_up(consumer, amount).await;
+    info!("Consumer {:?} top up credit {}, balance: {}", consumer, amount, balance);
+
+    Ok(serde_json::json!({
+        "consumer": format!("{:?}", consumer),
+        "balance": balance.to_string(),
+    }))
+}
+
+pub fn with_credit() -> impl Filter<Extract = (Address,), Error = Rejection> + Clone {
+    headers_cloned().and_then(authorize)
+}
+
+/// Recover the consumer from the signed `consumer + timestamp` in the header.
+async fn authorize(headers: HeaderMap<HeaderValue>) -> WebResult<Address> {
+    let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .ok_or(reject::custom(Error::NoPermissionError))?;
+    let params = serde_json::from_str::<Value>(header).map_err(|_| reject::custom(Error::InvalidAuthHeaderError))?;
+
+    let consumer: Address = params["consumer"]
+        .as_str()
+        .and_then(|v| v.parse().ok())
+        .ok_or(reject::custom(Error::InvalidAuthHeaderError))?;
+    let timestamp = params["timestamp"]
+        .as_i64()
+        .ok_or(reject::custom(Error::InvalidAuthHeaderError))?;
+    let sign = params["signature"]
+        .as_str()
+        .ok_or(reject::custom(Error::InvalidAuthHeaderError))?;
+
+    if (Utc::now().timestamp_millis() - timestamp).abs() > 120000 {
+        return Err(reject::custom(Error::JWTTokenExpiredError));
+    }
+
+    let msg = eth_message(format!("{:?}{}", consumer, timestamp));
+    let (sign, id) = convert_recovery_sign(&convert_string_to_sign(sign));
+    let signer = recover(&msg, &sign, id).map_err(|_| reject::custom(Error::InvalidSignature))?;
+    if signer != consumer {
+        return Err(reject::custom(Error::InvalidSignature));
+    }
+
+    Ok(consumer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use subql_proxy_utils::payg::convert_sign_to_string;
+    use warp::{http::StatusCode, Reply};
+    use web3::signing::{Key, SecretKeyRef};
+
+    #[tokio::test]
+    async fn debit_the_credit() {
+        let consumer = Address::from_low_u64_be(0xc1);
+        assert_eq!(top_up(consumer, U256::from(30u64)).await, U256::from(30u64));
+        assert_eq!(debit(&consumer, U256::from(10u64)).await.unwrap(), U256::from(20u64));
+        assert_eq!(debit(&consumer, U256::from(20u64)).await.unwrap(), U256::zero());
+        assert_eq!(balance(&consumer).await, U256::zero());
+    }
+
+    #[tokio::test]
+    async fn reject_insufficient_credit() {
+        let consumer = Address::from_low_u64_be(0xc2);
+        let res = debit(&consumer, U256::one()).await;
+        assert!(matches!(res, Err(Error::InsufficientCredit)));
+
+        top_up(consumer, U256::from(5u64)).await;
+        let res = debit(&consumer, U256::from(6u64)).await;
+        assert!(matches!(res, Err(Error::InsufficientCredit)));
+        assert_eq!(balance(&consumer).await, U256::from(5u64));
+    }
+
+    #[tokio::test]
+    async fn authorize_by_the_consumer_sign() {
+        let sk = secp256k1::SecretKey::from_slice(&[0x44; 32]).unwrap();
+        let key = SecretKeyRef::new(&sk);
+        let header = |consumer: Address| {
+            let timestamp = Utc::now().timestamp_millis();
+            let msg = eth_message(format!("{:?}{}", consumer, timestamp));
+            let sign = key.sign(&msg, None).unwrap();
+            serde_json::json!({
+                "consumer": format!("{:?}", consumer),
+                "timestamp": timestamp,
+                "signature": convert_sign_to_string(&sign),
+            })
+            .to_string()
+        };
+
+        let filter = with_credit();
+        let consumer = warp::test::request()
+            .header("authorization", header(key.address()))
+            .filter(&filter)
+            .await
+            .unwrap();
+        assert_eq!(consumer, key.address());
+
+        // signed by other key.
+        let res = warp::test::request()
+            .header("authorization", header(Address::from_low_u64_be(0xc3)))
+            .filter(&filter)
+            .await;
+        assert!(matches!(
+            res.unwrap_err().find::<Error>(),
+            Some(Error::InvalidSignature)
+        ));
+        let res = warp::test::request().filter(&filter).await;
+        let res = subql_proxy_utils::error::handle_rejection(res.unwrap_err(), false).await;
+        assert_eq!(res.unwrap().into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+}
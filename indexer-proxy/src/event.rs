@@ -0,0 +1,199 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! State channel lifecycle events, always log and send to the internal channel,
+//! and post to the webhook if configured.
+
+use chrono::prelude::*;
+use futures::Stream;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::fmt;
+use subql_proxy_utils::request::REQUEST_CLIENT;
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::sse::Event;
+use web3::types::{Address, H256, U256};
+
+#[cfg(feature = "p2p")]
+use subql_proxy_utils::p2p::rpc::notify_channel;
+
+use crate::cli::COMMAND;
+use crate::payg::ChannelEntry;
+
+/// Internal channel of lifecycle events, subscribe it to receive the events payload.
+pub static EVENTS: Lazy<broadcast::Sender<Value>> = Lazy::new(|| broadcast::channel(1024).0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum ChannelEvent {
+    /// channel opened with consumer.
+    Opened,
+    /// consumer queried with a new state.
+    Advanced,
+    /// the final state signed.
+    Finalized,
+    /// the balance not cover the next query.
+    Exhausted,
+    /// the latest state checkpointed on chain.
+    Checkpointed,
+    /// the expired channel claimed on chain.
+    Claimed,
+}
+
+impl fmt::Display for ChannelEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelEvent::Opened => write!(f, "opened"),
+            ChannelEvent::Advanced => write!(f, "advanced"),
+            ChannelEvent::Finalized => write!(f, "finalized"),
+            ChannelEvent::Exhausted => write!(f, "exhausted"),
+            ChannelEvent::Checkpointed => write!(f, "checkpointed"),
+            ChannelEvent::Claimed => write!(f, "claimed"),
+        }
+    }
+}
+
+pub fn emit(
+    event: ChannelEvent,
+    channel_id: U256,
+    deployment: &str,
+    consumer: Address,
+    amount: U256,
+    count: U256,
+    price: U256,
+) {
+    publish(payload(event, channel_id, deployment, consumer, amount, count, price));
+}
+
+/// Emit the event of the transaction confirmed on chain, with the count settled by it and its hash.
+pub fn emit_tx(event: ChannelEvent, channel_id: U256, channel: &ChannelEntry, count: U256, tx_hash: H256) {
+    let mut payload = payload(
+        event,
+        channel_id,
+        &channel.deployment,
+        channel.consumer,
+        channel.amount,
+        count,
+        channel.price,
+    );
+    payload["tx"] = format!("{:?}", tx_hash).into();
+    publish(payload);
+}
+
+fn payload(
+    event: ChannelEvent,
+    channel_id: U256,
+    deployment: &str,
+    consumer: Address,
+    amount: U256,
+    count: U256,
+    price: U256,
+) -> Value {
+    let spent = count.saturating_mul(price);
+    json!({
+        "event": event.to_string(),
+        "channelId": format!("{:#X}", channel_id),
+        "deployment": deployment,
+        "consumer": format!("{:?}", consumer),
+        "amount": amount.to_string(),
+        "count": count.to_string(),
+        "spent": spent.to_string(),
+        "remaining": amount.saturating_sub(spent).to_string(),
+        "timestamp": Utc::now().timestamp_millis(),
+    })
+}
+
+fn publish(payload: Value) {
+    info!("Channel event: {}", payload);
+
+    // no receivers is ok.
+    let _ = EVENTS.send(payload.clone());
+
+    #[cfg(feature = "p2p")]
+    notify_channel(payload["channelId"].as_str().unwrap_or_default(), payload.clone());
+
+    if let Some(url) = COMMAND.event_webhook() {
+        tokio::spawn(async move {
+            if let Err(err) = REQUEST_CLIENT.post(url).json(&payload).send().await {
+                warn!("Channel event webhook failure: {}", err);
+            }
+        });
+    }
+}
+
+/// Subscribe the lifecycle events as Server-Sent Events stream, the lagged events are skipped.
+pub fn sse_events() -> impl Stream<Item = Result<Event, Infallible>> {
+    futures::stream::unfold(EVENTS.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(payload) => {
+                    let name = payload["event"].as_str().unwrap_or_default().to_owned();
+                    let event = Event::default().event(name).data(payload.to_string());
+                    return Some((Ok(event), receiver));
+                }
+                Err(RecvError::Lagged(n)) => warn!("Events subscriber lagged {} events", n),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn emit_the_claimed_tx() {
+        let channel = ChannelEntry {
+            amount: U256::from(100u64),
+            consumer: Address::from_low_u64_be(0xc0),
+            coordinator: String::new(),
+            expiration: U256::zero(),
+            count: U256::from(3u64),
+            price: U256::from(10u64),
+            deployment: "Qm".to_owned(),
+            opened: Value::Null,
+            state: None,
+            closed: false,
+            permits: Arc::new(Semaphore::new(1)),
+        };
+        let mut receiver = EVENTS.subscribe();
+        emit_tx(
+            ChannelEvent::Claimed,
+            U256::from(0xc1a1u64),
+            &channel,
+            channel.count,
+            H256::repeat_byte(1),
+        );
+
+        // the events of other tests maybe interleaved.
+        let payload = loop {
+            let payload = receiver.recv().await.unwrap();
+            if payload["channelId"] == "0xC1A1" {
+                break payload;
+            }
+        };
+        assert_eq!(payload["event"], "claimed");
+        assert_eq!(payload["count"], "3");
+        assert_eq!(payload["spent"], "30");
+        assert_eq!(payload["remaining"], "70");
+        assert_eq!(payload["tx"], format!("{:?}", H256::repeat_byte(1)));
+    }
+}
@@ -19,22 +19,36 @@
 #[macro_use]
 extern crate tracing;
 
+mod access;
 mod account;
+mod admin;
 mod auth;
+mod cache;
+mod claim;
 mod cli;
+mod credit;
+mod event;
 mod payg;
+mod persisted;
 mod project;
 mod prometheus;
+mod scope;
 mod server;
+mod version;
 
 #[cfg(feature = "p2p")]
 mod p2p;
 
+#[cfg(test)]
+mod mock;
+
 use cli::COMMAND;
+use subql_proxy_utils::request::{init_retry_budget, init_upstream_ca};
 use tracing::Level;
+use tracing_subscriber::EnvFilter;
 
 #[cfg(feature = "p2p")]
-use subql_proxy_utils::p2p::{libp2p::identity::Keypair, server::server as p2p_server};
+use subql_proxy_utils::p2p::{load_key, rpc::check_bind as check_rpc_bind, server::server as p2p_server};
 
 #[tokio::main]
 async fn main() {
@@ -42,32 +56,59 @@ async fn main() {
     let host = COMMAND.host();
     let debug = COMMAND.debug();
 
-    let log_filter = if debug { Level::DEBUG } else { Level::INFO };
-    tracing_subscriber::fmt().with_max_level(log_filter).init();
+    // the logged queries are at DEBUG of target `query`, enable it without all the debug logs.
+    let log_filter = if debug {
+        Level::DEBUG.to_string()
+    } else if COMMAND.log_queries() {
+        format!("{},query={}", Level::INFO, Level::DEBUG)
+    } else {
+        Level::INFO.to_string()
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(log_filter));
+    if COMMAND.log_json() {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+
+    if let Some(path) = COMMAND.upstream_ca() {
+        let pem = tokio::fs::read(path).await.expect("Read upstream CA failed");
+        init_upstream_ca(&pem).expect("Invalid upstream CA");
+        info!("Upstream CA pinned: {}", path);
+    }
+    init_retry_budget(COMMAND.upstream_retry_budget(), COMMAND.upstream_deadline());
 
-    account::fetch_account_metadata().await.unwrap();
+    if let Err(err) = account::init_account().await {
+        if COMMAND.start_degraded() {
+            warn!("Start degraded without account metadata: {}", err);
+            account::fetch_in_background();
+        } else {
+            panic!("Fetch account metadata failure: {}", err);
+        }
+    }
+    account::refresh_controller();
     project::init_projects().await;
 
     project::subscribe();
+    claim::start();
 
     #[cfg(feature = "p2p")]
     {
         let p2p_bind = COMMAND.p2p();
         let p2p_rpc = COMMAND.rpc();
         let p2p_ws = COMMAND.ws();
+        let p2p_token = COMMAND.rpc_token();
+        // refuse to start before spawning, the P2P task failure not stops the proxy.
+        check_rpc_bind(p2p_rpc, p2p_ws, p2p_token.as_deref()).expect("Insecure P2P JSON-RPC bind");
+        let bootstrap = COMMAND.p2p_bootstrap();
         info!("P2P bind: {}", p2p_bind);
 
-        let key_path = std::path::PathBuf::from("indexer.key"); // DEBUG TODO
-        let key = if key_path.exists() {
-            let key_bytes = tokio::fs::read(&key_path).await.unwrap_or(vec![]); // safe.
-            Keypair::from_protobuf_encoding(&key_bytes).unwrap()
-        } else {
-            let key = Keypair::generate_ed25519();
-            let _ = tokio::fs::write(key_path, key.to_protobuf_encoding().unwrap()).await;
-            key
-        };
+        p2p::init_methods();
+        let key = load_key(COMMAND.p2p_key()).await;
         tokio::spawn(async move {
-            p2p_server::<p2p::IndexerP2p>(p2p_bind, p2p_rpc, p2p_ws, None, key).await.unwrap();
+            p2p_server::<p2p::IndexerP2p>(p2p_bind, p2p_rpc, p2p_ws, p2p_token, None, key, bootstrap)
+                .await
+                .unwrap();
         });
     }
 
@@ -0,0 +1,185 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Local mocks of the coordinator and upstream services, and the accounts of the unit tests.
+
+use once_cell::sync::Lazy;
+use secp256k1::SecretKey;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+use web3::{
+    signing::{keccak256, Key, SecretKeyRef},
+    types::Address,
+};
+
+use crate::account::init_test_account;
+use crate::project::{add_project, deployment_keys};
+
+/// The command line of tests, the coordinators and upstreams are registered per project.
+pub fn args() -> Vec<String> {
+    let args = [
+        "subql-proxy",
+        "--service-url",
+        "http://127.0.0.1:1/coordinator",
+        "--secret-key",
+        "0123456789abcdef0123456789abcdef",
+        "--fallback-price",
+        "7",
+        "--event-webhook",
+        &format!("http://{}/webhook", WEBHOOK.addr),
+    ];
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// The controller key of the test indexer.
+const CONTROLLER_KEY: [u8; 32] = [0x11; 32];
+
+/// The key of the test consumer.
+const CONSUMER_KEY: [u8; 32] = [0x22; 32];
+
+/// Seed of the deployment ids, every project of tests is a new deployment.
+static DEPLOYMENTS: AtomicU64 = AtomicU64::new(0);
+
+/// The indexer of tests with its controller key, the account is ready to sign.
+pub async fn indexer() -> (Address, SecretKey) {
+    let sk = SecretKey::from_slice(&CONTROLLER_KEY).unwrap();
+    let indexer = Address::from_low_u64_be(0x1de);
+    init_test_account(indexer, sk).await;
+    (indexer, sk)
+}
+
+/// The consumer of tests with its key.
+pub fn consumer() -> (Address, SecretKey) {
+    let sk = SecretKey::from_slice(&CONSUMER_KEY).unwrap();
+    (SecretKeyRef::new(&sk).address(), sk)
+}
+
+/// The mocked coordinator (`/coordinator`) and upstream (`/query`) of one test.
+pub struct MockServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockServer {
+    /// Serve the mocks in the runtime of test, the coordinator responds the `lastPrice` to the opens.
+    pub fn start(last_price: Value) -> Self {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let coordinator = warp::path!("coordinator")
+            .and(warp::body::json())
+            .map(move |body: Value| {
+                received.lock().unwrap().push(body.clone());
+                let query = body["query"].as_str().unwrap_or_default();
+                let data = if query.contains("channelOpen") {
+                    json!({ "channelOpen": { "lastPrice": last_price } })
+                } else if query.contains("getProjectPrice") {
+                    json!({ "getProjectPrice": { "lastPrice": last_price } })
+                } else {
+                    json!({ "channelUpdate": { "id": body["variables"]["id"] } })
+                };
+                warp::reply::json(&json!({ "data": data }))
+            });
+        let upstream = warp::path!("query").map(|| {
+            warp::reply::json(&json!({ "data": { "_metadata": { "chain": "test", "lastProcessedHeight": 1 } } }))
+        });
+
+        let (addr, server) = warp::serve(coordinator.or(upstream)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        MockServer {
+            url: format!("http://{}", addr),
+            requests,
+        }
+    }
+
+    pub fn coordinator(&self) -> String {
+        format!("{}/coordinator", self.url)
+    }
+
+    pub fn upstream(&self) -> String {
+        format!("{}/query", self.url)
+    }
+
+    /// Register a new project served by the mocks, return the deployment id bytes and key.
+    pub fn project(&self) -> ([u8; 32], String) {
+        let seed = DEPLOYMENTS.fetch_add(1, Ordering::Relaxed);
+        let deployment_id = keccak256(format!("deployment-{}", seed).as_bytes());
+        let [key, ..] = deployment_keys(&deployment_id);
+        add_project(key.clone(), self.upstream(), &self.coordinator());
+        (deployment_id, key)
+    }
+
+    /// The variables of the coordinator requests of the operation, e.g. `channelUpdate`.
+    pub fn requests(&self, operation: &str) -> Vec<Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|body| body["query"].as_str().unwrap_or_default().contains(operation))
+            .map(|body| body["variables"].clone())
+            .collect()
+    }
+}
+
+/// The mocked event webhook shared by all the tests as the command line is, served on its own
+/// runtime (the runtime of a test ends with it) at the port picked by the OS.
+static WEBHOOK: Lazy<MockWebhook> = Lazy::new(MockWebhook::start);
+
+struct MockWebhook {
+    addr: SocketAddr,
+    events: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockWebhook {
+    fn start() -> Self {
+        let events = Arc::new(Mutex::new(vec![]));
+        let received = events.clone();
+        let webhook = warp::path!("webhook").and(warp::body::json()).map(move |body: Value| {
+            received.lock().unwrap().push(body);
+            warp::reply()
+        });
+
+        let (send, recv) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let (addr, server) = warp::serve(webhook).bind_ephemeral(([127, 0, 0, 1], 0));
+                send.send(addr).unwrap();
+                server.await;
+            });
+        });
+        MockWebhook {
+            addr: recv.recv().unwrap(),
+            events,
+        }
+    }
+}
+
+/// The events of the channel received by the webhook, the channels of all tests are posted to it.
+pub fn webhook_events(channel_id: &str) -> Vec<Value> {
+    WEBHOOK
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| event["channelId"] == channel_id)
+        .cloned()
+        .collect()
+}
@@ -18,21 +18,53 @@
 
 use async_trait::async_trait;
 use serde_json::{json, Value};
-use subql_proxy_utils::p2p::{P2pHandler, Request, Response};
+use std::time::Duration;
+use subql_proxy_utils::{
+    p2p::{behaviour::group::GroupId, libp2p::PeerId, router, P2pHandler, Request, Response},
+    payg::{OpenState, QueryState},
+    query::METADATA_QUERY,
+    request::upstream_request,
+};
+use tracing::Instrument;
 use web3::types::U256;
 
 use crate::account::ACCOUNT;
-use crate::payg::{open_state, query_state, PRICE};
-use crate::project::list_projects;
+use crate::cli::COMMAND;
+use crate::payg::{close_state, latest_state, open_state, query_state, PRICE};
+use crate::project::{get_project, list_projects};
+use crate::prometheus::{P2P_GROUP_GAUGE, P2P_PEERS_GAUGE, P2P_PING_GAUGE, P2P_RPC_DROPPED_COUNTER};
+use crate::version::build_info;
 
 pub struct IndexerP2p;
 
+/// Register the custom request methods of indexer.
+pub fn init_methods() {
+    router::add_method("version", |_data: String| async move {
+        Response::Data(serde_json::to_string(&build_info()).unwrap())
+    });
+}
+
 #[async_trait]
 impl P2pHandler for IndexerP2p {
     async fn request(request: Request) -> Response {
         // handle request
+        let payg = matches!(
+            request,
+            Request::StateChannel(_) | Request::Close(_) | Request::ChannelState(_)
+        );
+        if payg && COMMAND.disable_payg() {
+            return Response::Error("payg disabled".to_owned());
+        }
         match request {
             Request::StateChannel(infos) => channel_handle(&infos).await,
+            Request::Close(infos) => close_handle(&infos).await,
+            Request::Metadata(deployment) => metadata_handle(&deployment).await,
+            Request::ChannelState(channel) => match latest_state(&channel).await {
+                Ok(state) => Response::StateChannel(serde_json::to_string(&state).unwrap()),
+                Err(err) => Response::Error(err.to_string()),
+            },
+            // dispatched by the router before here.
+            Request::Custom(method, _) => Response::Error(format!("method {} not found", method)),
             Request::Info => {
                 let projects = list_projects();
                 let account = ACCOUNT.read().await;
@@ -51,32 +83,83 @@ impl P2pHandler for IndexerP2p {
     async fn event() {
         todo!()
     }
+
+    fn max_inflight() -> usize {
+        COMMAND.p2p_max_inflight()
+    }
+
+    fn rpc_capacity() -> usize {
+        COMMAND.p2p_rpc_capacity()
+    }
+
+    fn rpc_dropped(kind: &str) {
+        P2P_RPC_DROPPED_COUNTER.with_label_values(&[kind]).inc();
+    }
+
+    fn ping_changed(peer: &PeerId, rtt: Option<Duration>) {
+        let peer = peer.to_base58();
+        match rtt {
+            Some(rtt) => P2P_PING_GAUGE.with_label_values(&[&peer]).set(rtt.as_millis() as i64),
+            None => {
+                let _ = P2P_PING_GAUGE.remove_label_values(&[&peer]);
+            }
+        }
+    }
+
+    fn peers_changed(count: usize) {
+        P2P_PEERS_GAUGE.set(count as i64);
+    }
+
+    fn group_changed(group: &GroupId, size: usize) {
+        P2P_GROUP_GAUGE.with_label_values(&[group.id()]).set(size as i64);
+    }
 }
 
 /// Handle the state channel request/response infos.
 async fn channel_handle(infos: &str) -> Response {
     let params = serde_json::from_str::<Value>(infos).unwrap_or(Value::default());
-    if params.get("method").is_none() || params.get("state").is_none() {
-        return Response::Error("Invalid request".to_owned());
-    }
-    let state_res = serde_json::from_str::<Value>(&params["state"].as_str().unwrap());
-    if state_res.is_err() {
-        return Response::Error("Invalid request state".to_owned());
-    }
-    let state = state_res.unwrap(); // safe unwrap.
-    match params["method"].as_str().unwrap() {
-        "open" => match open_state(&state).await {
-            Ok(state) => Response::StateChannel(serde_json::to_string(&state).unwrap()),
-            Err(err) => Response::Error(err.to_string()),
-        },
+    let (method, state) = match (params["method"].as_str(), params["state"].as_str()) {
+        (Some(method), Some(state)) => (method, state),
+        _ => return Response::Error("Invalid request".to_owned()),
+    };
+    let state = match serde_json::from_str::<Value>(state) {
+        Ok(state) => state,
+        Err(_) => return Response::Error("Invalid request state".to_owned()),
+    };
+    match method {
+        "open" => {
+            match OpenState::from_json(&state).and_then(|s| Ok(s.recover_consumer()? == s.consumer)) {
+                Ok(true) => {}
+                _ => return Response::Error("invalid consumer signature".to_owned()),
+            }
+            match open_state(&state).await {
+                Ok(state) => Response::StateChannel(serde_json::to_string(&state).unwrap()),
+                Err(err) => Response::Error(err.to_string()),
+            }
+        }
         "query" => {
-            if params.get("project").is_none() || params.get("query").is_none() {
-                return Response::Error("Invalid request".to_owned());
+            let query = params["query"]
+                .as_str()
+                .and_then(|q| serde_json::from_str::<Value>(q).ok());
+            let (project, query) = match (params["project"].as_str(), query) {
+                (Some(project), Some(query)) => (project, query),
+                _ => return Response::Error("Invalid request".to_owned()),
+            };
+            match QueryState::from_json(&state).and_then(|s| Ok(s.recover_consumer()? == s.consumer)) {
+                Ok(true) => {}
+                _ => return Response::Error("invalid consumer signature".to_owned()),
             }
-            let project = params.get("project").unwrap().as_str().unwrap();
-            let query_raw = params.get("query").unwrap().as_str().unwrap();
-            let query: Value = serde_json::from_str(query_raw).unwrap();
-            match query_state(project, &state, &query).await {
+            let request_id = params.get("requestId").and_then(|v| v.as_str()).unwrap_or("");
+            let span = info_span!("p2p-payg", request_id = %request_id);
+            // the consumers before `PaidData` only know the `StateChannel` response.
+            let paid = params.get("paidData").and_then(|v| v.as_bool()).unwrap_or(false);
+            match query_state(project, &state, &query).instrument(span).await {
+                Ok((state, query)) if paid => Response::PaidData {
+                    data: serde_json::to_string(&query).unwrap(),
+                    price: state["price"].as_str().unwrap_or("0").to_owned(),
+                    next_price: state["nextPrice"].as_str().unwrap_or("0").to_owned(),
+                    state: serde_json::to_string(&state).unwrap(),
+                },
                 Ok((state, query)) => {
                     Response::StateChannel(serde_json::to_string(&json!(vec![query, state])).unwrap())
                 }
@@ -86,3 +169,28 @@ async fn channel_handle(infos: &str) -> Response {
         _ => Response::Error("Invalid request".to_owned()),
     }
 }
+
+/// Handle the state channel close request, response the final state.
+async fn close_handle(infos: &str) -> Response {
+    let state = match serde_json::from_str::<Value>(infos) {
+        Ok(state) => state,
+        Err(_) => return Response::Error("Invalid request state".to_owned()),
+    };
+    match close_state(&state).await {
+        Ok(state) => Response::StateChannel(serde_json::to_string(&state).unwrap()),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+/// Handle the project metadata request, same as the HTTP metadata.
+async fn metadata_handle(deployment: &str) -> Response {
+    let query_url = match get_project(deployment) {
+        Ok(url) => url,
+        Err(err) => return Response::Error(err.to_string()),
+    };
+    let query = json!({ "query": METADATA_QUERY });
+    match upstream_request(&query_url, &query).await {
+        Ok(result) => Response::Data(serde_json::to_string(&result).unwrap()),
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
@@ -18,136 +18,1232 @@
 
 //! Pay-As-You-Go with state channel helper functions.
 
+use chrono::prelude::Utc;
+use once_cell::sync::Lazy;
 use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use subql_proxy_utils::{
-    error::Error,
-    payg::{convert_sign_to_string, OpenState, QueryState},
-    request::graphql_request,
+    coordinator::Coordinator,
+    error::{Error, GraphQLServerError},
+    payg::{convert_sign_to_bytes, OpenState, QueryState, Receipt},
+    query::validate_query,
+    request::{upstream_request, upstream_response},
     types::WebResult,
 };
+use tiny_keccak::{Hasher, Keccak};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 use warp::{
     filters::header::headers_cloned,
     http::header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    hyper::Body,
     reject, Filter, Rejection,
 };
-use web3::{signing::SecretKeyRef, types::U256};
+use web3::{
+    contract::tokens::Tokenizable,
+    ethabi::{decode, encode, ParamType},
+    signing::{keccak256, Signature},
+    transports::Http,
+    types::{Address, Bytes, CallRequest, H256, U256},
+    Web3,
+};
 
-use crate::account::ACCOUNT;
+use crate::access::{check_consumer, check_consumer_by_bytes};
+use crate::account::{ensure_ready, ACCOUNT};
 use crate::cli::COMMAND;
-use crate::project::get_project;
+use crate::event::{emit, ChannelEvent};
+use crate::project::{deployment_keys, get_coordinator, get_coordinator_by_bytes, get_project};
 
 pub const PRICE: u64 = 10; // TODO delete
 
+/// StateChannel contract channel query function.
+const CHANNEL_FN: &str = "channel(uint256)";
+
+/// EIP-1271 signature validation function, the selector is also the magic value of valid.
+const IS_VALID_SIGNATURE_FN: &str = "isValidSignature(bytes32,bytes)";
+
+/// The open status of on-chain channel.
+const CHANNEL_STATUS_OPEN: u64 = 1;
+
+/// Seconds to keep the processed idempotency keys of open.
+const IDEMPOTENCY_TTL: u64 = 3600;
+
+/// The store of opened channels and prepaid credits. Access it by `ChannelEntry` and `credit`.
+pub static STORE: Lazy<RwLock<Store>> = Lazy::new(|| RwLock::new(Store::default()));
+
+#[derive(Default)]
+pub struct Store {
+    /// channel id => channel.
+    pub channels: HashMap<U256, ChannelEntry>,
+    /// consumer => prepaid credit balance.
+    pub credits: HashMap<Address, U256>,
+}
+
+/// The open requests of idempotency keys, (consumer, idempotency key) => opening.
+static OPENED: Lazy<Mutex<HashMap<(Address, String), Opening>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The addresses checked whether is smart contract wallet, address => is contract.
+static CONTRACT_WALLETS: Lazy<RwLock<HashMap<Address, bool>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The channels verified on chain.
+static VERIFIED: Lazy<RwLock<HashSet<U256>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+
+#[derive(Clone)]
+pub struct ChannelEntry {
+    /// total amount of the channel.
+    pub amount: U256,
+    /// consumer of the channel.
+    pub consumer: Address,
+    /// coordinator of the channel's project.
+    pub coordinator: String,
+    /// expiration timestamp of the channel.
+    pub expiration: U256,
+    /// latest signed count of the channel.
+    pub count: U256,
+    /// price agreed at open, all the states of channel must use it, zero is unmetered.
+    pub price: U256,
+    /// deployment id of the channel's project.
+    pub deployment: String,
+    /// the signed open state response.
+    pub opened: Value,
+    /// the latest counter-signed query state.
+    pub state: Option<Value>,
+    /// closed by operator, no more queries accepted.
+    pub closed: bool,
+    /// the in-flight query permits, dropped with the channel.
+    pub permits: Arc<Semaphore>,
+}
+
+impl ChannelEntry {
+    pub async fn get(id: &U256) -> Option<ChannelEntry> {
+        STORE.read().await.channels.get(id).cloned()
+    }
+
+    /// Insert or update the channel, all the count mutations must go through it,
+    /// the count never goes backwards.
+    pub async fn upsert(id: U256, entry: ChannelEntry) -> Result<(), Error> {
+        let mut store = STORE.write().await;
+        if let Some(old) = store.channels.get(&id) {
+            if entry.count < old.count {
+                return Err(Error::InvalidStateCount);
+            }
+        }
+        store.channels.insert(id, entry);
+        Ok(())
+    }
+
+    pub async fn remove(id: &U256) -> Option<ChannelEntry> {
+        STORE.write().await.channels.remove(id)
+    }
+
+    /// Mark the channel closed, return the channel.
+    pub async fn close(id: &U256) -> Option<ChannelEntry> {
+        let mut store = STORE.write().await;
+        let channel = store.channels.get_mut(id)?;
+        channel.closed = true;
+        Some(channel.clone())
+    }
+
+    pub async fn list() -> Vec<(U256, ChannelEntry)> {
+        STORE
+            .read()
+            .await
+            .channels
+            .iter()
+            .map(|(id, c)| (*id, c.clone()))
+            .collect()
+    }
+
+    /// Count the unexpired channels of the consumer.
+    pub async fn count_open(consumer: &Address) -> usize {
+        let now = U256::from(Utc::now().timestamp().max(0));
+        STORE
+            .read()
+            .await
+            .channels
+            .values()
+            .filter(|c| c.consumer == *consumer && c.expiration > now)
+            .count()
+    }
+}
+
 pub async fn open_state(body: &Value) -> Result<Value, Error> {
+    ensure_ready()?;
     let mut state = OpenState::from_json(body)?;
 
     // TODO check project is exists. unify the deployment id store style.
 
     let account = ACCOUNT.read().await;
-    let key = SecretKeyRef::new(&account.controller_sk);
+    let key = account.controller_key()?;
     state.sign(key, false)?;
     drop(account);
 
-    let (_, _consumer) = state.recover()?;
+    let consumer = check_open(&state).await?;
+
+    // opened already (e.g. the response lost), return the opened state rather than re-open it.
+    if let Some(channel) = ChannelEntry::get(&state.channel_id).await {
+        let opened = OpenState::from_json(&channel.opened)?;
+        if opened.consumer != state.consumer || opened.amount != state.amount {
+            return Err(Error::InvalidRequest);
+        }
+        debug!("Channel {:#X} is opened already", state.channel_id);
+        return Ok(channel.opened);
+    }
+
+    let url = get_coordinator_by_bytes(&state.deployment_id);
+    let last_price = Coordinator::new(url.as_str()).channel_open(&state).await?;
+    state.next_price = resolve_price(last_price)?;
+    // zero price is free tier, the channel is not metered and only terminated by expiration.
+    let unmetered = state.next_price.is_zero();
+    if unmetered {
+        info!("Channel {:#X} opened with zero price, unmetered", state.channel_id);
+    }
 
-    let url = COMMAND.service_url();
+    let mut res = state.to_json();
+    res["unmetered"] = Value::Bool(unmetered);
 
-    let mdata = format!(
-        r#"mutation {{
-  channelOpen(id:"{:#X}", indexer:"{:?}", consumer:"{:?}", balance:{}, expiration:{}, deploymentId:"0x{}", callback:"0x{}", lastIndexerSign:"0x{}", lastConsumerSign:"0x{}") {{
-    lastPrice
-  }}
-}}
-"#,
+    let [deployment, ..] = deployment_keys(&state.deployment_id);
+    let channel = ChannelEntry {
+        amount: state.amount,
+        consumer,
+        coordinator: url,
+        count: U256::zero(),
+        price: state.next_price,
+        expiration: state.expiration,
+        deployment: deployment.clone(),
+        opened: res.clone(),
+        state: None,
+        closed: false,
+        permits: Arc::new(Semaphore::new(COMMAND.channel_max_inflight())),
+    };
+    ChannelEntry::upsert(state.channel_id, channel).await?;
+    emit(
+        ChannelEvent::Opened,
         state.channel_id,
-        state.indexer,
+        &deployment,
         state.consumer,
         state.amount,
-        state.expiration,
-        hex::encode(&state.deployment_id),
-        hex::encode(&state.callback),
-        convert_sign_to_string(&state.indexer_sign),
-        convert_sign_to_string(&state.consumer_sign)
+        U256::zero(),
+        state.next_price,
     );
 
-    let query = json!({ "query": mdata });
-    let result = graphql_request(&url, &query)
-        .await
-        .map_err(|_| Error::ServiceException)?;
-    let price = result
-        .get("data")
-        .ok_or(Error::ServiceException)?
-        .get("channelOpen")
-        .ok_or(Error::ServiceException)?
-        .get("lastPrice")
-        .ok_or(Error::ServiceException)?
-        .as_i64()
-        .ok_or(Error::ServiceException)?;
-    state.next_price = U256::from(price);
+    Ok(res)
+}
 
-    Ok(state.to_json())
+/// The latest counter-signed state of the channel, consumer can resync from it.
+pub async fn latest_state(channel_id: &str) -> Result<Value, Error> {
+    let id: U256 = channel_id.parse().map_err(|_| Error::InvalidRequest)?;
+    let channel = ChannelEntry::get(&id).await.ok_or(Error::ChannelNotFound)?;
+    channel.state.ok_or(Error::ChannelNotFound)
+}
+
+/// The checks of open state, shared by the open and the dry-run, return the consumer.
+async fn check_open(state: &OpenState) -> Result<Address, Error> {
+    if state.amount.is_zero() {
+        return Err(Error::InvalidRequest);
+    }
+    if state.expiration <= U256::from(Utc::now().timestamp().max(0)) {
+        return Err(Error::ChannelExpired);
+    }
+    let consumer = verify_open_consumer(state).await?;
+    check_consumer_by_bytes(&state.deployment_id, &consumer)?;
+
+    // the opened channel (re-open) is counted already.
+    let max_channels = COMMAND.max_channels_per_consumer();
+    if max_channels > 0
+        && ChannelEntry::get(&state.channel_id).await.is_none()
+        && ChannelEntry::count_open(&consumer).await >= max_channels
+    {
+        return Err(Error::TooManyChannels);
+    }
+    Ok(consumer)
+}
+
+/// The price of channel, the `lastPrice` of coordinator, or the fallback price if not usable.
+fn resolve_price(last_price: Option<Value>) -> Result<U256, Error> {
+    let price = last_price.map(|v| {
+        parse_price(&v).ok_or_else(|| {
+            warn!("Coordinator return invalid lastPrice: {}", v);
+            Error::InvalidProjectPrice
+        })
+    });
+    match (price, COMMAND.fallback_price()) {
+        (Some(Ok(price)), _) => Ok(price),
+        (_, Some(fallback)) => {
+            warn!(
+                "Coordinator not return usable lastPrice, use fallback price: {}",
+                fallback
+            );
+            Ok(fallback)
+        }
+        (Some(Err(e)), None) => Err(e),
+        (None, None) => Err(Error::ServiceException),
+    }
+}
+
+/// Parse the price in JSON number or decimal string.
+fn parse_price(value: &Value) -> Option<U256> {
+    match value {
+        Value::Number(n) => U256::from_dec_str(&n.to_string()).ok(),
+        Value::String(s) => U256::from_dec_str(s.trim()).ok(),
+        _ => None,
+    }
+}
+
+/// Open the state, a repeated idempotency key returns the original response instead of re-opening.
+pub async fn open_state_idempotent(key: Option<String>, body: &Value) -> Result<Value, Error> {
+    let key = match key {
+        Some(key) => key,
+        None => return open_state(body).await,
+    };
+    // the key is scoped to the consumer, others cannot take or block it.
+    let consumer = verify_open_consumer(&OpenState::from_json(body)?).await?;
+    let hash = keccak256(&serde_json::to_vec(body).map_err(|_| Error::InvalidSerialize)?);
+
+    let ttl = Duration::from_secs(IDEMPOTENCY_TTL);
+    let reserved = {
+        let mut opened = OPENED.lock().unwrap();
+        opened.retain(|_, opening| match opening {
+            Opening::Done(time, _, _) => time.elapsed() <= ttl,
+            Opening::InFlight(_) => true,
+        });
+        let key = (consumer, key);
+        match opened.get(&key) {
+            Some(Opening::Done(_, h, res)) if *h == hash => {
+                debug!("Open with processed idempotency key: {}", key.1);
+                return Ok(res.clone());
+            }
+            Some(Opening::InFlight(h)) if *h == hash => return Err(Error::OpenInProgress),
+            Some(_) => return Err(Error::IdempotencyKeyReused),
+            None => {}
+        }
+        opened.insert(key.clone(), Opening::InFlight(hash));
+        Reserved(Some(key))
+    };
+
+    let res = open_state(body).await?;
+    reserved.done(hash, res.clone());
+    Ok(res)
+}
+
+/// The open request of idempotency key, with the hash of request body.
+enum Opening {
+    InFlight([u8; 32]),
+    Done(Instant, [u8; 32], Value),
+}
+
+/// The reserved idempotency key, released when dropped before done (e.g. open failed).
+struct Reserved(Option<(Address, String)>);
+
+impl Reserved {
+    fn done(mut self, hash: [u8; 32], res: Value) {
+        if let Some(key) = self.0.take() {
+            OPENED
+                .lock()
+                .unwrap()
+                .insert(key, Opening::Done(Instant::now(), hash, res));
+        }
+    }
+}
+
+impl Drop for Reserved {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take() {
+            OPENED.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Recover the consumer of the open state, or verify the signature of smart contract wallet by EIP-1271.
+async fn verify_open_consumer(state: &OpenState) -> Result<Address, Error> {
+    match state.recover_consumer() {
+        Ok(consumer) if consumer == state.consumer => Ok(consumer),
+        // signed by other key, or for other chain.
+        Ok(_) if !COMMAND.eip1271() => Err(Error::InvalidSignature),
+        Err(e) if !COMMAND.eip1271() => Err(e),
+        _ => {
+            // not signed by the consumer as EOA, maybe a smart contract wallet.
+            verify_contract_sign(state.consumer, state.payload(), &state.consumer_sign).await?;
+            Ok(state.consumer)
+        }
+    }
+}
+
+/// Dry-run the checks of open state, without sign and send to coordinator.
+pub async fn validate_open(body: &Value) -> Value {
+    let res = async {
+        let state = OpenState::from_json(body)?;
+        check_open(&state).await?;
+        let url = get_coordinator_by_bytes(&state.deployment_id);
+        let last_price = Coordinator::new(url).project_price(&state.deployment_id).await?;
+        resolve_price(last_price)
+    };
+
+    match res.await {
+        Ok(price) => json!({ "valid": true, "nextPrice": price.to_string() }),
+        Err(err) => json!({ "valid": false, "error": err.to_string() }),
+    }
 }
 
 pub async fn query_state(project: &str, state: &Value, query: &Value) -> Result<(Value, Value), Error> {
     let query_url = get_project(project)?;
+    validate_query(query, &COMMAND.query_limits())?;
+    // released when dropped, also on the error paths.
+    let (state, _permit) = prepare_state(project, state).await?;
+
+    // query the data.
+    let data = match upstream_request(&query_url, query).await {
+        Ok(result) => {
+            // not counter-sign the state if no data at all, partial results are passed through.
+            if let Some(errors) = full_errors(&result) {
+                return Err(Error::GraphQLQueryError(errors.to_string()));
+            }
+            Ok(result)
+        }
+        Err(e) => upstream_failure(e),
+    }?;
+
+    update_state(&state, &get_coordinator(project)).await?;
+
+    let body = serde_json::to_string(&data).unwrap(); // safe unwrap
+    let res = state_json(project, &state, H256::from(keccak256(body.as_bytes()))).await?;
+    Ok((res, data))
+}
+
+/// Same as `query_state`, but stream the response body `[data, state]` without buffering the data,
+/// the state is appended after the data. The query is charged when upstream responds 200 with a
+/// non-empty body, only the response leading with errors is buffered and checked.
+pub async fn query_state_stream(project: &str, state: &Value, query: &Value) -> Result<Body, Error> {
+    let query_url = get_project(project)?;
+    validate_query(query, &COMMAND.query_limits())?;
+    let (state, _permit) = prepare_state(project, state).await?;
+
+    let mut res = match upstream_response(&query_url, query, &[]).await {
+        Ok(res) => res,
+        Err(e) => {
+            let data = upstream_failure(e)?;
+            return buffered_body(project, &state, data).await;
+        }
+    };
+    let first = res
+        .chunk()
+        .await
+        .map_err(|_| Error::UpstreamUnavailable)?
+        .unwrap_or_default();
+    if first.is_empty() {
+        return Err(Error::UpstreamUnavailable);
+    }
+    if leading_errors(&first) {
+        // the errors response is small, buffer and check it as usual.
+        let mut bytes = first.to_vec();
+        while let Some(chunk) = res.chunk().await.map_err(|_| Error::UpstreamUnavailable)? {
+            bytes.extend_from_slice(&chunk);
+        }
+        let data: Value = serde_json::from_slice(&bytes).map_err(|_| Error::UpstreamUnavailable)?;
+        if let Some(errors) = full_errors(&data) {
+            return Err(Error::GraphQLQueryError(errors.to_string()));
+        }
+        return buffered_body(project, &state, data).await;
+    }
+
+    update_state(&state, &get_coordinator(project)).await?;
 
+    let (mut sender, body) = Body::channel();
+    let project = project.to_owned();
+    tokio::spawn(async move {
+        let mut hasher = Keccak::v256();
+        hasher.update(&first);
+        if sender.send_data("[".into()).await.is_err() || sender.send_data(first).await.is_err() {
+            return;
+        }
+        loop {
+            match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    hasher.update(&chunk);
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Upstream stream failure: {}", e);
+                    return sender.abort();
+                }
+            }
+        }
+        let mut hash = [0u8; 32];
+        hasher.finalize(&mut hash);
+        match state_json(&project, &state, H256::from(hash)).await {
+            Ok(res) => {
+                let _ = sender.send_data(format!(",{}]", res).into()).await;
+            }
+            Err(e) => {
+                warn!("Sign receipt failure: {}", e);
+                sender.abort();
+            }
+        }
+    });
+
+    Ok(body)
+}
+
+/// Check the state and counter-sign it, the permit of channel must be held until state updated.
+async fn prepare_state(project: &str, state: &Value) -> Result<(QueryState, OwnedSemaphorePermit), Error> {
+    ensure_ready()?;
     let mut state = QueryState::from_json(state)?;
-    state.next_price = U256::from(PRICE);
+    // the unknown channel (e.g. opened before restart) has no agreed price.
+    let channel = ChannelEntry::get(&state.channel_id)
+        .await
+        .ok_or(Error::ChannelNotFound)?;
+    check_channel(&channel)?;
+    if state.price != channel.price {
+        return Err(Error::InvalidStatePrice);
+    }
+    // only the consumer is able to hold the permits of channel.
+    let consumer = verify_consumer(&state, &channel).await?;
+    check_consumer(project, &consumer)?;
+
+    let permit = channel_permit(&channel)?;
+    if COMMAND.verify_onchain() {
+        verify_onchain(state.channel_id).await?;
+    }
+    // the count maybe advanced before the permit acquired.
+    let channel = ChannelEntry::get(&state.channel_id)
+        .await
+        .ok_or(Error::ChannelNotFound)?;
+    check_channel(&channel)?;
+    if state.count <= channel.count {
+        return Err(Error::InvalidStateCount);
+    }
+    check_final(&state, &channel)?;
+    state.next_price = channel.price;
 
     let account = ACCOUNT.read().await;
-    let key = SecretKeyRef::new(&account.controller_sk);
+    let key = account.controller_key()?;
     state.sign(key, false)?;
     drop(account);
-    let (_, _signer) = state.recover()?;
-    // TODO more verify the signer
 
-    // query the data.
-    let data = match graphql_request(&query_url, query).await {
-        Ok(result) => {
-            let string = serde_json::to_string(&result).unwrap(); // safe unwrap
-            let _sign = crate::account::sign_message(&string.as_bytes()); // TODO add to header
+    Ok((state, permit))
+}
 
-            // TODO add state to header and request to coordiantor know the response.
+/// The channel accepts the queries until closed or expired.
+fn check_channel(channel: &ChannelEntry) -> Result<(), Error> {
+    if channel.closed {
+        return Err(Error::ChannelClosed);
+    }
+    if channel.expiration <= U256::from(Utc::now().timestamp().max(0)) {
+        return Err(Error::ChannelExpired);
+    }
+    Ok(())
+}
 
-            Ok(result)
+/// Recover the consumer of the state, or verify the signature of smart contract wallet by EIP-1271,
+/// it must be the consumer of the channel.
+async fn verify_consumer(state: &QueryState, channel: &ChannelEntry) -> Result<Address, Error> {
+    if state.consumer != channel.consumer {
+        return Err(Error::InvalidSignature);
+    }
+    match state.recover_consumer() {
+        Ok(consumer) if consumer == state.consumer => Ok(consumer),
+        // signed by other key, or for other chain, unless a smart contract wallet.
+        Ok(_) if !COMMAND.eip1271() => Err(Error::InvalidSignature),
+        Err(e) if !COMMAND.eip1271() => Err(e),
+        _ => {
+            verify_contract_sign(state.consumer, state.payload(), &state.consumer_sign).await?;
+            Ok(state.consumer)
         }
-        Err(_e) => Err(Error::ServiceException),
-    }?;
+    }
+}
+
+/// Handle the upstream failure, the bad request is charged as data if the policy enabled.
+fn upstream_failure(e: GraphQLServerError) -> Result<Value, Error> {
+    match e {
+        GraphQLServerError::BadRequest(body) => {
+            // the query is bad, consumer's fault, charge it if the policy enabled.
+            if COMMAND.charge_bad_request() {
+                Ok(serde_json::from_str(&body).unwrap_or(json!({ "errors": [{ "message": body }] })))
+            } else {
+                Err(Error::UpstreamBadRequest(body))
+            }
+        }
+        GraphQLServerError::Timeout(_) => Err(Error::Timeout),
+        e => {
+            warn!("Upstream failure: {}", e);
+            Err(Error::UpstreamUnavailable)
+        }
+    }
+}
+
+/// Charge the buffered data, and respond it with state in one body.
+async fn buffered_body(project: &str, state: &QueryState, data: Value) -> Result<Body, Error> {
+    update_state(state, &get_coordinator(project)).await?;
+    let body = serde_json::to_string(&data).unwrap(); // safe unwrap
+    let res = state_json(project, state, H256::from(keccak256(body.as_bytes()))).await?;
+    Ok(Body::from(format!("[{},{}]", body, res)))
+}
+
+/// Check the response leading with `errors`, upstream puts `data` first when it has any.
+fn leading_errors(bytes: &[u8]) -> bool {
+    let leading: Vec<u8> = bytes
+        .iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .take(9)
+        .cloned()
+        .collect();
+    leading.starts_with(b"{\"errors\"")
+}
 
-    // query the state.
-    let url = COMMAND.service_url();
-    let mdata = format!(
-        r#"mutation {{
-  channelUpdate(id:"{:#X}", count:{}, isFinal:{}, price:{}, indexerSign:"0x{}", consumerSign:"0x{}") {{ id }}
-}}
-"#,
+/// The responded state, with the closed flag and the signed receipt of the response data.
+async fn state_json(project: &str, state: &QueryState, response_hash: H256) -> Result<Value, Error> {
+    // sign the receipt of the response body, the consumer keeps it as proof-of-service.
+    let account = ACCOUNT.read().await;
+    let key = account.controller_key()?;
+    let receipt = Receipt::indexer_generate(project, state.channel_id, state.count, response_hash, key)?;
+    drop(account);
+
+    let mut res = state.to_json();
+    res["closed"] = Value::Bool(is_closed(state).await);
+    res["receipt"] = receipt.to_json();
+    Ok(res)
+}
+
+/// The channel is closed if the state is final, or the balance not cover the next query.
+/// The is_final is signed by consumer, so the indexer only signals it with the `closed` flag.
+async fn is_closed(state: &QueryState) -> bool {
+    if state.is_final {
+        return true;
+    }
+    match ChannelEntry::get(&state.channel_id).await {
+        Some(channel) if !state.next_price.is_zero() => {
+            let next_spent = state.count.saturating_add(U256::one()).saturating_mul(state.next_price);
+            next_spent > channel.amount
+        }
+        _ => false,
+    }
+}
+
+/// Verify the signature of smart contract wallet by EIP-1271, the non-contract address is rejected.
+async fn verify_contract_sign(wallet: Address, hash: [u8; 32], sign: &Signature) -> Result<(), Error> {
+    let web3 = Web3::new(Http::new(COMMAND.web3()).map_err(|_| Error::ServiceException)?);
+    let cached = CONTRACT_WALLETS.read().await.get(&wallet).cloned();
+    let is_contract = match cached {
+        Some(is_contract) => is_contract,
+        None => {
+            let code = web3.eth().code(wallet, None).await.map_err(|e| {
+                warn!("Wallet {:?} code query failure: {}", wallet, e);
+                Error::ServiceException
+            })?;
+            let is_contract = !code.0.is_empty();
+            CONTRACT_WALLETS.write().await.insert(wallet, is_contract);
+            is_contract
+        }
+    };
+    if !is_contract {
+        return Err(Error::InvalidSignature);
+    }
+
+    let selector = keccak256(IS_VALID_SIGNATURE_FN.as_bytes())[..4].to_vec();
+    let mut data = selector.clone();
+    data.extend(encode(&[
+        H256::from(hash).into_token(),
+        convert_sign_to_bytes(sign).into_token(),
+    ]));
+    let result = web3
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(wallet),
+                data: Some(Bytes(data)),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .map_err(|_| Error::InvalidSignature)?;
+
+    // the returned bytes4 is left aligned.
+    if result.0.len() >= 4 && result.0[..4] == selector[..] {
+        Ok(())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+/// Check the channel is open on chain with the same amount, only the success is cached.
+async fn verify_onchain(channel_id: U256) -> Result<(), Error> {
+    if VERIFIED.read().await.contains(&channel_id) {
+        return Ok(());
+    }
+
+    let contract = COMMAND.state_channel().ok_or(Error::ChannelNotOnChain)?;
+    let web3 = Web3::new(Http::new(COMMAND.web3()).map_err(|_| Error::ServiceException)?);
+    let mut data = keccak256(CHANNEL_FN.as_bytes())[..4].to_vec();
+    data.extend(encode(&[channel_id.into_token()]));
+    let result = web3
+        .eth()
+        .call(
+            CallRequest {
+                to: Some(contract),
+                data: Some(Bytes(data)),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            warn!("Channel {:#X} on-chain query failure: {}", channel_id, e);
+            Error::ServiceException
+        })?;
+
+    // (status, indexer, consumer, count, amount, ...)
+    let types = [
+        ParamType::Uint(8),
+        ParamType::Address,
+        ParamType::Address,
+        ParamType::Uint(256),
+        ParamType::Uint(256),
+    ];
+    let tokens = decode(&types, &result.0).map_err(|_| Error::ChannelNotOnChain)?;
+    let status = tokens[0].clone().into_uint().unwrap_or_default();
+    let amount = tokens[4].clone().into_uint().unwrap_or_default();
+    if status != U256::from(CHANNEL_STATUS_OPEN) {
+        return Err(Error::ChannelNotOnChain);
+    }
+    if let Some(channel) = ChannelEntry::get(&channel_id).await {
+        if channel.amount != amount {
+            return Err(Error::ChannelNotOnChain);
+        }
+    }
+
+    VERIFIED.write().await.insert(channel_id);
+    Ok(())
+}
+
+/// Acquire a permit of the channel, bound the concurrent queries of it.
+fn channel_permit(channel: &ChannelEntry) -> Result<OwnedSemaphorePermit, Error> {
+    channel
+        .permits
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| Error::RateLimited)
+}
+
+/// The `errors` of GraphQL response when the `data` is null or missing.
+pub fn full_errors(result: &Value) -> Option<&Value> {
+    let errors = result.get("errors")?;
+    match result.get("data") {
+        None | Some(Value::Null) => Some(errors),
+        _ => None,
+    }
+}
+
+/// Sign the final state for close the channel, consumer can settle it on-chain.
+pub async fn close_state(state: &Value) -> Result<Value, Error> {
+    ensure_ready()?;
+    let mut state = QueryState::from_json(state)?;
+    if !state.is_final {
+        return Err(Error::InvalidRequest);
+    }
+    let channel = ChannelEntry::get(&state.channel_id)
+        .await
+        .ok_or(Error::ChannelNotFound)?;
+    if channel.closed {
+        return Err(Error::ChannelClosed);
+    }
+    if state.price != channel.price {
+        return Err(Error::InvalidStatePrice);
+    }
+    verify_consumer(&state, &channel).await?;
+
+    // no query is in-flight when closing.
+    let _permit = channel_permit(&channel)?;
+    let channel = ChannelEntry::get(&state.channel_id)
+        .await
+        .ok_or(Error::ChannelNotFound)?;
+    if state.count < channel.count {
+        return Err(Error::InvalidStateCount);
+    }
+    state.next_price = channel.price;
+
+    let account = ACCOUNT.read().await;
+    let key = account.controller_key()?;
+    state.sign(key, false)?;
+    drop(account);
+
+    update_state(&state, &channel.coordinator).await?;
+    ChannelEntry::close(&state.channel_id).await;
+
+    Ok(state.to_json())
+}
+
+/// The state must be final if and only if the spent reaches the channel amount,
+/// no more states after the final one.
+fn check_final(state: &QueryState, channel: &ChannelEntry) -> Result<(), Error> {
+    // unmetered (zero price) channel is terminated by expiration, final is up to consumer.
+    if channel.price.is_zero() {
+        return Ok(());
+    }
+    // the final state is signed already.
+    if channel.count.saturating_mul(channel.price) >= channel.amount {
+        return Err(Error::ChannelExhausted);
+    }
+
+    let should_final = state.count.saturating_mul(channel.price) >= channel.amount;
+    if state.is_final != should_final {
+        return Err(Error::InvalidFinalFlag);
+    }
+    Ok(())
+}
+
+/// Send the latest state to the channel's coordinator, use the `coordinator` if channel is unknown.
+async fn update_state(state: &QueryState, coordinator: &str) -> Result<(), Error> {
+    let channel = ChannelEntry::get(&state.channel_id).await;
+    let (amount, url, deployment) = match &channel {
+        Some(channel) => (channel.amount, channel.coordinator.clone(), channel.deployment.clone()),
+        None => (U256::zero(), coordinator.to_owned(), String::new()),
+    };
+    Coordinator::new(url).channel_update(state).await?;
+
+    if let Some(mut channel) = channel {
+        channel.count = state.count;
+        channel.state = Some(state.to_json());
+        ChannelEntry::upsert(state.channel_id, channel).await?;
+    }
+
+    let event = if state.is_final {
+        ChannelEvent::Finalized
+    } else {
+        ChannelEvent::Advanced
+    };
+    emit(
+        event,
         state.channel_id,
+        &deployment,
+        state.consumer,
+        amount,
         state.count,
-        state.is_final,
         state.price,
-        convert_sign_to_string(&state.indexer_sign),
-        convert_sign_to_string(&state.consumer_sign)
     );
+    if !state.is_final && is_closed(state).await {
+        emit(
+            ChannelEvent::Exhausted,
+            state.channel_id,
+            &deployment,
+            state.consumer,
+            amount,
+            state.count,
+            state.price,
+        );
+    }
 
-    let query = json!({ "query": mdata });
-    let result = graphql_request(&url, &query)
-        .await
-        .map_err(|_| Error::ServiceException)?;
-    let _ = result.get("data").ok_or(Error::ServiceException)?;
+    Ok(())
+}
 
-    Ok((state.to_json(), data))
+/// The payg routes are not found if payg disabled.
+pub fn payg_enabled() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(|| async {
+            if COMMAND.disable_payg() {
+                Err(reject::not_found())
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
 }
 
-pub fn with_state() -> impl Filter<Extract = (Value,), Error = Rejection> + Clone {
+/// Extract the (state, query) of payg, the state in header is preferred,
+/// then the `state` field of body, then the `?state=` query param.
+pub fn with_state() -> impl Filter<Extract = (Value, Value), Error = Rejection> + Clone {
     headers_cloned()
         .map(move |headers: HeaderMap<HeaderValue>| (headers))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::json())
         .and_then(authorize)
+        .untuple_one()
 }
 
-async fn authorize(headers: HeaderMap<HeaderValue>) -> WebResult<Value> {
-    let header = headers
-        .get(AUTHORIZATION)
-        .and_then(|x| x.to_str().ok())
-        .ok_or(reject::custom(Error::NoPermissionError))?;
-    serde_json::from_str::<Value>(header).map_err(|_| reject::custom(Error::InvalidAuthHeaderError))
+async fn authorize(
+    headers: HeaderMap<HeaderValue>,
+    params: HashMap<String, String>,
+    mut query: Value,
+) -> WebResult<(Value, Value)> {
+    // not forward the state to upstream.
+    let body_state = query.as_object_mut().and_then(|q| q.remove("state"));
+
+    let state = if let Some(header) = headers.get(AUTHORIZATION).and_then(|x| x.to_str().ok()) {
+        serde_json::from_str::<Value>(header).map_err(|_| reject::custom(Error::InvalidAuthHeaderError))?
+    } else if let Some(state) = body_state {
+        match state {
+            Value::String(s) => serde_json::from_str(&s).map_err(|_| reject::custom(Error::InvalidAuthHeaderError))?,
+            state => state,
+        }
+    } else if let Some(param) = params.get("state") {
+        serde_json::from_str::<Value>(param).map_err(|_| reject::custom(Error::InvalidAuthHeaderError))?
+    } else {
+        return Err(reject::custom(Error::NoPermissionError));
+    };
+
+    Ok((state, query))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{consumer, indexer, webhook_events, MockServer};
+    use web3::signing::{Key, SecretKeyRef};
+
+    /// Open a channel of the deployment with the consumer signed state, return the open response.
+    async fn open(deployment_id: [u8; 32], amount: u64) -> Result<Value, Error> {
+        let (indexer, _) = indexer().await;
+        let (consumer, sk) = consumer();
+        let expiration = U256::from(Utc::now().timestamp() + 3600);
+        let state = OpenState::consumer_generate(
+            None,
+            indexer,
+            consumer,
+            U256::from(amount),
+            expiration,
+            deployment_id,
+            vec![],
+            SecretKeyRef::new(&sk),
+        )?;
+        open_state(&state.to_json()).await
+    }
+
+    /// The consumer signed state of the opened channel.
+    fn signed(opened: &OpenState, count: u64, price: U256, is_final: bool) -> QueryState {
+        let (consumer, sk) = consumer();
+        QueryState::consumer_generate(
+            opened.channel_id,
+            opened.indexer,
+            consumer,
+            U256::from(count),
+            price,
+            is_final,
+            SecretKeyRef::new(&sk),
+        )
+        .unwrap()
+    }
+
+    /// Query the project with the consumer signed state, return the indexer signed state.
+    async fn query(
+        project: &str,
+        opened: &OpenState,
+        count: u64,
+        price: U256,
+        is_final: bool,
+    ) -> Result<QueryState, Error> {
+        let state = signed(opened, count, price, is_final);
+        let query = json!({ "query": "query { _metadata { chain } }" });
+        let (state, _data) = query_state(project, &state.to_json(), &query).await?;
+        QueryState::from_json(&state)
+    }
+
+    #[tokio::test]
+    async fn open_and_query_with_coordinator() {
+        let server = MockServer::start(json!("10"));
+        let (deployment_id, project) = server.project();
+        let controller = SecretKeyRef::new(&indexer().await.1).address();
+        let (consumer, _) = consumer();
+
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+        assert_eq!(opened.recover().unwrap(), (controller, consumer));
+        assert_eq!(opened.next_price, U256::from(10u64));
+        assert_eq!(server.requests("channelOpen").len(), 1);
+
+        let state = query(&project, &opened, 1, opened.next_price, false).await.unwrap();
+        assert_eq!(state.recover().unwrap(), (controller, consumer));
+        assert_eq!(state.price, U256::from(10u64));
+        assert_eq!(state.next_price, U256::from(10u64));
+        let updates = server.requests("channelUpdate");
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0]["count"], json!(1));
+        assert_eq!(ChannelEntry::get(&opened.channel_id).await.unwrap().count, U256::one());
+    }
+
+    #[tokio::test]
+    async fn route_updates_to_the_coordinator_of_deployment() {
+        let (first, second) = (MockServer::start(json!("10")), MockServer::start(json!("10")));
+        let (first_id, first_project) = first.project();
+        let (second_id, second_project) = second.project();
+
+        let first_opened = OpenState::from_json(&open(first_id, 100).await.unwrap()).unwrap();
+        let second_opened = OpenState::from_json(&open(second_id, 100).await.unwrap()).unwrap();
+        assert_eq!(first.requests("channelOpen").len(), 1);
+        assert_eq!(second.requests("channelOpen").len(), 1);
+
+        query(&first_project, &first_opened, 1, first_opened.next_price, false)
+            .await
+            .unwrap();
+        query(&first_project, &first_opened, 2, first_opened.next_price, false)
+            .await
+            .unwrap();
+        query(&second_project, &second_opened, 1, second_opened.next_price, false)
+            .await
+            .unwrap();
+
+        let id = |opened: &OpenState| json!(format!("{:#X}", opened.channel_id));
+        let first_updates = first.requests("channelUpdate");
+        assert_eq!(first_updates.len(), 2);
+        assert!(first_updates.iter().all(|update| update["id"] == id(&first_opened)));
+        let second_updates = second.requests("channelUpdate");
+        assert_eq!(second_updates.len(), 1);
+        assert_eq!(second_updates[0]["id"], id(&second_opened));
+    }
+
+    #[tokio::test]
+    async fn post_the_open_event_to_webhook() {
+        let server = MockServer::start(json!("10"));
+        let (deployment_id, project) = server.project();
+        let (consumer, _) = consumer();
+
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+        let channel_id = format!("{:#X}", opened.channel_id);
+        let mut events = vec![];
+        for _ in 0..50 {
+            events = webhook_events(&channel_id);
+            if !events.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["event"], "opened");
+        assert_eq!(event["deployment"], json!(project));
+        assert_eq!(event["consumer"], json!(format!("{:?}", consumer)));
+        assert_eq!(event["amount"], "100");
+        assert_eq!(event["count"], "0");
+        assert_eq!(event["spent"], "0");
+        assert_eq!(event["remaining"], "100");
+        assert!(event["timestamp"].as_i64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn open_with_fallback_price() {
+        // the fallback price of tests is 7.
+        let server = MockServer::start(Value::Null);
+        let (deployment_id, _) = server.project();
+
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+        assert_eq!(opened.next_price, U256::from(7u64));
+        assert_eq!(server.requests("channelOpen").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_price_channel_is_unmetered_until_expiration() {
+        let server = MockServer::start(json!(0));
+        let (deployment_id, project) = server.project();
+
+        let res = open(deployment_id, 100).await.unwrap();
+        assert_eq!(res["unmetered"], json!(true));
+        let opened = OpenState::from_json(&res).unwrap();
+        assert!(opened.next_price.is_zero());
+
+        // never final by the spent.
+        let state = query(&project, &opened, 1000, U256::zero(), false).await.unwrap();
+        assert!(!state.is_final);
+        assert!(state.next_price.is_zero());
+
+        let mut channel = ChannelEntry::get(&opened.channel_id).await.unwrap();
+        channel.expiration = U256::from(Utc::now().timestamp() - 1);
+        ChannelEntry::upsert(opened.channel_id, channel).await.unwrap();
+        let res = query(&project, &opened, 1001, U256::zero(), false).await;
+        assert!(matches!(res, Err(Error::ChannelExpired)));
+    }
+
+    #[tokio::test]
+    async fn normal_price_channel_rejects_other_price() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, project) = server.project();
+
+        let res = open(deployment_id, 100).await.unwrap();
+        assert_eq!(res["unmetered"], json!(false));
+        let opened = OpenState::from_json(&res).unwrap();
+        assert_eq!(opened.next_price, U256::from(10u64));
+
+        // the consumer signed zero price is not free on the metered channel.
+        let res = query(&project, &opened, 1, U256::zero(), false).await;
+        assert!(matches!(res, Err(Error::InvalidStatePrice)));
+        let res = query(&project, &opened, 1, U256::from(11u64), false).await;
+        assert!(matches!(res, Err(Error::InvalidStatePrice)));
+
+        let state = query(&project, &opened, 1, U256::from(10u64), false).await.unwrap();
+        assert_eq!(state.price, U256::from(10u64));
+        assert_eq!(server.requests("channelUpdate").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unsigned_state_holds_no_permit() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, project) = server.project();
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+
+        // signed by other key, but claimed as the consumer of channel.
+        let (consumer, _) = consumer();
+        let other = secp256k1::SecretKey::from_slice(&[0x33; 32]).unwrap();
+        let state = QueryState::consumer_generate(
+            opened.channel_id,
+            opened.indexer,
+            consumer,
+            U256::one(),
+            U256::from(10u64),
+            false,
+            SecretKeyRef::new(&other),
+        )
+        .unwrap();
+        let res = prepare_state(&project, &state.to_json()).await;
+        assert!(matches!(res, Err(Error::InvalidSignature)));
+
+        let channel = ChannelEntry::get(&opened.channel_id).await.unwrap();
+        assert_eq!(channel.permits.available_permits(), COMMAND.channel_max_inflight());
+        let (_, permit) = prepare_state(&project, &signed(&opened, 1, U256::from(10u64), false).to_json())
+            .await
+            .unwrap();
+        assert_eq!(channel.permits.available_permits(), COMMAND.channel_max_inflight() - 1);
+        drop(permit);
+    }
+
+    #[tokio::test]
+    async fn close_with_the_consumer_signed_final_state() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, project) = server.project();
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+        query(&project, &opened, 2, U256::from(10u64), false).await.unwrap();
+
+        let mut unknown = signed(&opened, 2, U256::from(10u64), true);
+        unknown.channel_id = U256::one();
+        let res = close_state(&unknown.to_json()).await;
+        assert!(matches!(res, Err(Error::ChannelNotFound)));
+
+        let res = close_state(&signed(&opened, 1, U256::from(10u64), true).to_json()).await;
+        assert!(matches!(res, Err(Error::InvalidStateCount)));
+        let res = close_state(&signed(&opened, 2, U256::from(20u64), true).to_json()).await;
+        assert!(matches!(res, Err(Error::InvalidStatePrice)));
+        let mut forged = signed(&opened, 2, U256::from(10u64), true);
+        forged.consumer_sign = signed(&opened, 3, U256::from(10u64), true).consumer_sign;
+        let res = close_state(&forged.to_json()).await;
+        assert!(matches!(res, Err(Error::InvalidSignature)));
+
+        let res = close_state(&signed(&opened, 2, U256::from(10u64), true).to_json()).await;
+        let state = QueryState::from_json(&res.unwrap()).unwrap();
+        assert_eq!(state.recover().unwrap().1, opened.consumer);
+        assert_eq!(state.next_price, U256::from(10u64));
+        assert!(ChannelEntry::get(&opened.channel_id).await.unwrap().closed);
+        assert_eq!(server.requests("channelUpdate").len(), 2);
+
+        let res = query(&project, &opened, 3, U256::from(10u64), false).await;
+        assert!(matches!(res, Err(Error::ChannelClosed)));
+        let res = close_state(&signed(&opened, 3, U256::from(10u64), true).to_json()).await;
+        assert!(matches!(res, Err(Error::ChannelClosed)));
+    }
+
+    #[tokio::test]
+    async fn final_flag_must_match_the_spent() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, project) = server.project();
+        let opened = OpenState::from_json(&open(deployment_id, 100).await.unwrap()).unwrap();
+        let price = U256::from(10u64);
+
+        // premature final, spent 50 of 100.
+        let res = query(&project, &opened, 5, price, true).await;
+        assert!(matches!(res, Err(Error::InvalidFinalFlag)));
+        // missing final, spent 100 of 100.
+        let res = query(&project, &opened, 10, price, false).await;
+        assert!(matches!(res, Err(Error::InvalidFinalFlag)));
+        assert!(server.requests("channelUpdate").is_empty());
+
+        let state = query(&project, &opened, 10, price, true).await.unwrap();
+        assert!(state.is_final);
+        assert_eq!(server.requests("channelUpdate").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn exhausted_channel_rejects_queries() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, project) = server.project();
+        let opened = OpenState::from_json(&open(deployment_id, 20).await.unwrap()).unwrap();
+        let price = U256::from(10u64);
+
+        query(&project, &opened, 2, price, true).await.unwrap();
+        let res = query(&project, &opened, 3, price, true).await;
+        assert!(matches!(res, Err(Error::ChannelExhausted)));
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_is_bound_to_the_body() {
+        let server = MockServer::start(json!(10));
+        let (deployment_id, _) = server.project();
+        let (indexer, _) = indexer().await;
+        let (consumer, sk) = consumer();
+        let expiration = U256::from(Utc::now().timestamp() + 3600);
+        let generate = |deployment_id, amount: u64| {
+            OpenState::consumer_generate(
+                None,
+                indexer,
+                consumer,
+                U256::from(amount),
+                expiration,
+                deployment_id,
+                vec![],
+                SecretKeyRef::new(&sk),
+            )
+            .unwrap()
+            .to_json()
+        };
+        let key = || Some(format!("key-{:?}", deployment_id));
+
+        let body = generate(deployment_id, 100);
+        let first = open_state_idempotent(key(), &body).await.unwrap();
+        let second = open_state_idempotent(key(), &body).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(server.requests("channelOpen").len(), 1);
+
+        let res = open_state_idempotent(key(), &generate(deployment_id, 200)).await;
+        assert!(matches!(res, Err(Error::IdempotencyKeyReused)));
+
+        // the failed open (unknown coordinator) releases the key.
+        let key = "key-failed".to_owned();
+        assert!(open_state_idempotent(Some(key.clone()), &generate([0xff; 32], 100))
+            .await
+            .is_err());
+        let reserved = (consumer, key);
+        assert!(!OPENED.lock().unwrap().contains_key(&reserved));
+    }
+
+    #[tokio::test]
+    async fn validate_open_without_open() {
+        let server = MockServer::start(json!("12"));
+        let (deployment_id, _) = server.project();
+        let (indexer, _) = indexer().await;
+        let (consumer, sk) = consumer();
+        let generate = |expiration: i64| {
+            OpenState::consumer_generate(
+                None,
+                indexer,
+                consumer,
+                U256::from(100u64),
+                U256::from(Utc::now().timestamp() + expiration),
+                deployment_id,
+                vec![],
+                SecretKeyRef::new(&sk),
+            )
+            .unwrap()
+            .to_json()
+        };
+
+        let res = validate_open(&generate(3600)).await;
+        assert_eq!(res, json!({ "valid": true, "nextPrice": "12" }));
+        assert_eq!(server.requests("getProjectPrice").len(), 1);
+        assert!(server.requests("channelOpen").is_empty());
+
+        let res = validate_open(&generate(-1)).await;
+        assert_eq!(res["valid"], json!(false));
+        assert_eq!(res["error"], json!(Error::ChannelExpired.to_string()));
+        let res = open_state(&generate(-1)).await;
+        assert!(matches!(res, Err(Error::ChannelExpired)));
+    }
 }
@@ -0,0 +1,60 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+//! Persisted queries, only the stored queries are allowed when enabled.
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use subql_proxy_utils::error::Error;
+
+use crate::cli::COMMAND;
+
+/// The persisted queries, query hash => query text. None if not enabled.
+pub static PERSISTED: Lazy<Option<HashMap<String, String>>> = Lazy::new(|| {
+    let path = COMMAND.persisted_queries()?;
+    let file = std::fs::File::open(path).expect("Read persisted queries failed");
+    let queries: HashMap<String, String> =
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("Invalid persisted queries");
+    Some(queries.into_iter().map(|(k, v)| (normalize_hash(&k), v)).collect())
+});
+
+fn normalize_hash(hash: &str) -> String {
+    hash.trim_start_matches("0x").to_lowercase()
+}
+
+/// Resolve the `{"id": "<hash>"}` body to the stored query, the variables are kept.
+/// Return the body unchanged if the persisted queries are not enabled.
+pub fn resolve_query(body: Value) -> Result<Value, Error> {
+    let queries = match PERSISTED.as_ref() {
+        Some(queries) => queries,
+        None => return Ok(body),
+    };
+    if body.get("query").is_some() {
+        return Err(Error::InvalidRequest);
+    }
+
+    let id = body.get("id").and_then(|id| id.as_str()).ok_or(Error::InvalidRequest)?;
+    let query = queries.get(&normalize_hash(id)).ok_or(Error::InvalidRequest)?;
+    let mut resolved = json!({ "query": query });
+    for key in ["variables", "operationName"] {
+        if let Some(value) = body.get(key) {
+            resolved[key] = value.clone();
+        }
+    }
+    Ok(resolved)
+}
@@ -18,12 +18,19 @@
 
 use once_cell::sync::Lazy;
 use reqwest::header::HeaderValue;
-use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use subql_proxy_utils::{error::Error, request::graphql_request};
+use std::time::{Duration, Instant};
+use subql_proxy_utils::{
+    coordinator::{Coordinator, ProjectItem},
+    error::{Error, GraphQLServerError},
+    query::METADATA_QUERY,
+    request::upstream_request,
+    traits::Hash,
+};
+use tokio::sync::Semaphore;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::{connect, Message};
 
@@ -31,14 +38,108 @@ use crate::cli::COMMAND;
 
 pub static PROJECTS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
-pub fn add_project(deployment_id: String, url: String) {
+/// The coordinator which the project registered, deployment id => coordinator url.
+pub static COORDINATORS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The coordinators configured per deployment, preferred over the registered ones,
+/// deployment id => coordinator url.
+static COORDINATOR_OVERRIDES: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    if let Some(path) = COMMAND.project_coordinators() {
+        let file = std::fs::File::open(path).expect("Read project coordinators failed");
+        let coordinators: HashMap<String, String> =
+            serde_json::from_reader(std::io::BufReader::new(file)).expect("Invalid project coordinators");
+        coordinators
+            .into_iter()
+            .map(|(k, v)| (deployment_key(&k).unwrap_or(k), v))
+            .collect()
+    } else {
+        HashMap::new()
+    }
+});
+
+pub fn add_project(deployment_id: String, url: String, coordinator: &str) {
+    let deployment_id = deployment_key(&deployment_id).unwrap_or_else(|_| {
+        warn!("Project with unknown deployment id format: {}", deployment_id);
+        deployment_id
+    });
     let mut map = PROJECTS.lock().unwrap();
-    map.insert(deployment_id, url);
+    map.insert(deployment_id.clone(), url);
+    drop(map);
+    COORDINATORS
+        .lock()
+        .unwrap()
+        .insert(deployment_id, coordinator.to_owned());
+}
+
+/// Get the coordinator of the project, default is the main coordinator.
+pub fn get_coordinator(key: &str) -> String {
+    let key = deployment_key(key).unwrap_or_else(|_| key.to_owned());
+    if let Some(url) = COORDINATOR_OVERRIDES.get(&key) {
+        return url.clone();
+    }
+    let map = COORDINATORS.lock().unwrap();
+    map.get(&key)
+        .cloned()
+        .unwrap_or_else(|| COMMAND.service_url().to_owned())
+}
+
+/// Normalize the deployment id in bs58 CIDv0 (Qm...) or hex (`0x` prefix is optional) to the digest bytes.
+pub fn normalize_deployment_id(id: &str) -> Result<[u8; 32], Error> {
+    let bytes = match id.strip_prefix("0x") {
+        Some(s) => hex::decode(s).map_err(|_| Error::InvalidProejctId)?,
+        None => match bs58::decode(id).into_vec() {
+            // CIDv0 is the sha2-256 multihash.
+            Ok(bytes) if bytes.len() == 34 && bytes[..2] == [0x12, 0x20] => bytes[2..].to_vec(),
+            Ok(bytes) if bytes.len() == 32 => bytes,
+            _ => hex::decode(id).map_err(|_| Error::InvalidProejctId)?,
+        },
+    };
+    if bytes.len() != 32 {
+        return Err(Error::InvalidProejctId);
+    }
+    let mut deployment_id = [0u8; 32];
+    deployment_id.copy_from_slice(&bytes);
+    Ok(deployment_id)
+}
+
+/// The canonical key (bs58 CIDv0) of deployment id, projects are stored and looked up with it.
+pub fn deployment_key(id: &str) -> Result<String, Error> {
+    let [key, ..] = deployment_keys(&normalize_deployment_id(id)?);
+    Ok(key)
+}
+
+/// The possible stored keys of deployment id bytes, CIDv0 (Qm...) or hex.
+pub fn deployment_keys(deployment_id: &[u8; 32]) -> [String; 3] {
+    let mut cid = vec![0x12, 0x20]; // sha2-256 multihash prefix
+    cid.extend_from_slice(deployment_id);
+    [
+        bs58::encode(&cid).into_string(),
+        format!("0x{}", hex::encode(deployment_id)),
+        hex::encode(deployment_id),
+    ]
+}
+
+/// Get the coordinator of the project by deployment id bytes,
+/// the project maybe stored as CIDv0 (Qm...) or hex.
+pub fn get_coordinator_by_bytes(deployment_id: &[u8; 32]) -> String {
+    let keys = deployment_keys(deployment_id);
+    if let Some(url) = COORDINATOR_OVERRIDES.get(&keys[0]) {
+        return url.clone();
+    }
+
+    let map = COORDINATORS.lock().unwrap();
+    for key in keys.iter() {
+        if let Some(url) = map.get(key) {
+            return url.clone();
+        }
+    }
+    COMMAND.service_url().to_owned()
 }
 
 pub fn get_project(key: &str) -> Result<String, Error> {
+    let key = deployment_key(key).unwrap_or_else(|_| key.to_owned());
     let map = PROJECTS.lock().unwrap();
-    let url = match map.get(key) {
+    let url = match map.get(&key) {
         Some(url) => url,
         None => return Err(Error::InvalidProejctId),
     };
@@ -50,36 +151,79 @@ pub fn list_projects() -> Vec<String> {
     map.keys().map(|v| v.to_owned()).collect()
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProjectsResponse {
-    #[serde(rename = "getAliveProjects")]
-    get_alive_projects: Vec<ProjectItem>,
+/// Seconds to reuse the metadata of project before querying the upstream again.
+const METADATA_TTL: Duration = Duration::from_secs(5);
+
+/// The last metadata of projects, deployment id => (queried time, metadata, etag).
+static METADATA: Lazy<Mutex<HashMap<String, (Instant, Value, String)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The metadata of project with its ETag (the hash of content), reused within `METADATA_TTL`.
+pub async fn project_metadata(id: &str, url: &str) -> Result<(Value, String), GraphQLServerError> {
+    if let Some((at, metadata, etag)) = METADATA.lock().unwrap().get(id) {
+        if at.elapsed() < METADATA_TTL {
+            return Ok((metadata.clone(), etag.clone()));
+        }
+    }
+
+    let query = json!({ "query": METADATA_QUERY });
+    let metadata = upstream_request(url, &query).await?;
+    let etag = format!("\"{}\"", metadata.to_string().hash());
+    METADATA
+        .lock()
+        .unwrap()
+        .insert(id.to_owned(), (Instant::now(), metadata.clone(), etag.clone()));
+    Ok((metadata, etag))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProjectItem {
-    id: String,
-    #[serde(rename = "queryEndpoint")]
-    query_endpoint: String,
+/// Max number of concurrent metadata requests to the projects.
+const METADATA_CONCURRENCY: usize = 8;
+
+/// Query the metadata of all hosted projects concurrently,
+/// the failure project includes the error rather than failing all.
+pub async fn projects_metadata() -> Value {
+    let projects: Vec<(String, String)> = PROJECTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    let semaphore = Arc::new(Semaphore::new(METADATA_CONCURRENCY));
+    let mut handles = vec![];
+    for (id, url) in projects {
+        let semaphore = semaphore.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire().await;
+            let query = json!({ "query": METADATA_QUERY });
+            match upstream_request(&url, &query).await {
+                Ok(result) => json!({ "id": id, "metadata": result.pointer("/data/_metadata") }),
+                Err(err) => json!({ "id": id, "error": err.to_string() }),
+            }
+        }));
+    }
+
+    let mut results = vec![];
+    for handle in handles {
+        if let Ok(result) = handle.await {
+            results.push(result);
+        }
+    }
+    Value::Array(results)
 }
 
 pub async fn init_projects() {
-    let url = COMMAND.service_url();
-    // graphql query for getting alive projects
-    let query = json!({ "query": "query { getAliveProjects { id queryEndpoint } }" });
-    let result = graphql_request(&url, &query).await;
-
-    match result {
-        Ok(value) => match value.pointer("/data") {
-            Some(v_d) => {
-                let v_str: String = serde_json::to_string(v_d).unwrap_or(String::from(""));
-                let v: ProjectsResponse = serde_json::from_str(v_str.as_str()).unwrap();
-                for item in v.get_alive_projects {
-                    add_project(item.id, item.query_endpoint);
-                }
+    for url in COMMAND.service_urls() {
+        init_coordinator_projects(url).await;
+    }
+}
+
+async fn init_coordinator_projects(url: &str) {
+    match Coordinator::new(url).alive_projects().await {
+        Ok(projects) => {
+            for item in projects {
+                add_project(item.id, item.query_endpoint, url);
             }
-            _ => {}
-        },
+        }
         Err(e) => println!("Init projects failed: {}", e),
     };
 
@@ -87,9 +231,11 @@ pub async fn init_projects() {
 }
 
 pub fn subscribe() {
-    thread::spawn(move || {
-        subscribe_project_change(COMMAND.service_url());
-    });
+    for url in COMMAND.service_urls() {
+        thread::spawn(move || {
+            subscribe_project_change(url);
+        });
+    }
 }
 
 fn subscribe_project_change(url: &str) {
@@ -117,7 +263,7 @@ fn subscribe_project_change(url: &str) {
         let value: Value = serde_json::from_str(text).unwrap();
         let project = value.pointer("/payload/data/projectChanged").unwrap();
         let item: ProjectItem = serde_json::from_str(project.to_string().as_str()).unwrap();
-        add_project(item.id, item.query_endpoint);
+        add_project(item.id, item.query_endpoint, url);
 
         debug!("indexing projects: {:?}", PROJECTS.lock().unwrap());
     }
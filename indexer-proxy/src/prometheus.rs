@@ -18,6 +18,11 @@
 
 use once_cell::sync::Lazy;
 use prometheus::{labels, register_int_counter_vec, IntCounterVec};
+use subql_proxy_utils::error::{Error, GraphQLServerError};
+use warp::Rejection;
+
+#[cfg(feature = "p2p")]
+use prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec};
 
 use crate::{account, cli::COMMAND};
 
@@ -25,7 +30,115 @@ pub static QUERY_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "subquery_indexer_query_total",
         "Total number of query request.",
-        &["deployment_id"]
+        &["deployment_id", "auth"]
+    )
+    .unwrap()
+});
+
+pub static QUERY_ERROR_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "subquery_indexer_query_errors_total",
+        "Total number of failed query request by the kind of fault.",
+        &["deployment_id", "kind"]
+    )
+    .unwrap()
+});
+
+/// The kind of query failure, tells the upstream query node fault from the proxy's own.
+pub enum ErrorKind {
+    /// transport failure or 5xx of upstream.
+    Upstream,
+    /// upstream responded the `errors` of query.
+    GraphQLUser,
+    /// signature, auth, serialization and other proxy checks.
+    Proxy,
+}
+
+impl ErrorKind {
+    fn label(&self) -> &'static str {
+        match self {
+            ErrorKind::Upstream => "upstream_error",
+            ErrorKind::GraphQLUser => "graphql_user_error",
+            ErrorKind::Proxy => "proxy_error",
+        }
+    }
+
+    pub fn from_error(err: &Error) -> Self {
+        match err {
+            Error::UpstreamUnavailable | Error::Timeout => ErrorKind::Upstream,
+            Error::GraphQLQueryError(_) | Error::UpstreamBadRequest(_) => ErrorKind::GraphQLUser,
+            _ => ErrorKind::Proxy,
+        }
+    }
+
+    pub fn from_rejection(err: &Rejection) -> Option<Self> {
+        if let Some(e) = err.find::<Error>() {
+            Some(Self::from_error(e))
+        } else if let Some(e) = err.find::<GraphQLServerError>() {
+            match e {
+                GraphQLServerError::BadRequest(_) => Some(ErrorKind::GraphQLUser),
+                // the query error is the transport failure of upstream request.
+                GraphQLServerError::QueryError(_)
+                | GraphQLServerError::InternalError(_)
+                | GraphQLServerError::UpstreamError(_)
+                | GraphQLServerError::Timeout(_) => Some(ErrorKind::Upstream),
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// How the query is paid, the paid PAYG query or the agreement (JWT token) query.
+pub enum QueryAuth {
+    Payg,
+    Agreement,
+}
+
+impl QueryAuth {
+    fn label(&self) -> &'static str {
+        match self {
+            QueryAuth::Payg => "payg",
+            QueryAuth::Agreement => "agreement",
+        }
+    }
+}
+
+pub fn push_error_metrics(id: &str, kind: ErrorKind) {
+    QUERY_ERROR_COUNTER.with_label_values(&[id, kind.label()]).inc();
+}
+
+#[cfg(feature = "p2p")]
+pub static P2P_PEERS_GAUGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("subquery_indexer_p2p_connected_peers", "Number of connected p2p peers.").unwrap()
+});
+
+#[cfg(feature = "p2p")]
+pub static P2P_GROUP_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "subquery_indexer_p2p_group_peers",
+        "Number of peers in the joined p2p group.",
+        &["group"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "p2p")]
+pub static P2P_PING_GAUGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "subquery_indexer_p2p_ping_rtt_ms",
+        "Last ping round-trip time of the connected p2p peer in milliseconds.",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "p2p")]
+pub static P2P_RPC_DROPPED_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "subquery_indexer_p2p_rpc_dropped_total",
+        "Total number of P2P JSON-RPC messages dropped for the full queue.",
+        &["kind"]
     )
     .unwrap()
 });
@@ -40,15 +153,15 @@ fn pushgateway_url() -> String {
     url.to_string()
 }
 
-pub fn push_query_metrics(id: String) {
-    tokio::spawn(push_query_total(id));
+pub fn push_query_metrics(id: String, auth: QueryAuth) {
+    tokio::spawn(push_query_total(id, auth));
 }
 
-pub async fn push_query_total(id: String) {
+pub async fn push_query_total(id: String, auth: QueryAuth) {
     let url = pushgateway_url();
     let indexer = account::get_indexer().await;
 
-    QUERY_COUNTER.with_label_values(&[&id]).inc();
+    QUERY_COUNTER.with_label_values(&[&id, auth.label()]).inc();
 
     let _ = prometheus::push_add_metrics(
         "subql_indexer_query",
@@ -58,3 +171,50 @@ pub async fn push_query_total(id: String) {
         None,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_errors_to_kind() {
+        let table = [
+            (warp::reject::custom(Error::UpstreamUnavailable), "upstream_error"),
+            (warp::reject::custom(Error::Timeout), "upstream_error"),
+            (
+                warp::reject::custom(Error::GraphQLQueryError("e".into())),
+                "graphql_user_error",
+            ),
+            (
+                warp::reject::custom(Error::UpstreamBadRequest("e".into())),
+                "graphql_user_error",
+            ),
+            (warp::reject::custom(Error::InvalidSignature), "proxy_error"),
+            (
+                warp::reject::custom(GraphQLServerError::QueryError("e".into())),
+                "upstream_error",
+            ),
+            (
+                warp::reject::custom(GraphQLServerError::InternalError("e".into())),
+                "upstream_error",
+            ),
+            (
+                warp::reject::custom(GraphQLServerError::UpstreamError("e".into())),
+                "upstream_error",
+            ),
+            (
+                warp::reject::custom(GraphQLServerError::Timeout("e".into())),
+                "upstream_error",
+            ),
+            (
+                warp::reject::custom(GraphQLServerError::BadRequest("e".into())),
+                "graphql_user_error",
+            ),
+        ];
+        for (rejection, label) in table {
+            let kind = ErrorKind::from_rejection(&rejection).unwrap();
+            assert_eq!(kind.label(), label, "{:?}", rejection);
+        }
+        assert!(ErrorKind::from_rejection(&warp::reject::not_found()).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Response fields masking for the JWT scope of agreement consumers.
+
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use web3::types::Address;
+
+use crate::cli::COMMAND;
+use crate::project::deployment_key;
+
+/// The scope of tokens without scope.
+const DEFAULT_SCOPE: &str = "default";
+
+/// The allowed response fields, deployment id => (scope => fields).
+/// Field is the path in `data`, e.g. `_metadata.chain`.
+pub static SCOPES: Lazy<HashMap<String, HashMap<String, Vec<String>>>> = Lazy::new(|| {
+    if let Some(path) = COMMAND.scope_fields() {
+        let file = std::fs::File::open(path).expect("Read scope fields failed");
+        let scopes: HashMap<String, HashMap<String, Vec<String>>> =
+            serde_json::from_reader(std::io::BufReader::new(file)).expect("Invalid scope fields");
+        scopes
+            .into_iter()
+            .map(|(k, v)| (deployment_key(&k).unwrap_or(k), v))
+            .collect()
+    } else {
+        HashMap::new()
+    }
+});
+
+/// The scope of agreement, agreement address => scope, the token scope is decided by its agreement.
+pub static AGREEMENT_SCOPES: Lazy<HashMap<Address, String>> = Lazy::new(|| {
+    if let Some(path) = COMMAND.agreement_scopes() {
+        let file = std::fs::File::open(path).expect("Read agreement scopes failed");
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("Invalid agreement scopes")
+    } else {
+        HashMap::new()
+    }
+});
+
+/// The scope of the agreement, None if the agreement is unknown or not scoped.
+pub fn agreement_scope(agreement: &Option<String>) -> Option<String> {
+    let agreement: Address = agreement.as_ref()?.parse().ok()?;
+    AGREEMENT_SCOPES.get(&agreement).cloned()
+}
+
+/// The deployment has scopes configured, its responses must be masked.
+pub fn is_scoped(deployment: &str) -> bool {
+    SCOPES.contains_key(deployment)
+}
+
+/// Remove the fields of the response which the scope isn't entitled to.
+/// No masking if the deployment is not configured.
+pub fn mask_response(deployment: &str, scope: &Option<String>, response: &mut Value) {
+    if let Some(scopes) = SCOPES.get(deployment) {
+        mask_scoped(scopes, scope, response);
+    }
+}
+
+/// Mask the response by the fields of scope. The token without scope, or with an unknown scope,
+/// gets the `default` scope, or nothing if no `default`.
+fn mask_scoped(scopes: &HashMap<String, Vec<String>>, scope: &Option<String>, response: &mut Value) {
+    let empty = vec![];
+    let fields = scope
+        .as_ref()
+        .and_then(|s| scopes.get(s))
+        .or_else(|| scopes.get(DEFAULT_SCOPE))
+        .unwrap_or(&empty);
+    if let Some(data) = response.get_mut("data") {
+        let paths: Vec<Vec<&str>> = fields.iter().map(|f| f.split('.').collect()).collect();
+        mask_value(data, &paths);
+    }
+}
+
+fn mask_value(value: &mut Value, paths: &[Vec<&str>]) {
+    match value {
+        Value::Object(map) => {
+            let old = std::mem::replace(map, Map::new());
+            for (key, mut v) in old {
+                let subpaths: Vec<Vec<&str>> = paths
+                    .iter()
+                    .filter(|p| p.first() == Some(&key.as_str()))
+                    .map(|p| p[1..].to_vec())
+                    .collect();
+                if subpaths.is_empty() {
+                    continue;
+                }
+                // the whole field is allowed if any path ends here.
+                if subpaths.iter().all(|p| !p.is_empty()) {
+                    mask_value(&mut v, &subpaths);
+                }
+                map.insert(key, v);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                mask_value(item, paths);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn mask_by_the_scope() {
+        let scopes: HashMap<String, Vec<String>> = serde_json::from_value(json!({
+            "basic": ["_metadata.chain"],
+            "full": ["_metadata", "accounts"],
+        }))
+        .unwrap();
+        let response = json!({ "data": {
+            "_metadata": { "chain": "test", "lastProcessedHeight": 1 },
+            "accounts": [{ "id": "a" }],
+        }});
+
+        let mut basic = response.clone();
+        mask_scoped(&scopes, &Some("basic".to_owned()), &mut basic);
+        assert_eq!(basic, json!({ "data": { "_metadata": { "chain": "test" } } }));
+
+        let mut full = response.clone();
+        mask_scoped(&scopes, &Some("full".to_owned()), &mut full);
+        assert_eq!(full, response);
+
+        // no default scope, nothing allowed.
+        let mut none = response.clone();
+        mask_scoped(&scopes, &None, &mut none);
+        assert_eq!(none, json!({ "data": {} }));
+        let mut unknown = response.clone();
+        mask_scoped(&scopes, &Some("admin".to_owned()), &mut unknown);
+        assert_eq!(unknown, json!({ "data": {} }));
+    }
+
+    #[test]
+    fn mask_by_the_default_scope() {
+        let scopes: HashMap<String, Vec<String>> = serde_json::from_value(json!({
+            "default": ["accounts.id"],
+            "full": ["_metadata", "accounts"],
+        }))
+        .unwrap();
+        let mut response = json!({ "data": {
+            "_metadata": { "chain": "test" },
+            "accounts": [{ "id": "a", "balance": 1 }],
+        }});
+        mask_scoped(&scopes, &None, &mut response);
+        assert_eq!(response, json!({ "data": { "accounts": [{ "id": "a" }] } }));
+    }
+}
@@ -17,23 +17,47 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 #![deny(warnings)]
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
+use once_cell::sync::Lazy;
 use serde::Serialize;
 use serde_json::{json, Value};
 use subql_proxy_utils::{
-    constants::HEADERS,
-    error::{handle_rejection, Error},
-    query::METADATA_QUERY,
-    request::graphql_request,
+    constants::{HEADERS, IDEMPOTENCY_KEY, REQUEST_ID},
+    error::{handle_rejection, method_fallback, Error},
+    query::{redact_variables, validate_query},
+    request::{upstream_request, upstream_request_with_headers, upstream_response},
+    tools::request_id,
+    traits::Hash,
     types::WebResult,
 };
-use warp::{reject, reply, Filter, Reply};
+use tracing::Instrument;
+use warp::{
+    filters::header::headers_cloned,
+    http::{
+        header::{HeaderMap, HeaderValue, CONTENT_TYPE, ETAG},
+        StatusCode,
+    },
+    hyper::Body,
+    reject, reply, Filter, Rejection, Reply,
+};
+use web3::types::{Address, U256};
 
+use crate::admin::{self, with_admin};
 use crate::auth::{self, with_auth};
-use crate::payg::{open_state, query_state, with_state};
-use crate::project::get_project;
-use crate::{account, cli::COMMAND, prometheus};
+use crate::cache;
+use crate::cli::COMMAND;
+use crate::credit::{self, with_credit};
+use crate::event::sse_events;
+use crate::payg::{
+    full_errors, latest_state, open_state_idempotent, payg_enabled, query_state, query_state_stream, validate_open,
+    with_state, PRICE,
+};
+use crate::persisted::resolve_query;
+use crate::project::{deployment_key, get_project, project_metadata, projects_metadata};
+use crate::prometheus::{self, ErrorKind, QueryAuth};
+use crate::scope::{is_scoped, mask_response};
+use crate::version::build_info;
 
 #[derive(Serialize)]
 pub struct QueryUri {
@@ -47,6 +71,35 @@ pub struct QueryToken {
     pub token: String,
 }
 
+/// The known paths and their methods, for the 405 and `OPTIONS` fallback.
+/// The PAYG paths are unknown (404) if PAYG disabled.
+static ROUTES: Lazy<Vec<(&str, &str)>> = Lazy::new(|| known_routes(!COMMAND.disable_payg()));
+
+fn known_routes(payg: bool) -> Vec<(&'static str, &'static str)> {
+    let mut routes = vec![
+        ("token", "POST"),
+        ("query/*", "POST"),
+        ("metadata", "GET"),
+        ("metadata/*", "GET"),
+        ("version", "GET"),
+        ("admin/channels", "GET"),
+    ];
+    if payg {
+        routes.extend([
+            ("open", "POST"),
+            ("open/validate", "POST"),
+            ("payg/*", "POST"),
+            ("credit/*", "GET"),
+            ("credit", "POST"),
+            ("credit/query/*", "POST"),
+            ("channel/*/state", "GET"),
+            ("events", "GET"),
+            ("admin/channels/*/close", "POST"),
+        ]);
+    }
+    routes
+}
+
 pub async fn start_server(host: &str, port: u16) {
     // create token for query.
     let token_route = warp::path!("token")
@@ -58,40 +111,125 @@ pub async fn start_server(host: &str, port: u16) {
     let query_route = warp::path!("query" / String)
         .and(warp::post())
         .and(with_auth())
+        .and(headers_cloned())
         .and(warp::body::json())
         .and_then(query_handler);
 
     // open a state channel for payg.
     let open_route = warp::path!("open")
+        .and(payg_enabled())
         .and(warp::post())
+        .and(warp::header::optional::<String>(IDEMPOTENCY_KEY))
         .and(warp::body::json())
         .and_then(generate_payg);
 
+    // validate the open state, not open it.
+    let open_validate_route = warp::path!("open" / "validate")
+        .and(payg_enabled())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(validate_payg);
+
     // query with Pay-As-You-Go with state channel
     let payg_route = warp::path!("payg" / String)
+        .and(payg_enabled())
         .and(warp::post())
         .and(with_state())
-        .and(warp::body::json())
         .and_then(payg_handler);
 
+    // query the prepaid credit balance of consumer, by the consumer self.
+    let credit_route = warp::path!("credit" / String)
+        .and(payg_enabled())
+        .and(warp::get())
+        .and(with_credit())
+        .and_then(credit_handler);
+
+    // top up the prepaid credit of consumer, signal from coordinator or operator.
+    let top_up_route = warp::path!("credit")
+        .and(payg_enabled())
+        .and(warp::post())
+        .and(with_admin())
+        .and(warp::body::json())
+        .and_then(top_up_handler);
+
+    // query with prepaid credit.
+    let credit_query_route = warp::path!("credit" / "query" / String)
+        .and(payg_enabled())
+        .and(warp::post())
+        .and(with_credit())
+        .and(warp::body::json())
+        .and_then(credit_query_handler);
+
+    // query the metadata of all hosted projects.
+    let projects_metadata_route = warp::path!("metadata")
+        .and(warp::get())
+        .and_then(projects_metadata_handler);
+
     // query the metadata (indexer, controller, payg-price)
     let metadata_route = warp::path!("metadata" / String)
         .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
         .and_then(metadata_handler);
 
+    // query the latest signed state of channel.
+    let channel_state_route = warp::path!("channel" / String / "state")
+        .and(payg_enabled())
+        .and(warp::get())
+        .and_then(channel_state_handler);
+
+    // query the build info of this proxy.
+    let version_route = warp::path!("version")
+        .and(warp::get())
+        .map(|| reply::json(&build_info()));
+
+    // subscribe the state channel lifecycle events.
+    let events_route = warp::path!("events")
+        .and(payg_enabled())
+        .and(warp::get())
+        .map(|| warp::sse::reply(warp::sse::keep_alive().stream(sse_events())));
+
+    // list all the channels, for operators.
+    let admin_channels_route = warp::path!("admin" / "channels")
+        .and(warp::get())
+        .and(with_admin())
+        .and_then(admin_channels_handler);
+
+    // force close the channel and checkpoint it on chain, for operators.
+    let admin_close_route = warp::path!("admin" / "channels" / String / "close")
+        .and(payg_enabled())
+        .and(warp::post())
+        .and(with_admin())
+        .and_then(admin_close_handler);
+
     // chain the routes
     let routes = token_route
         .or(query_route)
         .or(open_route)
+        .or(open_validate_route)
         .or(payg_route)
+        .or(credit_route)
+        .or(top_up_route)
+        .or(credit_query_route)
+        .or(projects_metadata_route)
         .or(metadata_route)
+        .or(channel_state_route)
+        .or(version_route)
+        .or(events_route)
+        .or(admin_channels_route)
+        .or(admin_close_route)
+        .or(method_fallback(ROUTES.as_slice()))
         .recover(|err| handle_rejection(err, COMMAND.dev()));
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(HEADERS)
+        .allow_headers(COMMAND.passthrough_headers())
+        .expose_headers(vec![REQUEST_ID, ETAG.as_str()])
         .allow_methods(vec!["GET", "POST"]);
 
-    let ip_address: Ipv4Addr = host.parse().unwrap_or(Ipv4Addr::LOCALHOST);
+    let ip_address: IpAddr = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse()
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
     warp::serve(routes.with(cors)).run((ip_address, port)).await;
 }
 
@@ -107,49 +245,322 @@ pub async fn generate_token(payload: auth::Payload) -> WebResult<impl Reply> {
     Ok(reply::json(&QueryToken { token }))
 }
 
-pub async fn query_handler(id: String, deployment_id: String, query: Value) -> WebResult<impl Reply> {
+pub async fn query_handler(
+    id: String,
+    deployment_id: String,
+    scope: Option<String>,
+    headers: HeaderMap,
+    query: Value,
+) -> WebResult<impl Reply> {
+    let query = resolve_query(query).map_err(|e| reject::custom(e))?;
+    let headers = passthrough_headers(&headers);
+    let id = deployment_key(&id).map_err(|e| reject::custom(e))?;
+    let deployment_id = deployment_key(&deployment_id).unwrap_or(deployment_id);
+    let request_id = request_id();
+    let span = info_span!("query", request_id = %request_id);
+    span.in_scope(|| log_query(&query));
+    // the response of scoped deployment must be masked, so it is buffered.
+    if COMMAND.stream_response() && !is_scoped(&id) {
+        let body = query_stream(id.clone(), deployment_id, headers, query)
+            .instrument(span)
+            .await
+            .map_err(|e| query_error(&id, e))?;
+        return Ok(stream_reply(body, request_id));
+    }
+    let result = query_request(id.clone(), deployment_id, scope, headers, query)
+        .instrument(span)
+        .await
+        .map_err(|e| query_error(&id, e))?;
+    if result.get("errors").is_some() {
+        prometheus::push_error_metrics(&id, ErrorKind::GraphQLUser);
+    }
+    Ok(reply::with_header(reply::json(&result), REQUEST_ID, request_id).into_response())
+}
+
+/// Log the query body with the variables redacted if `--log-queries`, otherwise only its hash.
+fn log_query(query: &Value) {
+    if COMMAND.log_queries() {
+        let query = redact_variables(query, COMMAND.redact_variables());
+        debug!(target: "query", "Query: {}", query);
+    } else {
+        debug!(target: "query", "Query hash: {}", query.to_string().hash());
+    }
+}
+
+/// Count the failed query by the kind of fault.
+fn query_error(id: &str, err: Rejection) -> Rejection {
+    if let Some(kind) = ErrorKind::from_rejection(&err) {
+        prometheus::push_error_metrics(id, kind);
+    }
+    err
+}
+
+/// Same as `query_error`, for the errors of payg.
+fn payg_error(id: &str, err: Error) -> Rejection {
+    prometheus::push_error_metrics(id, ErrorKind::from_error(&err));
+    reject::custom(err)
+}
+
+/// The request headers in the passthrough allowlist.
+fn passthrough_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    COMMAND
+        .passthrough_headers()
+        .into_iter()
+        .filter_map(|name| {
+            let value = headers.get(name.as_str())?.to_str().ok()?.to_owned();
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// Check the query, and return the upstream url of project.
+fn check_query(id: &str, deployment_id: &str, query: &Value) -> WebResult<String> {
     if COMMAND.auth() && id != deployment_id {
         return Err(reject::custom(Error::JWTTokenError));
     };
 
-    let query_url = match get_project(&id) {
+    let query_url = match get_project(id) {
         Ok(url) => url,
         Err(e) => return Err(reject::custom(e)),
     };
 
-    prometheus::push_query_metrics(id.to_owned());
+    validate_query(query, &COMMAND.query_limits()).map_err(|e| reject::custom(e))?;
+
+    prometheus::push_query_metrics(id.to_owned(), QueryAuth::Agreement);
+    Ok(query_url)
+}
+
+async fn query_request(
+    id: String,
+    deployment_id: String,
+    scope: Option<String>,
+    headers: Vec<(String, String)>,
+    query: Value,
+) -> WebResult<Value> {
+    let query_url = check_query(&id, &deployment_id, &query)?;
+
+    // the passthrough headers maybe change the response, not cache it.
+    let cacheable = headers.is_empty();
+    if let Some(mut result) = cache::get(&id, &query).await.filter(|_| cacheable) {
+        debug!("Query cache hit");
+        mask_response(&id, &scope, &mut result);
+        return Ok(result);
+    }
 
-    let response = graphql_request(&query_url, &query).await;
+    let response = if cacheable {
+        let body = query.clone();
+        let request = async move { upstream_request_with_headers(&query_url, &body, &[]).await };
+        cache::single_flight(&id, &query, request).await
+    } else {
+        upstream_request_with_headers(&query_url, &query, &headers).await
+    };
     match response {
-        Ok(result) => Ok(reply::json(&result)),
-        Err(e) => Err(reject::custom(e)),
+        Ok(mut result) => {
+            if cacheable && full_errors(&result).is_none() {
+                cache::insert(&id, &query, &result).await;
+            }
+            mask_response(&id, &scope, &mut result);
+            Ok(result)
+        }
+        Err(e) => {
+            warn!("Query failure: {}", e);
+            Err(reject::custom(e))
+        }
+    }
+}
+
+/// Stream the upstream response to client chunk by chunk.
+async fn query_stream(
+    id: String,
+    deployment_id: String,
+    headers: Vec<(String, String)>,
+    query: Value,
+) -> WebResult<Body> {
+    let query_url = check_query(&id, &deployment_id, &query)?;
+    let mut res = upstream_response(&query_url, &query, &headers).await.map_err(|e| {
+        warn!("Query failure: {}", e);
+        reject::custom(e)
+    })?;
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Query stream failure: {}", e);
+                    return sender.abort();
+                }
+            }
+        }
+    });
+
+    Ok(body)
+}
+
+/// The JSON response with streaming body.
+fn stream_reply(body: Body, request_id: String) -> reply::Response {
+    let mut res = reply::Response::new(body);
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID, value);
     }
+    res
 }
 
-pub async fn generate_payg(payload: Value) -> WebResult<impl Reply> {
-    let state = open_state(&payload).await.map_err(|e| reject::custom(e))?;
+pub async fn generate_payg(idempotency_key: Option<String>, payload: Value) -> WebResult<impl Reply> {
+    let state = open_state_idempotent(idempotency_key, &payload)
+        .await
+        .map_err(|e| reject::custom(e))?;
     Ok(reply::json(&state))
 }
 
+pub async fn validate_payg(payload: Value) -> WebResult<impl Reply> {
+    Ok(reply::json(&validate_open(&payload).await))
+}
+
 pub async fn payg_handler(id: String, state: Value, query: Value) -> WebResult<impl Reply> {
-    let (state_data, query_data) = query_state(&id, &state, &query).await?;
-    prometheus::push_query_metrics(id);
-    Ok(reply::json(&json!([query_data, state_data])))
+    let id = deployment_key(&id).map_err(|e| reject::custom(e))?;
+    let request_id = request_id();
+    let span = info_span!("payg", request_id = %request_id);
+    span.in_scope(|| log_query(&query));
+    if COMMAND.stream_response() {
+        let body = query_state_stream(&id, &state, &query)
+            .instrument(span)
+            .await
+            .map_err(|e| payg_error(&id, e))?;
+        prometheus::push_query_metrics(id, QueryAuth::Payg);
+        return Ok(stream_reply(body, request_id));
+    }
+    let (state_data, query_data) = query_state(&id, &state, &query)
+        .instrument(span)
+        .await
+        .map_err(|e| payg_error(&id, e))?;
+    if query_data.get("errors").is_some() {
+        prometheus::push_error_metrics(&id, ErrorKind::GraphQLUser);
+    }
+    prometheus::push_query_metrics(id, QueryAuth::Payg);
+    Ok(reply::with_header(reply::json(&json!([query_data, state_data])), REQUEST_ID, request_id).into_response())
 }
 
-pub async fn metadata_handler(id: String) -> WebResult<impl Reply> {
+pub async fn channel_state_handler(id: String) -> WebResult<impl Reply> {
+    let state = latest_state(&id).await.map_err(|e| reject::custom(e))?;
+    Ok(reply::json(&state))
+}
+
+pub async fn admin_channels_handler() -> WebResult<impl Reply> {
+    Ok(reply::json(&admin::list_channels().await))
+}
+
+pub async fn admin_close_handler(id: String) -> WebResult<impl Reply> {
+    let res = admin::force_close(&id).await.map_err(|e| reject::custom(e))?;
+    Ok(reply::json(&res))
+}
+
+pub async fn credit_handler(consumer: String, signer: Address) -> WebResult<impl Reply> {
+    let consumer: Address = consumer.parse().map_err(|_| reject::custom(Error::InvalidRequest))?;
+    if consumer != signer {
+        return Err(reject::custom(Error::NoPermissionError));
+    }
+    let balance = credit::balance(&consumer).await;
+    Ok(reply::json(&json!({
+        "consumer": format!("{:?}", consumer),
+        "balance": balance.to_string(),
+    })))
+}
+
+pub async fn top_up_handler(payload: Value) -> WebResult<impl Reply> {
+    let res = credit::top_up_signal(&payload).await.map_err(|e| reject::custom(e))?;
+    Ok(reply::json(&res))
+}
+
+pub async fn credit_query_handler(id: String, consumer: Address, query: Value) -> WebResult<impl Reply> {
+    let id = deployment_key(&id).map_err(|e| reject::custom(e))?;
+    let query_url = get_project(&id).map_err(|e| reject::custom(e))?;
+    validate_query(&query, &COMMAND.query_limits()).map_err(|e| reject::custom(e))?;
+
+    let price = U256::from(PRICE);
+    let remaining = credit::debit(&consumer, price).await.map_err(|e| reject::custom(e))?;
+
+    match upstream_request(&query_url, &query).await {
+        Ok(result) if full_errors(&result).is_some() => {
+            // refund the credit when no data at all.
+            credit::top_up(consumer, price).await;
+            let errors = full_errors(&result).unwrap(); // safe unwrap.
+            Err(reject::custom(Error::GraphQLQueryError(errors.to_string())))
+        }
+        Ok(result) => {
+            prometheus::push_query_metrics(id, QueryAuth::Payg);
+            Ok(reply::json(&json!([result, { "credit": remaining.to_string() }])))
+        }
+        Err(e) => {
+            // refund the credit when query failure.
+            credit::top_up(consumer, price).await;
+            Err(reject::custom(e))
+        }
+    }
+}
+
+pub async fn metadata_handler(id: String, if_none_match: Option<String>) -> WebResult<impl Reply> {
+    let id = deployment_key(&id).map_err(|e| reject::custom(e))?;
     let query_url = match get_project(&id) {
         Ok(url) => url,
         Err(e) => return Err(reject::custom(e)),
     };
 
-    // TODO: move to other place
-    let _ = account::fetch_account_metadata().await;
+    let (metadata, etag) = project_metadata(&id, &query_url).await.map_err(|e| reject::custom(e))?;
+    // the consumer polling has the same metadata.
+    let matched = if_none_match
+        .map(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
+        .unwrap_or(false);
+    if matched {
+        let res = reply::with_status(reply::reply(), StatusCode::NOT_MODIFIED);
+        return Ok(reply::with_header(res, ETAG, etag).into_response());
+    }
+    Ok(reply::with_header(reply::json(&metadata), ETAG, etag).into_response())
+}
 
-    let query = json!({ "query": METADATA_QUERY });
-    let response = graphql_request(&query_url, &query).await;
-    match response {
-        Ok(result) => Ok(reply::json(&result)),
-        Err(e) => Err(reject::custom(e)),
+pub async fn projects_metadata_handler() -> WebResult<impl Reply> {
+    Ok(reply::json(&projects_metadata().await))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fallback of the known routes, as the last route of server.
+    fn fallback(payg: bool) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
+        let routes: &'static [(&str, &str)] = Box::leak(known_routes(payg).into_boxed_slice());
+        method_fallback(routes).recover(|err| handle_rejection(err, false))
+    }
+
+    #[tokio::test]
+    async fn disabled_payg_paths_are_not_found() {
+        let enabled = fallback(true);
+        let disabled = fallback(false);
+        for path in [
+            "/open",
+            "/payg/Qm",
+            "/credit",
+            "/credit/0x01",
+            "/admin/channels/0x01/close",
+        ] {
+            let res = warp::test::request().method("PUT").path(path).reply(&enabled).await;
+            assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+            let res = warp::test::request().method("PUT").path(path).reply(&disabled).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/token")
+            .reply(&disabled)
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
     }
 }
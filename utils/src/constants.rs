@@ -22,10 +22,32 @@ pub const KEEP_ALIVE: &str = "Keep-Alive";
 
 pub const AUTHORIZATION: &str = "Authorization";
 
-pub const HEADERS: [&'static str; 5] = [
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+/// Hop-by-hop and auth headers, never passed through to upstream.
+pub const BLOCKED_PASSTHROUGH_HEADERS: [&'static str; 12] = [
+    "authorization",
+    "cookie",
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub const HEADERS: [&'static str; 7] = [
     "content-type",
     "x-apollo-tracing",
     "agent",
     "authorization",
     "user-agent",
+    "idempotency-key",
+    "if-none-match",
 ];
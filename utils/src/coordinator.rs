@@ -0,0 +1,162 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Client of the coordinator service, the queries and mutations are sent with variables.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use web3::types::{Address, U256};
+
+use crate::error::Error;
+use crate::payg::{convert_sign_to_string, OpenState, QueryState};
+use crate::request::graphql_request;
+
+const ALIVE_PROJECTS: &str = "query { getAliveProjects { id queryEndpoint } }";
+
+const CHANNEL_OPEN: &str = "mutation ChannelOpen($id: String!, $indexer: String!, $consumer: String!, \
+    $balance: Float!, $expiration: Float!, $deploymentId: String!, $callback: String!, \
+    $lastIndexerSign: String!, $lastConsumerSign: String!) { \
+    channelOpen(id: $id, indexer: $indexer, consumer: $consumer, balance: $balance, expiration: $expiration, \
+    deploymentId: $deploymentId, callback: $callback, lastIndexerSign: $lastIndexerSign, \
+    lastConsumerSign: $lastConsumerSign) { lastPrice } }";
+
+const CHANNEL_UPDATE: &str = "mutation ChannelUpdate($id: String!, $count: Float!, $isFinal: Boolean!, \
+    $price: Float!, $indexerSign: String!, $consumerSign: String!) { \
+    channelUpdate(id: $id, count: $count, isFinal: $isFinal, price: $price, indexerSign: $indexerSign, \
+    consumerSign: $consumerSign) { id } }";
+
+const PROJECT_PRICE: &str = "query GetProjectPrice($deploymentId: String!) { \
+    getProjectPrice(deploymentId: $deploymentId) { lastPrice } }";
+
+const ADD_INDEXER: &str = "mutation AddIndexer($indexer: String!) { addIndexer(indexer: $indexer) { indexer } }";
+
+const UPDATE_CONTROLLER: &str =
+    "mutation UpdateController($controller: String!) { updateController(controller: $controller) { controller } }";
+
+/// The alive project of coordinator.
+#[derive(Deserialize, Debug)]
+pub struct ProjectItem {
+    pub id: String,
+    #[serde(rename = "queryEndpoint")]
+    pub query_endpoint: String,
+}
+
+/// The coordinator service of the url.
+#[derive(Clone, Debug)]
+pub struct Coordinator {
+    url: String,
+}
+
+impl Coordinator {
+    pub fn new(url: impl Into<String>) -> Self {
+        Coordinator { url: url.into() }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The alive projects (deployment id and query endpoint).
+    pub async fn alive_projects(&self) -> Result<Vec<ProjectItem>, Error> {
+        let data = self.request(ALIVE_PROJECTS, Value::Null).await?;
+        serde_json::from_value(data["getAliveProjects"].clone()).map_err(|_| Error::ServiceException)
+    }
+
+    /// Save the opened channel, return the `lastPrice` of project, None if not set.
+    pub async fn channel_open(&self, state: &OpenState) -> Result<Option<Value>, Error> {
+        let variables = json!({
+            "id": format!("{:#X}", state.channel_id),
+            "indexer": format!("{:?}", state.indexer),
+            "consumer": format!("{:?}", state.consumer),
+            "balance": number(state.amount),
+            "expiration": number(state.expiration),
+            "deploymentId": format!("0x{}", hex::encode(&state.deployment_id)),
+            "callback": format!("0x{}", hex::encode(&state.callback)),
+            "lastIndexerSign": format!("0x{}", convert_sign_to_string(&state.indexer_sign)),
+            "lastConsumerSign": format!("0x{}", convert_sign_to_string(&state.consumer_sign)),
+        });
+        // the fallback price is used if no lastPrice, not failure.
+        let data = self.request_partial(CHANNEL_OPEN, variables).await?;
+        match data.pointer("/channelOpen/lastPrice") {
+            None | Some(Value::Null) => Ok(None),
+            Some(price) => Ok(Some(price.clone())),
+        }
+    }
+
+    /// The `lastPrice` of project, None if not set. Read only, nothing saved.
+    pub async fn project_price(&self, deployment_id: &[u8; 32]) -> Result<Option<Value>, Error> {
+        let variables = json!({ "deploymentId": format!("0x{}", hex::encode(deployment_id)) });
+        let data = self.request_partial(PROJECT_PRICE, variables).await?;
+        match data.pointer("/getProjectPrice/lastPrice") {
+            None | Some(Value::Null) => Ok(None),
+            Some(price) => Ok(Some(price.clone())),
+        }
+    }
+
+    /// Save the latest query state of channel.
+    pub async fn channel_update(&self, state: &QueryState) -> Result<(), Error> {
+        let variables = json!({
+            "id": format!("{:#X}", state.channel_id),
+            "count": number(state.count),
+            "isFinal": state.is_final,
+            "price": number(state.price),
+            "indexerSign": format!("0x{}", convert_sign_to_string(&state.indexer_sign)),
+            "consumerSign": format!("0x{}", convert_sign_to_string(&state.consumer_sign)),
+        });
+        self.request(CHANNEL_UPDATE, variables).await.map(|_| ())
+    }
+
+    /// Add the indexer to coordinator.
+    pub async fn add_indexer(&self, indexer: Address) -> Result<(), Error> {
+        let variables = json!({ "indexer": format!("{:?}", indexer) });
+        self.request(ADD_INDEXER, variables).await.map(|_| ())
+    }
+
+    /// Update the controller secret key (hex) of indexer.
+    pub async fn update_controller(&self, controller: &str) -> Result<(), Error> {
+        let variables = json!({ "controller": format!("0x{}", controller.trim_start_matches("0x")) });
+        self.request(UPDATE_CONTROLLER, variables).await.map(|_| ())
+    }
+
+    /// Send the query with variables, return the `data`, failure if no data at all.
+    async fn request(&self, query: &str, variables: Value) -> Result<Value, Error> {
+        let data = self.request_partial(query, variables).await?;
+        if data.is_null() {
+            return Err(Error::ServiceException);
+        }
+        Ok(data)
+    }
+
+    /// Send the query with variables, return the `data` (maybe null), the `errors` are logged only.
+    async fn request_partial(&self, query: &str, variables: Value) -> Result<Value, Error> {
+        let body = json!({ "query": query, "variables": variables });
+        let mut result = graphql_request(&self.url, &body).await.map_err(|e| {
+            warn!("Coordinator request failure: {}", e);
+            Error::ServiceException
+        })?;
+        if let Some(errors) = result.get("errors") {
+            warn!("Coordinator response errors: {}", errors);
+        }
+        Ok(result.get_mut("data").map(Value::take).unwrap_or(Value::Null))
+    }
+}
+
+/// The number variable, same as the number literal in query.
+fn number(value: U256) -> Value {
+    serde_json::from_str(&value.to_string()).unwrap_or(Value::Null)
+}
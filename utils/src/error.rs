@@ -20,7 +20,12 @@ use serde::Serialize;
 use std::convert::Infallible;
 use std::fmt;
 use thiserror::Error;
-use warp::{http::StatusCode, Rejection, Reply};
+use warp::{
+    http::{header::ALLOW, HeaderValue, Method, StatusCode},
+    path::FullPath,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
 
 // TODO: reorganise the errors
 #[derive(Error, Debug)]
@@ -51,6 +56,55 @@ pub enum Error {
     ServiceException,
     #[error("invalid request")]
     InvalidRequest,
+    #[error("invalid request: {0}")]
+    InvalidParams(String),
+    #[error("insufficient credit")]
+    InsufficientCredit,
+    #[error("invalid final flag of state")]
+    InvalidFinalFlag,
+    #[error("too many aliases in query")]
+    TooManyAliases,
+    #[error("graphql query errors: {0}")]
+    GraphQLQueryError(String),
+    #[error("upstream rejected the query: {0}")]
+    UpstreamBadRequest(String),
+    #[error("upstream service unavailable")]
+    UpstreamUnavailable,
+    #[error("too many concurrent requests")]
+    RateLimited,
+    #[error("state channel not open on chain")]
+    ChannelNotOnChain,
+    #[error("account metadata not available yet")]
+    AccountUnavailable,
+    #[error("state channel exhausted")]
+    ChannelExhausted,
+    #[error("state channel expired")]
+    ChannelExpired,
+    #[error("invalid state count")]
+    InvalidStateCount,
+    #[error("state price is not the channel price")]
+    InvalidStatePrice,
+    #[error("request timeout")]
+    Timeout,
+    #[error("consumer not allowed")]
+    ConsumerNotAllowed,
+    #[error("invalid project price from coordinator")]
+    InvalidProjectPrice,
+    #[error("state channel not found")]
+    ChannelNotFound,
+    #[error("too many open channels of consumer")]
+    TooManyChannels,
+    #[error("state channel closed by indexer")]
+    ChannelClosed,
+    #[error("open with the idempotency key is in progress")]
+    OpenInProgress,
+    #[error("idempotency key used by other request")]
+    IdempotencyKeyReused,
+    #[error("admin token required")]
+    AdminForbidden,
+    /// the path is known but not with this method, carries the allowed methods.
+    #[error("method not allowed")]
+    MethodNotAllowed(String),
 }
 
 #[derive(Serialize, Debug)]
@@ -62,6 +116,7 @@ struct ErrorResponse {
 impl warp::reject::Reject for Error {}
 
 pub async fn handle_rejection(err: Rejection, debug: bool) -> std::result::Result<impl Reply, Infallible> {
+    let mut allow = None;
     let (code, message) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not Found".to_string())
     } else if let Some(e) = err.find::<Error>() {
@@ -71,8 +126,35 @@ pub async fn handle_rejection(err: Rejection, debug: bool) -> std::result::Resul
             Error::JWTTokenError => (StatusCode::UNAUTHORIZED, e.to_string()),
             Error::JWTTokenExpiredError => (StatusCode::UNAUTHORIZED, e.to_string()),
             Error::JWTTokenCreationError => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            Error::InsufficientCredit => (StatusCode::PAYMENT_REQUIRED, e.to_string()),
+            Error::UpstreamUnavailable => (StatusCode::BAD_GATEWAY, e.to_string()),
+            Error::RateLimited => (StatusCode::TOO_MANY_REQUESTS, e.to_string()),
+            Error::AccountUnavailable => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+            Error::ChannelExhausted => (StatusCode::PAYMENT_REQUIRED, e.to_string()),
+            Error::ChannelExpired => (StatusCode::GONE, e.to_string()),
+            Error::InvalidStateCount => (StatusCode::CONFLICT, e.to_string()),
+            Error::Timeout => (StatusCode::GATEWAY_TIMEOUT, e.to_string()),
+            Error::ConsumerNotAllowed => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::InvalidProjectPrice => (StatusCode::BAD_GATEWAY, e.to_string()),
+            Error::ChannelNotFound => (StatusCode::NOT_FOUND, e.to_string()),
+            Error::TooManyChannels => (StatusCode::TOO_MANY_REQUESTS, e.to_string()),
+            Error::ChannelClosed => (StatusCode::GONE, e.to_string()),
+            Error::AdminForbidden => (StatusCode::FORBIDDEN, e.to_string()),
+            Error::OpenInProgress => (StatusCode::CONFLICT, e.to_string()),
+            Error::IdempotencyKeyReused => (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()),
+            Error::MethodNotAllowed(methods) => {
+                allow = Some(methods.clone());
+                (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+            }
             _ => (StatusCode::BAD_REQUEST, e.to_string()),
         }
+    } else if let Some(e) = err.find::<GraphQLServerError>() {
+        match e {
+            GraphQLServerError::BadRequest(_) => (StatusCode::BAD_REQUEST, e.to_string()),
+            GraphQLServerError::QueryError(_) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+            GraphQLServerError::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, e.to_string()),
+            _ => (StatusCode::BAD_GATEWAY, e.to_string()),
+        }
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         (StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed".to_string())
     } else {
@@ -88,13 +170,61 @@ pub async fn handle_rejection(err: Rejection, debug: bool) -> std::result::Resul
         message,
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut res = warp::reply::with_status(json, code).into_response();
+    if let Some(value) = allow.and_then(|a| HeaderValue::from_str(&a).ok()) {
+        res.headers_mut().insert(ALLOW, value);
+    }
+    Ok(res)
 }
 
-#[derive(Debug)]
+/// The last route of the server, `routes` is the known `(path, method)`, `*` matches any segment.
+/// A known path with a wrong method is rejected with 405 and the allowed methods,
+/// a plain `OPTIONS` (not the CORS preflight, which answered by cors filter) is answered with 204.
+pub fn method_fallback(
+    routes: &'static [(&'static str, &'static str)],
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::method())
+        .and_then(move |path: FullPath, method: Method| async move {
+            let segments: Vec<&str> = path.as_str().trim_matches('/').split('/').collect();
+            let mut methods: Vec<&str> = vec![];
+            for (pattern, m) in routes {
+                let parts: Vec<&str> = pattern.split('/').collect();
+                let matched = parts.len() == segments.len()
+                    && parts.iter().zip(segments.iter()).all(|(p, s)| *p == "*" || p == s);
+                if matched && !methods.contains(m) {
+                    methods.push(m);
+                }
+            }
+            // unknown path, or the route of this method rejected for other reason.
+            if methods.is_empty() || methods.contains(&method.as_str()) {
+                return Err(warp::reject::not_found());
+            }
+            methods.push("OPTIONS");
+            let allow = methods.join(", ");
+
+            if method == Method::OPTIONS {
+                let mut res = StatusCode::NO_CONTENT.into_response();
+                if let Ok(value) = HeaderValue::from_str(&allow) {
+                    res.headers_mut().insert(ALLOW, value);
+                }
+                Ok(res)
+            } else {
+                Err(warp::reject::custom(Error::MethodNotAllowed(allow)))
+            }
+        })
+}
+
+#[derive(Debug, Clone)]
 pub enum GraphQLServerError {
     QueryError(String),
     InternalError(String),
+    /// upstream responded 4xx, the query is bad.
+    BadRequest(String),
+    /// upstream responded 5xx, the upstream is fault.
+    UpstreamError(String),
+    /// the deadline of upstream request exceeded.
+    Timeout(String),
 }
 
 impl warp::reject::Reject for GraphQLServerError {}
@@ -108,6 +238,15 @@ impl fmt::Display for GraphQLServerError {
             GraphQLServerError::InternalError(ref e) => {
                 write!(f, "GraphQL server error (internal error): {}", e)
             }
+            GraphQLServerError::BadRequest(ref e) => {
+                write!(f, "GraphQL server error (bad request): {}", e)
+            }
+            GraphQLServerError::UpstreamError(ref e) => {
+                write!(f, "GraphQL server error (upstream error): {}", e)
+            }
+            GraphQLServerError::Timeout(ref e) => {
+                write!(f, "GraphQL server error (timeout): {}", e)
+            }
         }
     }
 }
@@ -121,6 +260,104 @@ impl std::error::Error for GraphQLServerError {
         match *self {
             GraphQLServerError::QueryError(_) => None,
             GraphQLServerError::InternalError(_) => None,
+            GraphQLServerError::BadRequest(_) => None,
+            GraphQLServerError::UpstreamError(_) => None,
+            GraphQLServerError::Timeout(_) => None,
         }
     }
 }
+
+impl GraphQLServerError {
+    /// The transport failure and 5xx of upstream maybe recovered by retry.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            GraphQLServerError::QueryError(_) | GraphQLServerError::UpstreamError(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The status and body of the rejected error.
+    async fn reply(err: Error) -> (StatusCode, serde_json::Value) {
+        let res = handle_rejection(warp::reject::custom(err), false)
+            .await
+            .unwrap()
+            .into_response();
+        let status = res.status();
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn map_errors_to_status() {
+        let table = [
+            (Error::ChannelExhausted, StatusCode::PAYMENT_REQUIRED),
+            (Error::ChannelExpired, StatusCode::GONE),
+            (Error::InvalidStateCount, StatusCode::CONFLICT),
+            (Error::RateLimited, StatusCode::TOO_MANY_REQUESTS),
+            (Error::Timeout, StatusCode::GATEWAY_TIMEOUT),
+            (Error::InsufficientCredit, StatusCode::PAYMENT_REQUIRED),
+            (Error::JWTTokenError, StatusCode::UNAUTHORIZED),
+            (Error::InvalidSignature, StatusCode::BAD_REQUEST),
+        ];
+        for (err, status) in table {
+            let message = err.to_string();
+            let (code, body) = reply(err).await;
+            assert_eq!(code, status, "{}", message);
+            assert_eq!(body["status"], serde_json::json!(status.to_string()));
+            assert_eq!(body["message"], serde_json::json!(message));
+        }
+    }
+
+    #[tokio::test]
+    async fn answer_options_and_wrong_methods() {
+        static ROUTES: [(&str, &str); 2] = [("token", "POST"), ("query/*", "POST")];
+        let token = warp::path!("token").and(warp::post()).map(warp::reply);
+        let routes = token
+            .or(method_fallback(&ROUTES))
+            .recover(|err| handle_rejection(err, false))
+            .with(warp::cors().allow_any_origin().allow_methods(vec!["GET", "POST"]));
+
+        let res = warp::test::request().method("GET").path("/token").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[ALLOW], "POST, OPTIONS");
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/query/Qm")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/token")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers()[ALLOW], "POST, OPTIONS");
+
+        // the CORS preflight is answered by the cors filter.
+        let res = warp::test::request()
+            .method("OPTIONS")
+            .path("/token")
+            .header("origin", "http://example.com")
+            .header("access-control-request-method", "POST")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key("access-control-allow-methods"));
+
+        let res = warp::test::request().method("POST").path("/token").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = warp::test::request()
+            .method("GET")
+            .path("/unknown")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
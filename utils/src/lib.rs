@@ -20,6 +20,7 @@
 extern crate tracing;
 
 pub mod constants;
+pub mod coordinator;
 pub mod eip712;
 pub mod error;
 pub mod payg;
@@ -25,7 +25,8 @@ use libp2p::{
     },
     swarm::{
         dial_opts::{self, DialOpts},
-        NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, OneShotHandler, PollParameters,
+        DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, OneShotHandler, PollParameters,
+        SubstreamProtocol,
     },
 };
 use rand_chacha::{
@@ -36,15 +37,20 @@ use smallvec::SmallVec;
 use std::{
     collections::{
         hash_map::{DefaultHasher, HashMap},
-        VecDeque,
+        HashSet, VecDeque,
     },
+    hash::{Hash, Hasher},
     task::{Context, Poll},
+    time::Instant,
 };
 
 use super::protocol::{GroupAction, GroupActionType, GroupProtocol};
 use super::{GroupConfig, GroupEvent, GroupId, GroupMessage};
 use crate::p2p::primitives::{group_protocol, naive_nat, SubqueryProtocol};
 
+/// Max number of dials triggered by a single group sync.
+const MAX_SYNC_DIALS: usize = 16;
+
 /// Network behaviour that handles the Group system.
 pub struct Group {
     /// Events that need to be yielded to the outside when polling.
@@ -58,33 +64,106 @@ pub struct Group {
     /// List of groups we're join to. Necessary to filter out messages that we receive
     /// erroneously.
     groups: HashMap<GroupId, Vec<PeerId>>,
+    /// Peers being dialed by the group sync, removed when connected or dial failure.
+    dialing: HashSet<PeerId>,
+    /// The consecutive dial failures of peers (count, first failure in window).
+    failures: HashMap<PeerId, (u32, Instant)>,
+    /// Peers gone after too many dial failures, not dialed until they connect again.
+    pruned: HashSet<PeerId>,
     /// We keep track of the messages we received (in the format `hash(source ID, seq_no)`) so that
     /// we don't dispatch the same message twice if we receive it twice on the network.
     received: CuckooFilter<DefaultHasher>,
+    /// The received messages in order (received time, hash of message), to forget the oldest.
+    history: VecDeque<(Instant, u64)>,
 }
 
 impl Group {
     /// Creates a `Group` with the given configuration.
     pub fn new(config: GroupConfig) -> Self {
         Group {
-            config,
             protocol: group_protocol(),
             events: VecDeque::new(),
             peers: HashMap::new(),
             groups: HashMap::new(),
-            received: CuckooFilter::new(),
+            dialing: HashSet::new(),
+            failures: HashMap::new(),
+            pruned: HashSet::new(),
+            received: CuckooFilter::with_capacity(config.history_capacity),
+            history: VecDeque::new(),
+            config,
+        }
+    }
+
+    /// Remember the message, return false if it was received already.
+    fn remember(&mut self, message: &GroupMessage) -> bool {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        let key = hasher.finish();
+
+        self.forget_expired();
+        match self.received.test_and_add(&key) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e @ CuckooError::NotEnoughSpace) => {
+                // Message added, but some other removed.
+                warn!(
+                    "Message was added to 'received' Cuckoofilter but some \
+                     other message was removed as a consequence: {}",
+                    e,
+                );
+            }
+        }
+        self.history.push_back((Instant::now(), key));
+        true
+    }
+
+    /// Forget the messages out of the history window or capacity, oldest first.
+    fn forget_expired(&mut self) {
+        let now = Instant::now();
+        while let Some((time, key)) = self.history.front() {
+            let expired = self
+                .config
+                .history_window
+                .map(|window| now.duration_since(*time) >= window)
+                .unwrap_or(false);
+            if !expired && self.history.len() < self.config.history_capacity {
+                break;
+            }
+            self.received.delete(key);
+            self.history.pop_front();
+        }
+    }
+
+    /// Count the dial failure of peer, prune it if failed too many times in the window.
+    fn dial_failed(&mut self, peer: PeerId) {
+        let now = Instant::now();
+        let entry = self.failures.entry(peer).or_insert((0, now));
+        if now.duration_since(entry.1) > self.config.prune_window {
+            *entry = (0, now);
+        }
+        entry.0 += 1;
+        let failures = entry.0;
+
+        if self.config.prune_failures > 0 && failures >= self.config.prune_failures {
+            debug!("====== GROUP: prune peer {} after {} dial failures", peer, failures);
+            self.failures.remove(&peer);
+            self.peers.remove(&peer);
+            for peers in self.groups.values_mut() {
+                peers.retain(|p| p != &peer);
+            }
+            self.pruned.insert(peer);
         }
     }
 
     /// Add a node to the sharding group.
     pub fn add_node_to_group(&mut self, group: GroupId, peer_id: PeerId) {
         if let Some(peers) = self.groups.get(&group) {
-            if !peers.contains(&peer_id) {
+            if !peers.contains(&peer_id) && !self.pruned.contains(&peer_id) {
                 self.events.push_back(NetworkBehaviourAction::Dial {
                     opts: DialOpts::peer_id(peer_id)
                         .condition(dial_opts::PeerCondition::Disconnected)
                         .build(),
-                    handler: OneShotHandler::default(),
+                    handler: group_handler(&self.config),
                 });
             }
         }
@@ -125,6 +204,14 @@ impl Group {
         true
     }
 
+    /// List the groups we're join to, and the connected peers of them.
+    pub fn groups(&self) -> Vec<(GroupId, Vec<PeerId>)> {
+        self.groups
+            .iter()
+            .map(|(gid, peers)| (gid.clone(), peers.clone()))
+            .collect()
+    }
+
     /// Leave from a group.
     pub fn leave(&mut self, group: GroupId) {
         if let Some(peers) = self.groups.remove(&group) {
@@ -158,15 +245,10 @@ impl Group {
             group: group,
         };
 
+        if self.groups.contains_key(&message.group) {
+            self.remember(&message);
+        }
         if let Some(peers) = self.groups.get(&message.group) {
-            if let Err(e @ CuckooError::NotEnoughSpace) = self.received.add(&message) {
-                warn!(
-                    "Message was added to 'received' Cuckoofilter but some \
-                     other message was removed as a consequence: {}",
-                    e,
-                );
-            }
-
             if self.config.subscribe_local_messages {
                 self.events
                     .push_back(NetworkBehaviourAction::GenerateEvent(GroupEvent::Message(
@@ -194,7 +276,7 @@ impl NetworkBehaviour for Group {
     type OutEvent = GroupEvent;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        OneShotHandler::default()
+        group_handler(&self.config)
     }
 
     fn inject_new_external_addr(&mut self, addr: &Multiaddr) {
@@ -244,6 +326,9 @@ impl NetworkBehaviour for Group {
             }
         }
 
+        self.dialing.remove(id);
+        self.failures.remove(id);
+        self.pruned.remove(id);
         let addr = endpoint.get_remote_address().clone();
         self.peers.insert(*id, (SmallVec::new(), addr));
     }
@@ -270,16 +355,26 @@ impl NetworkBehaviour for Group {
         for (_group, peers) in self.groups.iter_mut() {
             if let Some(pos) = peers.iter().position(|x| x == id) {
                 peers.remove(pos);
+                if self.pruned.contains(id) {
+                    continue;
+                }
                 self.events.push_back(NetworkBehaviourAction::Dial {
                     opts: DialOpts::peer_id(*id)
                         .condition(dial_opts::PeerCondition::Disconnected)
                         .build(),
-                    handler: Default::default(),
+                    handler: group_handler(&self.config),
                 });
             }
         }
     }
 
+    fn inject_dial_failure(&mut self, peer: Option<PeerId>, _: Self::ConnectionHandler, _: &DialError) {
+        if let Some(peer) = peer {
+            self.dialing.remove(&peer);
+            self.dial_failed(peer);
+        }
+    }
+
     fn inject_event(&mut self, peer_id: PeerId, _connection: ConnectionId, event: InnerMessage) {
         debug!("====== GROUP: inject event: {}", peer_id);
         // We ignore successful sends or timeouts.
@@ -353,16 +448,30 @@ impl NetworkBehaviour for Group {
                                 }));
                         }
                     }
-                    GroupActionType::Sync(others) => {
+                    GroupActionType::Sync(mut others) => {
                         debug!("***** Sync: {:?}", others);
+                        // same order for every sync, and every peer only once.
+                        others.sort_by_key(|(peer_id, _)| peer_id.to_bytes());
+                        others.dedup_by_key(|(peer_id, _)| *peer_id);
+
+                        let mut dials = 0;
                         for (peer_id, addr) in others {
-                            if !peers.contains(&peer_id) && peer_id != self.config.local_peer_id {
+                            if dials >= MAX_SYNC_DIALS {
+                                break;
+                            }
+                            if !peers.contains(&peer_id)
+                                && peer_id != self.config.local_peer_id
+                                && !self.peers.contains_key(&peer_id)
+                                && !self.pruned.contains(&peer_id)
+                                && self.dialing.insert(peer_id)
+                            {
+                                dials += 1;
                                 self.events.push_back(NetworkBehaviourAction::Dial {
                                     opts: DialOpts::peer_id(peer_id)
                                         .addresses(vec![addr])
                                         .condition(dial_opts::PeerCondition::Disconnected)
                                         .build(),
-                                    handler: OneShotHandler::default(),
+                                    handler: group_handler(&self.config),
                                 });
                             }
                         }
@@ -379,17 +488,8 @@ impl NetworkBehaviour for Group {
         for message in event.messages {
             if self.groups.contains_key(&message.group) {
                 debug!("====== GROUP: inject event is GroupMessage");
-                match self.received.test_and_add(&message) {
-                    Ok(true) => {}         // Message  was added.
-                    Ok(false) => continue, // Message already existed.
-                    Err(e @ CuckooError::NotEnoughSpace) => {
-                        // Message added, but some other removed.
-                        warn!(
-                            "Message was added to 'received' Cuckoofilter but some \
-                         other message was removed as a consequence: {}",
-                            e,
-                        );
-                    }
+                if !self.remember(&message) {
+                    continue; // Message already existed.
                 }
 
                 let event = GroupEvent::Message(message.clone());
@@ -411,6 +511,14 @@ impl NetworkBehaviour for Group {
     }
 }
 
+/// Build the connection handler with the config of group.
+fn group_handler(config: &GroupConfig) -> OneShotHandler<GroupProtocol, GroupProtocol, InnerMessage> {
+    OneShotHandler::new(
+        SubstreamProtocol::new(GroupProtocol::default(), ()),
+        config.handler_config(),
+    )
+}
+
 /// Transmission between the `OneShotHandler` and the `GroupHandler`.
 #[derive(Debug)]
 pub enum InnerMessage {
@@ -433,3 +541,122 @@ impl From<()> for InnerMessage {
         InnerMessage::Sent
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn addr(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    /// The group behaviour of a new local peer, joined to the group.
+    fn joined(gid: &GroupId, config: GroupConfig) -> Group {
+        let mut group = Group::new(config);
+        group.join(gid.clone());
+        group
+    }
+
+    /// Receive the shared peers of the group from a remote.
+    fn sync(group: &mut Group, gid: &GroupId, others: Vec<(PeerId, Multiaddr)>) {
+        let event = GroupProtocol {
+            actions: vec![GroupAction {
+                group: gid.clone(),
+                action: GroupActionType::Sync(others),
+            }],
+            ..Default::default()
+        };
+        group.inject_event(PeerId::random(), ConnectionId::new(0), InnerMessage::Rx(event));
+    }
+
+    /// Take the queued events, return the number of dials.
+    fn dials(group: &mut Group) -> usize {
+        group
+            .events
+            .drain(..)
+            .filter(|event| matches!(event, NetworkBehaviourAction::Dial { .. }))
+            .count()
+    }
+
+    fn dial_failure(group: &mut Group, peer: PeerId) {
+        let handler = group.new_handler();
+        group.inject_dial_failure(Some(peer), handler, &DialError::NoAddresses);
+    }
+
+    #[test]
+    fn sync_dials_every_peer_once() {
+        let gid = GroupId::new("test");
+        let mut group = joined(&gid, GroupConfig::new(PeerId::random()));
+        let local = group.config.local_peer_id;
+        let connected = PeerId::random();
+        group.peers.insert(connected, (SmallVec::new(), addr(1)));
+
+        // every peer is shared twice, with the local and connected peers.
+        let peers: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        let mut others: Vec<(PeerId, Multiaddr)> = peers.iter().map(|p| (*p, addr(2))).collect();
+        others.extend(peers.iter().map(|p| (*p, addr(3))));
+        others.push((local, addr(4)));
+        others.push((connected, addr(1)));
+
+        sync(&mut group, &gid, others.clone());
+        assert_eq!(dials(&mut group), peers.len());
+        assert_eq!(group.dialing, peers.iter().copied().collect::<HashSet<_>>());
+
+        // the peers being dialed are skipped by the next sync.
+        sync(&mut group, &gid, others.clone());
+        assert_eq!(dials(&mut group), 0);
+
+        // the failed dial is retried by the next sync.
+        dial_failure(&mut group, peers[0]);
+        sync(&mut group, &gid, others);
+        assert_eq!(dials(&mut group), 1);
+    }
+
+    #[test]
+    fn sync_dials_at_most_the_cap() {
+        let gid = GroupId::new("test");
+        let mut group = joined(&gid, GroupConfig::new(PeerId::random()));
+        let others: Vec<(PeerId, Multiaddr)> = (0..MAX_SYNC_DIALS + 4)
+            .map(|i| (PeerId::random(), addr(7000 + i as u16)))
+            .collect();
+
+        sync(&mut group, &gid, others.clone());
+        assert_eq!(dials(&mut group), MAX_SYNC_DIALS);
+        // the rest are dialed by the next sync.
+        sync(&mut group, &gid, others);
+        assert_eq!(dials(&mut group), 4);
+    }
+
+    #[test]
+    fn prune_peer_after_dial_failures() {
+        let gid = GroupId::new("test");
+        let mut config = GroupConfig::new(PeerId::random());
+        config.prune_failures = 3;
+        config.prune_window = Duration::from_secs(1);
+        let mut group = joined(&gid, config);
+        let peer = PeerId::random();
+        group.peers.insert(peer, (SmallVec::new(), addr(1)));
+        group.groups.get_mut(&gid).unwrap().push(peer);
+
+        // the failures out of the window are not counted.
+        dial_failure(&mut group, peer);
+        dial_failure(&mut group, peer);
+        group.failures.get_mut(&peer).unwrap().1 -= Duration::from_secs(2);
+        dial_failure(&mut group, peer);
+        dial_failure(&mut group, peer);
+        assert!(group.groups[&gid].contains(&peer));
+        assert!(!group.pruned.contains(&peer));
+
+        dial_failure(&mut group, peer);
+        assert!(!group.groups[&gid].contains(&peer));
+        assert!(!group.peers.contains_key(&peer));
+        assert!(group.pruned.contains(&peer));
+
+        // the pruned peer is not dialed again, until it connects on its own.
+        group.events.clear();
+        sync(&mut group, &gid, vec![(peer, addr(1))]);
+        group.add_node_to_group(gid.clone(), peer);
+        assert_eq!(dials(&mut group), 0);
+    }
+}
@@ -16,8 +16,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use libp2p::{Multiaddr, PeerId};
+use libp2p::{swarm::OneShotHandlerConfig, Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 mod handler;
 mod protocol;
@@ -78,6 +79,28 @@ pub struct GroupConfig {
     /// `true` if messages published by local node should be propagated as messages received from
     /// the network, `true` by default.
     pub subscribe_local_messages: bool,
+
+    /// Idle time before the group connection is closed, the `OneShotHandler` default if unset.
+    pub keep_alive_timeout: Option<Duration>,
+
+    /// Timeout of opening an outbound substream, the `OneShotHandler` default if unset.
+    pub outbound_substream_timeout: Option<Duration>,
+
+    /// Max number of outbound substreams being negotiated, the `OneShotHandler` default if unset.
+    pub max_dial_negotiated: Option<u32>,
+
+    /// Max number of received messages remembered for dedup, the oldest are forgotten first.
+    pub history_capacity: usize,
+
+    /// The received messages are forgotten after this time, kept until out of capacity if unset.
+    pub history_window: Option<Duration>,
+
+    /// A peer is pruned after this number of consecutive dial failures within `prune_window`,
+    /// and not redialed until it connects again, 0 is never pruned.
+    pub prune_failures: u32,
+
+    /// The window of counting the dial failures of a peer.
+    pub prune_window: Duration,
 }
 
 impl GroupConfig {
@@ -87,7 +110,29 @@ impl GroupConfig {
             local_port: 0,
             external_addr: None,
             subscribe_local_messages: true,
+            keep_alive_timeout: None,
+            outbound_substream_timeout: None,
+            max_dial_negotiated: None,
+            history_capacity: cuckoofilter::DEFAULT_CAPACITY,
+            history_window: None,
+            prune_failures: 5,
+            prune_window: Duration::from_secs(600),
+        }
+    }
+
+    /// The config of group connection handler, unset options keep the defaults.
+    pub fn handler_config(&self) -> OneShotHandlerConfig {
+        let mut config = OneShotHandlerConfig::default();
+        if let Some(timeout) = self.keep_alive_timeout {
+            config.keep_alive_timeout = timeout;
+        }
+        if let Some(timeout) = self.outbound_substream_timeout {
+            config.outbound_substream_timeout = timeout;
+        }
+        if let Some(max) = self.max_dial_negotiated {
+            config.max_dial_negotiated = max;
         }
+        config
     }
 }
 
@@ -27,7 +27,7 @@ use libp2p::{
 };
 use smallvec::SmallVec;
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     fmt, io,
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -60,6 +60,8 @@ pub struct RpcHandler {
     pending_events: VecDeque<RpcHandlerEvent>,
     /// Outbound upgrades waiting to be emitted as an `OutboundSubstreamRequest`.
     outbound: VecDeque<RequestProtocol>,
+    /// Cancelled outbound requests whose substream is in progress, the result is dropped.
+    cancelled: HashSet<RequestId>,
     /// Inbound upgrades waiting for the incoming request.
     inbound: FuturesUnordered<BoxFuture<'static, Result<((RequestId, Request), Sender<Response>), RecvError>>>,
     inbound_request_id: Arc<AtomicU64>,
@@ -78,6 +80,7 @@ impl RpcHandler {
             keep_alive_timeout,
             substream_timeout,
             outbound: VecDeque::new(),
+            cancelled: HashSet::new(),
             inbound: FuturesUnordered::new(),
             pending_events: VecDeque::new(),
             pending_error: None,
@@ -86,6 +89,16 @@ impl RpcHandler {
     }
 }
 
+/// The events sent to the [`RpcHandler`] by the `Rpc` behaviour.
+#[doc(hidden)]
+#[derive(Debug)]
+pub enum RpcHandlerIn {
+    /// Send an outbound request.
+    Request(RequestProtocol),
+    /// Cancel an outbound request, drop the substream result of it.
+    Cancel(RequestId),
+}
+
 /// The events emitted by the [`RpcHandler`].
 #[doc(hidden)]
 pub enum RpcHandlerEvent {
@@ -161,7 +174,7 @@ impl fmt::Debug for RpcHandlerEvent {
 }
 
 impl ConnectionHandler for RpcHandler {
-    type InEvent = RequestProtocol;
+    type InEvent = RpcHandlerIn;
     type OutEvent = RpcHandlerEvent;
     type Error = ConnectionHandlerUpgrErr<io::Error>;
     type InboundProtocol = ResponseProtocol;
@@ -214,19 +227,38 @@ impl ConnectionHandler for RpcHandler {
 
     fn inject_fully_negotiated_outbound(&mut self, response: Response, request_id: RequestId) {
         debug!("------ RPC: inject_fully_negotiated_outbound");
+        if self.cancelled.remove(&request_id) {
+            return;
+        }
         self.pending_events
             .push_back(RpcHandlerEvent::Response { request_id, response });
     }
 
-    fn inject_event(&mut self, request: Self::InEvent) {
+    fn inject_event(&mut self, event: Self::InEvent) {
         debug!("------ RPC: inject_event");
-        self.keep_alive = KeepAlive::Yes;
-        self.outbound.push_back(request);
+        match event {
+            RpcHandlerIn::Request(request) => {
+                self.keep_alive = KeepAlive::Yes;
+                self.outbound.push_back(request);
+            }
+            RpcHandlerIn::Cancel(request_id) => {
+                let len = self.outbound.len();
+                self.outbound.retain(|r| r.request_id != request_id);
+                if self.outbound.len() == len {
+                    // the substream already requested.
+                    self.cancelled.insert(request_id);
+                }
+            }
+        }
     }
 
     fn inject_dial_upgrade_error(&mut self, info: RequestId, error: ConnectionHandlerUpgrErr<io::Error>) {
         debug!("------ RPC: inject_dial_upgrade_error");
+        // the failure of cancelled request is reported by the behaviour already.
+        let cancelled = self.cancelled.remove(&info);
         match error {
+            ConnectionHandlerUpgrErr::Timeout if cancelled => {}
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) if cancelled => {}
             ConnectionHandlerUpgrErr::Timeout => {
                 debug!("------ RPC: inject_dial_upgrade_error timeout");
                 self.pending_events.push_back(RpcHandlerEvent::OutboundTimeout(info));
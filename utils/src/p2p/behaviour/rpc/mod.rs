@@ -40,7 +40,7 @@ mod codec;
 mod handler;
 mod protocol;
 
-use handler::{RpcHandler, RpcHandlerEvent};
+use handler::{RpcHandler, RpcHandlerEvent, RpcHandlerIn};
 use protocol::RequestProtocol;
 
 pub type RequestId = u64;
@@ -68,6 +68,27 @@ pub enum Request {
     Info,
     /// state channel JSON info.
     StateChannel(String),
+    /// close state channel with the final state JSON info.
+    Close(String),
+    /// request the project's metadata, deployment id.
+    Metadata(String),
+    /// request the latest signed state of channel, channel id.
+    ChannelState(String),
+    /// custom request (method, data), handled by the registered handler of `router`.
+    Custom(String, String),
+}
+
+impl Request {
+    /// The unix timestamp deadline of the query, the query after it should be dropped.
+    pub fn deadline(&self) -> Option<i64> {
+        match self {
+            Request::StateChannel(infos) => serde_json::from_str::<serde_json::Value>(infos)
+                .ok()?
+                .get("deadline")?
+                .as_i64(),
+            _ => None,
+        }
+    }
 }
 
 /// Rpc Request type.
@@ -79,6 +100,14 @@ pub enum Response {
     StateChannel(String),
     /// error response.
     Error(String),
+    /// payg query response with the charged price, for the requests with `paidData`.
+    /// prices are decimal strings, `next_price` is the price of the next query.
+    PaidData {
+        data: String,
+        state: String,
+        price: String,
+        next_price: String,
+    },
 }
 
 /// An inbound request or response.
@@ -158,6 +187,8 @@ pub enum OutboundFailure {
     ConnectionClosed,
     /// The remote supports none of the requested protocols.
     UnsupportedProtocols,
+    /// The request was cancelled by the local node, the late response is dropped.
+    Cancelled,
 }
 
 impl fmt::Display for OutboundFailure {
@@ -171,6 +202,7 @@ impl fmt::Display for OutboundFailure {
             OutboundFailure::UnsupportedProtocols => {
                 write!(f, "The remote supports none of the requested protocols")
             }
+            OutboundFailure::Cancelled => write!(f, "The request was cancelled"),
         }
     }
 }
@@ -358,6 +390,46 @@ impl Rpc {
         }
     }
 
+    /// Cancels a pending outbound request to the peer, whether it is waiting
+    /// for a connection or for the response. [`RpcEvent::OutboundFailure`] with
+    /// [`OutboundFailure::Cancelled`] is emitted, and the handler drops the substream result.
+    ///
+    /// Returns `false` if the request is not pending.
+    pub fn cancel_request(&mut self, peer: &PeerId, request_id: RequestId) -> bool {
+        let mut cancelled = false;
+        if let Some(pending) = self.pending_outbound_requests.get_mut(peer) {
+            if let Some(p) = pending.iter().position(|r| r.request_id == request_id) {
+                pending.remove(p);
+                cancelled = true;
+            }
+            if pending.is_empty() {
+                self.pending_outbound_requests.remove(peer);
+            }
+        }
+        if let Some(connections) = self.connected.get_mut(peer) {
+            for conn in connections.iter_mut() {
+                if conn.pending_inbound_responses.remove(&request_id) {
+                    self.pending_events.push_back(NetworkBehaviourAction::NotifyHandler {
+                        peer_id: *peer,
+                        handler: NotifyHandler::One(conn.id),
+                        event: RpcHandlerIn::Cancel(request_id),
+                    });
+                    cancelled = true;
+                }
+            }
+        }
+
+        if cancelled {
+            self.pending_events
+                .push_back(NetworkBehaviourAction::GenerateEvent(RpcEvent::OutboundFailure {
+                    peer: *peer,
+                    request_id,
+                    error: OutboundFailure::Cancelled,
+                }));
+        }
+        cancelled
+    }
+
     /// Adds a known address for a peer that can be used for
     /// dialing attempts by the `Swarm`, i.e. is returned
     /// by [`NetworkBehaviour::addresses_of_peer`].
@@ -388,6 +460,15 @@ impl Rpc {
         }
     }
 
+    /// Checks whether there are requests to the peer waiting for a connection
+    /// to be established.
+    pub fn is_pending(&self, peer: &PeerId) -> bool {
+        self.pending_outbound_requests
+            .get(peer)
+            .map(|rps| !rps.is_empty())
+            .unwrap_or(false)
+    }
+
     /// Checks whether an outbound request to the peer with the provided
     /// [`PeerId`] initiated by [`Rpc::send_request`] is still
     /// pending, i.e. waiting for a response.
@@ -440,7 +521,7 @@ impl Rpc {
             self.pending_events.push_back(NetworkBehaviourAction::NotifyHandler {
                 peer_id: *peer,
                 handler: NotifyHandler::One(conn.id),
-                event: request,
+                event: RpcHandlerIn::Request(request),
             });
             None
         } else {
@@ -634,8 +715,10 @@ impl NetworkBehaviour for Rpc {
         debug!("------ RPC: inject event: {}", peer);
         match event {
             RpcHandlerEvent::Response { request_id, response } => {
-                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
-                debug_assert!(removed, "Expect request_id to be pending before receiving response.",);
+                if !self.remove_pending_inbound_response(&peer, connection, &request_id) {
+                    // the request is cancelled, the failure is emitted already.
+                    return;
+                }
 
                 let message = RpcMessage::Response { request_id, response };
                 self.pending_events
@@ -696,8 +779,9 @@ impl NetworkBehaviour for Rpc {
                     }));
             }
             RpcHandlerEvent::OutboundTimeout(request_id) => {
-                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
-                debug_assert!(removed, "Expect request_id to be pending before request times out.");
+                if !self.remove_pending_inbound_response(&peer, connection, &request_id) {
+                    return;
+                }
 
                 self.pending_events
                     .push_back(NetworkBehaviourAction::GenerateEvent(RpcEvent::OutboundFailure {
@@ -721,8 +805,9 @@ impl NetworkBehaviour for Rpc {
                     }));
             }
             RpcHandlerEvent::OutboundUnsupportedProtocols(request_id) => {
-                let removed = self.remove_pending_inbound_response(&peer, connection, &request_id);
-                debug_assert!(removed, "Expect request_id to be pending before failing to connect.",);
+                if !self.remove_pending_inbound_response(&peer, connection, &request_id) {
+                    return;
+                }
 
                 self.pending_events
                     .push_back(NetworkBehaviourAction::GenerateEvent(RpcEvent::OutboundFailure {
@@ -789,3 +874,130 @@ impl Connection {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade, Transport},
+        identity::Keypair,
+        plaintext::PlainText2Config,
+        swarm::{Swarm, SwarmBuilder, SwarmEvent},
+        yamux::YamuxConfig,
+    };
+    use tokio::time::timeout;
+
+    /// The swarm of rpc behaviour on the in-memory transport.
+    fn swarm() -> Swarm<Rpc> {
+        let key = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(key.public());
+        let transport = MemoryTransport::default()
+            .upgrade(upgrade::Version::V1)
+            .authenticate(PlainText2Config {
+                local_public_key: key.public(),
+            })
+            .multiplex(YamuxConfig::default())
+            .boxed();
+        SwarmBuilder::new(transport, Rpc::new(RpcConfig::default()), peer_id)
+            .executor(Box::new(|fut| {
+                tokio::spawn(fut);
+            }))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn request_and_response() {
+        let (mut server, mut client) = (swarm(), swarm());
+        let (server_id, client_id) = (*server.local_peer_id(), *client.local_peer_id());
+        server.listen_on("/memory/0".parse().unwrap()).unwrap();
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = server.select_next_some().await {
+                break address;
+            }
+        };
+
+        client.behaviour_mut().add_address(&server_id, addr);
+        let request_id = client
+            .behaviour_mut()
+            .request(server_id, Request::StateChannel("{}".to_owned()));
+
+        // until the client gets the response and the server knows it sent.
+        let (mut response, mut sent) = (None, false);
+        timeout(Duration::from_secs(10), async {
+            while response.is_none() || !sent {
+                tokio::select! {
+                    event = server.select_next_some() => match event {
+                        SwarmEvent::Behaviour(RpcEvent::Message {
+                            peer,
+                            message: RpcMessage::Request { request_id, request },
+                        }) => {
+                            assert_eq!(peer, client_id);
+                            assert!(matches!(request, Request::StateChannel(_)));
+                            let res = Response::StateChannel("ok".to_owned());
+                            server.behaviour_mut().response(request_id, res).unwrap();
+                        }
+                        SwarmEvent::Behaviour(RpcEvent::ResponseSent { .. }) => sent = true,
+                        SwarmEvent::Behaviour(event) => panic!("unexpected server event: {:?}", event),
+                        _ => {}
+                    },
+                    event = client.select_next_some() => match event {
+                        SwarmEvent::Behaviour(RpcEvent::Message {
+                            peer,
+                            message: RpcMessage::Response { request_id: id, response: res },
+                        }) => {
+                            assert_eq!((peer, id), (server_id, request_id));
+                            response = Some(res);
+                        }
+                        SwarmEvent::Behaviour(event) => panic!("unexpected client event: {:?}", event),
+                        _ => {}
+                    },
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(response, Some(Response::StateChannel(data)) if data == "ok"));
+
+        // nothing left pending on both sides.
+        let (client, server) = (client.behaviour(), server.behaviour());
+        assert!(client.pending_outbound_requests.is_empty());
+        assert!(client.connected[&server_id]
+            .iter()
+            .all(|c| c.pending_inbound_responses.is_empty() && c.pending_outbound_responses.is_empty()));
+        assert!(server.waiting_requests.is_empty());
+        assert!(server.connected[&client_id]
+            .iter()
+            .all(|c| c.pending_inbound_responses.is_empty() && c.pending_outbound_responses.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn fail_the_request_of_unreachable_peer() {
+        let mut client = swarm();
+        let peer = PeerId::random();
+        // nothing listens on the address.
+        client
+            .behaviour_mut()
+            .add_address(&peer, "/memory/4242424242".parse().unwrap());
+        let request_id = client.behaviour_mut().request(peer, Request::Info);
+        assert!(client.behaviour().is_pending(&peer));
+
+        let failure = timeout(Duration::from_secs(10), async {
+            loop {
+                if let SwarmEvent::Behaviour(RpcEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                }) = client.select_next_some().await
+                {
+                    return (peer, request_id, error);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(failure, (peer, request_id, OutboundFailure::DialFailure));
+        assert!(!client.behaviour().is_pending(&peer));
+        assert!(client.behaviour().pending_outbound_requests.is_empty());
+    }
+}
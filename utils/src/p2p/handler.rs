@@ -16,6 +16,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::prelude::Utc;
 use std::sync::Arc;
 
 use super::behaviour::{
@@ -24,6 +25,10 @@ use super::behaviour::{
 };
 use super::rpc::helper::{json, RpcError, RpcHandler, RpcParam};
 use super::server::Event;
+use crate::tools::request_id;
+
+/// Default seconds from now to the deadline of query, same as the request timeout.
+const QUERY_DEADLINE: i64 = 10;
 
 pub struct State;
 
@@ -44,6 +49,43 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
         Ok(vec![Event::Connect(addr), Event::Rpc(Default::default())])
     });
 
+    rpc_handler.add_method(
+        "connect-persistent",
+        |params: Vec<RpcParam>, _state: Arc<State>| async move {
+            if params.len() != 1 {
+                return Err(RpcError::ParseError);
+            }
+            let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+            let addr = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+
+            Ok(vec![Event::ConnectPersistent(addr), Event::Rpc(Default::default())])
+        },
+    );
+
+    rpc_handler.add_method("disconnect", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 1 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+
+        Ok(vec![Event::Disconnect(pid), Event::Rpc(Default::default())])
+    });
+
+    rpc_handler.add_method("peer-status", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 1 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+
+        Ok(vec![Event::PeerStatus(pid)])
+    });
+
+    rpc_handler.add_method("ping-status", |_params: Vec<RpcParam>, _state: Arc<State>| async move {
+        Ok(vec![Event::PingStatus])
+    });
+
     rpc_handler.add_method(
         "state-channel",
         |params: Vec<RpcParam>, _state: Arc<State>| async move {
@@ -63,8 +105,67 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
         },
     );
 
+    rpc_handler.add_method(
+        "state-channel-close",
+        |params: Vec<RpcParam>, _state: Arc<State>| async move {
+            if params.len() != 2 {
+                return Err(RpcError::ParseError);
+            }
+            let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+            let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+            let sign = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
+
+            Ok(vec![Event::RequestSync(pid, Request::Close(sign))])
+        },
+    );
+
+    rpc_handler.add_method("metadata", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 2 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+        let deployment = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
+
+        Ok(vec![Event::RequestSync(pid, Request::Metadata(deployment))])
+    });
+
+    rpc_handler.add_method("get-state", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 2 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+        let channel = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
+
+        Ok(vec![Event::RequestSync(pid, Request::ChannelState(channel))])
+    });
+
+    rpc_handler.add_method("custom", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 3 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+        let method = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
+        let data = params[2].as_str().ok_or(RpcError::ParseError)?.to_owned();
+
+        Ok(vec![Event::RequestSync(pid, Request::Custom(method, data))])
+    });
+
+    rpc_handler.add_method("cancel", |params: Vec<RpcParam>, _state: Arc<State>| async move {
+        if params.len() != 2 {
+            return Err(RpcError::ParseError);
+        }
+        let s = params[0].as_str().ok_or(RpcError::ParseError)?;
+        let pid = s.parse().map_err(|_e| RpcError::InvalidRequest)?;
+        let rid = params[1].as_u64().ok_or(RpcError::ParseError)?;
+
+        Ok(vec![Event::Cancel(pid, rid)])
+    });
+
     rpc_handler.add_method("payg", |params: Vec<RpcParam>, _state: Arc<State>| async move {
-        if params.len() != 4 {
+        if params.len() != 4 && params.len() != 5 {
             return Err(RpcError::ParseError);
         }
         let s = params[0].as_str().ok_or(RpcError::ParseError)?;
@@ -72,11 +173,18 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
         let project = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
         let query = params[2].as_str().ok_or(RpcError::ParseError)?.to_owned();
         let sign = params[3].as_str().ok_or(RpcError::ParseError)?.to_owned();
+        let deadline = match params.get(4) {
+            Some(deadline) => deadline.as_i64().ok_or(RpcError::ParseError)?,
+            None => Utc::now().timestamp() + QUERY_DEADLINE,
+        };
         let query = serde_json::to_string(&json!({
             "method": "query",
             "project": project,
             "query": query,
             "state": sign,
+            "requestId": request_id(),
+            "deadline": deadline,
+            "paidData": true,
         }))
         .unwrap();
 
@@ -84,7 +192,7 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
     });
 
     rpc_handler.add_method("payg-sync", |params: Vec<RpcParam>, _state: Arc<State>| async move {
-        if params.len() != 4 {
+        if params.len() != 4 && params.len() != 5 {
             return Err(RpcError::ParseError);
         }
         let s = params[0].as_str().ok_or(RpcError::ParseError)?;
@@ -92,11 +200,18 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
         let project = params[1].as_str().ok_or(RpcError::ParseError)?.to_owned();
         let query = params[2].as_str().ok_or(RpcError::ParseError)?.to_owned();
         let sign = params[3].as_str().ok_or(RpcError::ParseError)?.to_owned();
+        let deadline = match params.get(4) {
+            Some(deadline) => deadline.as_i64().ok_or(RpcError::ParseError)?,
+            None => Utc::now().timestamp() + QUERY_DEADLINE,
+        };
         let query = serde_json::to_string(&json!({
             "method": "query",
             "project": project,
             "query": query,
             "state": sign,
+            "requestId": request_id(),
+            "deadline": deadline,
+            "paidData": true,
         }))
         .unwrap();
 
@@ -140,6 +255,10 @@ pub fn init_rpc_handler() -> RpcHandler<State> {
         ])
     });
 
+    rpc_handler.add_method("group-list", |_params: Vec<RpcParam>, _state: Arc<State>| async move {
+        Ok(vec![Event::GroupList])
+    });
+
     rpc_handler.add_method(
         "group-broadcast",
         |params: Vec<RpcParam>, _state: Arc<State>| async move {
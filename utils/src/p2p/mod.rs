@@ -19,6 +19,7 @@
 pub mod behaviour;
 pub mod handler;
 pub mod primitives;
+pub mod router;
 pub mod rpc;
 pub mod server;
 
@@ -27,10 +28,71 @@ pub use libp2p; // re-export
 pub use behaviour::rpc::{Request, Response};
 
 use async_trait::async_trait;
+use behaviour::group::GroupId;
+use libp2p::{identity::Keypair, PeerId};
+use std::path::Path;
+use std::time::Duration;
+
+/// Env var of the hex encoded p2p identity key, preferred over the key file.
+pub const P2P_KEY_ENV: &str = "P2P_KEY";
+
+/// Default max number of concurrent inbound requests per peer.
+pub const DEFAULT_MAX_INFLIGHT: usize = 8;
+
+/// Default seconds an inbound request is handled in, answered with error after it.
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+
+/// Default capacity of the JSON-RPC message queues.
+pub const DEFAULT_RPC_CAPACITY: usize = 128;
 
 #[async_trait]
 pub trait P2pHandler {
     async fn request(req: Request) -> Response;
 
     async fn event() {}
+
+    /// Max number of concurrent inbound requests per peer.
+    fn max_inflight() -> usize {
+        DEFAULT_MAX_INFLIGHT
+    }
+
+    /// Max seconds of handling an inbound request, the request's own deadline may be earlier.
+    fn request_timeout() -> u64 {
+        DEFAULT_REQUEST_TIMEOUT
+    }
+
+    /// Capacity of the JSON-RPC message queues, the ws pushes over it are dropped
+    /// and the requests over it are answered busy.
+    fn rpc_capacity() -> usize {
+        DEFAULT_RPC_CAPACITY
+    }
+
+    /// A JSON-RPC message dropped for the full queue, `kind` is `ws` or `busy`.
+    fn rpc_dropped(_kind: &str) {}
+
+    /// The ping rtt of the peer measured, none if the peer disconnected.
+    fn ping_changed(_peer: &PeerId, _rtt: Option<Duration>) {}
+
+    /// The number of connected peers changed.
+    fn peers_changed(_count: usize) {}
+
+    /// The number of peers in a joined group changed.
+    fn group_changed(_group: &GroupId, _size: usize) {}
+}
+
+/// Load the p2p identity key from env or the key file, generate and save it if missing.
+pub async fn load_key(path: &Path) -> Keypair {
+    if let Ok(hex_key) = std::env::var(P2P_KEY_ENV) {
+        let key_bytes = hex::decode(hex_key.trim_start_matches("0x")).expect("invalid P2P_KEY hex");
+        return Keypair::from_protobuf_encoding(&key_bytes).expect("invalid P2P_KEY");
+    }
+
+    if path.exists() {
+        let key_bytes = tokio::fs::read(path).await.unwrap_or(vec![]); // safe.
+        Keypair::from_protobuf_encoding(&key_bytes).unwrap()
+    } else {
+        let key = Keypair::generate_ed25519();
+        let _ = tokio::fs::write(path, key.to_protobuf_encoding().unwrap()).await;
+        key
+    }
 }
@@ -0,0 +1,56 @@
+// This file is part of SubQuery.
+
+// Copyright (C) 2020-2022 SubQuery Pte Ltd authors & contributors
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+//! Registry of custom P2P request handlers, keyed by the method of `Request::Custom`.
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use super::Response;
+
+pub trait P2pFn: Send + Sync + 'static {
+    fn call(&self, data: String) -> BoxFuture<'static, Response>;
+}
+
+impl<F, Fut> P2pFn for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn call(&self, data: String) -> BoxFuture<'static, Response> {
+        Box::pin((self)(data))
+    }
+}
+
+static METHODS: Lazy<RwLock<HashMap<String, Arc<dyn P2pFn>>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Register the handler of custom request method, replace the old one if exists.
+pub fn add_method(method: &str, f: impl P2pFn) {
+    METHODS.write().unwrap().insert(method.to_owned(), Arc::new(f)); // safe, no panic when locked.
+}
+
+/// Dispatch the custom request to the registered handler.
+pub async fn dispatch(method: &str, data: String) -> Response {
+    let f = METHODS.read().unwrap().get(method).cloned(); // safe, no panic when locked.
+    match f {
+        Some(f) => f.call(data).await,
+        None => Response::Error(format!("method {} not found", method)),
+    }
+}
@@ -35,6 +35,8 @@ pub enum RpcError {
     InvalidVersion,
     InvalidResponse,
     MethodNotFound(String),
+    /// the request queue is full, retry later.
+    Busy,
     Custom(String),
 }
 
@@ -98,6 +100,14 @@ impl RpcError {
                     "message": "Invalid Response"
                 }
             }),
+            RpcError::Busy => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {
+                    "code": -32000,
+                    "message": "Server busy"
+                }
+            }),
             RpcError::Custom(m) => json!({
                 "jsonrpc": "2.0",
                 "id": id,
@@ -23,19 +23,22 @@ use rand_chacha::{
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     fs,
     io::{AsyncReadExt, AsyncWriteExt, Result},
     net::{TcpListener, TcpStream},
-    sync::mpsc::Sender,
+    sync::mpsc::{error::TrySendError, Sender},
     sync::RwLock,
+    time::timeout,
 };
 
-use super::helper::parse_jsonrpc;
-use super::{rpc_inner_channel, RpcInnerMessage};
+use super::helper::{parse_jsonrpc, RpcError};
+use super::{authorized, dropped, rpc_inner_channel, RpcInnerMessage, DROPPED_BUSY};
 
 pub(super) async fn http_listen(
     index: Option<PathBuf>,
+    token: Option<String>,
     send: Sender<RpcInnerMessage>,
     listener: TcpListener,
 ) -> Result<()> {
@@ -47,56 +50,169 @@ pub(super) async fn http_listen(
     let homelink = Arc::new(RwLock::new(homepage));
 
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(http_connection(homelink.clone(), send.clone(), stream, addr));
+        tokio::spawn(http_connection(
+            homelink.clone(),
+            token.clone(),
+            send.clone(),
+            stream,
+            addr,
+        ));
     }
 
     Ok(())
 }
 
+/// Seconds to receive the whole request, the slow clients are dropped.
+const READ_TIMEOUT: u64 = 30;
+
+/// Max bytes of the request, also the cap of body without Content-Length.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Response headers after the status line.
+const RESPONSE_HEADERS: &str =
+    "\r\nAccess-Control-Allow-Origin:*;\r\nContent-Type:application/json;charset=UTF-8\r\n\r\n";
+
 enum HTTP {
-    Ok(usize),
-    NeedMore(usize, usize),
+    /// the headers are complete, (headers length, body length),
+    /// the body without Content-Length is read until the connection closed.
+    Ok(usize, Option<usize>),
+    /// the headers are partial, need more.
+    Partial,
 }
 
-fn parse_req<'a>(src: &[u8]) -> std::result::Result<HTTP, &'a str> {
+/// The rejected request, response with the status and JSON-RPC error.
+enum Reject {
+    BadRequest(&'static str),
+    MethodNotAllowed,
+    Unauthorized,
+    LengthRequired,
+    TooLarge,
+    Timeout,
+}
+
+impl Reject {
+    fn status(&self) -> &'static str {
+        match self {
+            Reject::BadRequest(_) => "400 Bad Request",
+            Reject::MethodNotAllowed => "405 Method Not Allowed",
+            Reject::Unauthorized => "401 Unauthorized",
+            Reject::LengthRequired => "411 Length Required",
+            Reject::TooLarge => "413 Payload Too Large",
+            Reject::Timeout => "408 Request Timeout",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Reject::BadRequest(msg) => *msg,
+            Reject::MethodNotAllowed => "Only POST is allowed",
+            Reject::Unauthorized => "Invalid or missing bearer token",
+            Reject::LengthRequired => "Chunked transfer-encoding is not supported, use Content-Length",
+            Reject::TooLarge => "Request is too large",
+            Reject::Timeout => "Request timeout",
+        }
+    }
+}
+
+fn parse_req(src: &[u8], token: Option<&str>) -> std::result::Result<HTTP, Reject> {
     let mut req_parsed_headers = [httparse::EMPTY_HEADER; 16];
     let mut req = httparse::Request::new(&mut req_parsed_headers);
-    let status = req.parse(&src).map_err(|_| "HTTP parse error")?;
+    let amt = match req.parse(&src).map_err(|_| Reject::BadRequest("HTTP parse error"))? {
+        httparse::Status::Complete(amt) => amt,
+        httparse::Status::Partial => return Ok(HTTP::Partial),
+    };
+
+    if req.method != Some("POST") {
+        return Err(Reject::MethodNotAllowed);
+    }
 
-    let content_length_headers: Vec<httparse::Header> = req
+    let authorization = req
         .headers
         .iter()
-        .filter(|header| header.name.to_ascii_lowercase() == "content-length")
-        .cloned()
-        .collect();
-
-    if content_length_headers.len() != 1 {
-        return Err("HTTP header is invalid");
+        .find(|header| header.name.eq_ignore_ascii_case("authorization"))
+        .map(|header| String::from_utf8_lossy(header.value));
+    if !authorized(token, authorization.as_deref()) {
+        return Err(Reject::Unauthorized);
     }
 
-    let length_bytes = content_length_headers.first().unwrap().value;
-    let mut length_string = String::new();
+    let mut length = None;
+    for header in req.headers.iter() {
+        if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            if String::from_utf8_lossy(header.value)
+                .to_ascii_lowercase()
+                .contains("chunked")
+            {
+                return Err(Reject::LengthRequired);
+            }
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            if length.is_some() {
+                return Err(Reject::BadRequest("HTTP header is invalid"));
+            }
+            let value = String::from_utf8_lossy(header.value);
+            let len = value
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| Reject::BadRequest("HTTP length is invalid"))?;
+            length = Some(len);
+        }
+    }
 
-    for b in length_bytes {
-        length_string.push(*b as char);
+    if length.unwrap_or(0) > MAX_REQUEST_SIZE {
+        return Err(Reject::TooLarge);
     }
 
-    let length = length_string.parse::<usize>().map_err(|_| "HTTP length is invalid")?;
+    Ok(HTTP::Ok(amt, length))
+}
 
-    let amt = match status {
-        httparse::Status::Complete(amt) => amt,
-        httparse::Status::Partial => return Err("HTTP parse error"),
+/// Read the request body, until the Content-Length or the connection closed.
+async fn read_req(stream: &mut TcpStream, token: Option<&str>) -> std::result::Result<Vec<u8>, Reject> {
+    let mut buf = vec![];
+    let mut tmp = vec![0u8; 1024];
+
+    let (amt, length) = loop {
+        let n = stream
+            .read(&mut tmp)
+            .await
+            .map_err(|_| Reject::BadRequest("HTTP read error"))?;
+        buf.extend(&tmp[..n]);
+        match parse_req(&buf, token)? {
+            HTTP::Ok(amt, length) => break (amt, length),
+            HTTP::Partial if n == 0 => return Err(Reject::BadRequest("HTTP parse error")),
+            HTTP::Partial if buf.len() > MAX_REQUEST_SIZE => return Err(Reject::TooLarge),
+            HTTP::Partial => {}
+        }
     };
 
-    if src[amt..].len() >= length {
-        return Ok(HTTP::Ok(amt));
+    loop {
+        let body_len = buf.len() - amt;
+        match length {
+            Some(len) if body_len >= len => {
+                buf.truncate(amt + len);
+                break;
+            }
+            None if body_len > MAX_REQUEST_SIZE => return Err(Reject::TooLarge),
+            _ => {}
+        }
+
+        let n = stream
+            .read(&mut tmp)
+            .await
+            .map_err(|_| Reject::BadRequest("HTTP read error"))?;
+        if n == 0 {
+            if length.is_some() {
+                return Err(Reject::BadRequest("HTTP body is incomplete"));
+            }
+            break;
+        }
+        buf.extend(&tmp[..n]);
     }
 
-    Ok(HTTP::NeedMore(amt, length))
+    Ok(buf.split_off(amt))
 }
 
 async fn http_connection(
     _homelink: Arc<RwLock<String>>,
+    token: Option<String>,
     send: Sender<RpcInnerMessage>,
     mut stream: TcpStream,
     addr: SocketAddr,
@@ -104,46 +220,51 @@ async fn http_connection(
     debug!("DEBUG: HTTP connection established: {}", addr);
     let mut rng = ChaChaRng::from_entropy();
     let id: u64 = rng.next_u64();
-    let (s_send, mut s_recv) = rpc_inner_channel();
+    // only one response of the request.
+    let (s_send, mut s_recv) = rpc_inner_channel(1);
 
-    let mut buf = vec![];
-
-    // TODO add timeout
-    let mut tmp_buf = vec![0u8; 1024];
-    let n = stream.read(&mut tmp_buf).await?;
-    let body = match parse_req(&tmp_buf[..n]) {
-        Ok(HTTP::NeedMore(amt, len)) => {
-            buf.extend(&tmp_buf[amt..n]);
-            loop {
-                let mut tmp = vec![0u8; 1024];
-                let n = stream.read(&mut tmp).await?;
-                buf.extend(&tmp[..n]);
-                if buf.len() >= len {
-                    break;
-                }
-            }
-            &buf[..]
-        }
-        Ok(HTTP::Ok(amt)) => &tmp_buf[amt..n],
-        Err(e) => {
-            info!("TDN: HTTP JSONRPC parse error: {}", e);
+    let read = timeout(
+        Duration::from_secs(READ_TIMEOUT),
+        read_req(&mut stream, token.as_deref()),
+    )
+    .await;
+    let body = match read.unwrap_or(Err(Reject::Timeout)) {
+        Ok(body) => body,
+        Err(reject) => {
+            info!("HTTP JSONRPC request rejected: {}", reject.message());
+            let err = RpcError::Custom(reject.message().to_owned()).json(0);
+            stream
+                .write_all(format!("HTTP/1.1 {}{}{}", reject.status(), RESPONSE_HEADERS, err).as_bytes())
+                .await?;
+            let _ = stream.flush().await;
+            stream.shutdown().await?;
             return Ok(());
         }
     };
 
-    let msg = String::from_utf8_lossy(body);
-    let res =
-        "HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin:*;\r\nContent-Type:application/json;charset=UTF-8\r\n\r\n";
+    let msg = String::from_utf8_lossy(&body);
+    let res = format!("HTTP/1.1 200 OK{}", RESPONSE_HEADERS);
 
     match parse_jsonrpc((*msg).to_string()) {
         Ok(rpc_param) => {
-            send.send(RpcInnerMessage::Request(id, rpc_param, Some(s_send)))
-                .await
-                .expect("Http to Rpc channel closed");
+            let rpc_id = rpc_param["id"].as_u64().unwrap_or(0);
+            match send.try_send(RpcInnerMessage::Request(id, rpc_param, Some(s_send))) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    dropped(DROPPED_BUSY);
+                    stream
+                        .write_all(format!("{}{}", res, RpcError::Busy.json(rpc_id).to_string()).as_bytes())
+                        .await?;
+                    let _ = stream.flush().await;
+                    stream.shutdown().await?;
+                    return Ok(());
+                }
+                Err(TrySendError::Closed(_)) => panic!("Http to Rpc channel closed"),
+            }
         }
         Err((err, id)) => {
             stream
-                .write(format!("{}{}", res, err.json(id).to_string()).as_bytes())
+                .write_all(format!("{}{}", res, err.json(id).to_string()).as_bytes())
                 .await?;
             let _ = stream.flush().await;
             stream.shutdown().await?;
@@ -155,7 +276,9 @@ async fn http_connection(
             RpcInnerMessage::Response(param) => param,
             _ => Default::default(),
         };
-        stream.write(format!("{}{}", res, param.to_string()).as_bytes()).await?;
+        stream
+            .write_all(format!("{}{}", res, param.to_string()).as_bytes())
+            .await?;
         let _ = stream.flush().await;
         stream.shutdown().await?;
         break;
@@ -16,40 +16,113 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::{HashMap, HashSet};
 use std::io::Result;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use tokio::{
     net::TcpListener,
     select,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+    },
 };
 
 pub mod helper;
 mod http;
 mod ws;
 
-use helper::RpcParam;
+use helper::{rpc_response, RpcError, RpcParam};
+
+/// The ws method to subscribe the state updates of channel.
+const SUBSCRIBE_CHANNEL: &str = "subscribe-channel";
+
+/// The state updates of channels, channel id => payload.
+static CHANNEL_UPDATES: Lazy<broadcast::Sender<(String, RpcParam)>> = Lazy::new(|| broadcast::channel(128).0);
+
+/// Called when a message dropped for the full queue, with the kind `ws` or `busy`.
+static ON_DROPPED: OnceCell<fn(&str)> = OnceCell::new();
+
+/// The ws push dropped for the slow client.
+const DROPPED_WS: &str = "ws";
+/// The request refused as busy.
+const DROPPED_BUSY: &str = "busy";
+
+fn dropped(kind: &str) {
+    debug!("RPC message dropped: {}", kind);
+    if let Some(f) = ON_DROPPED.get() {
+        f(kind);
+    }
+}
+
+/// Push the state update of the channel to its ws subscribers.
+pub fn notify_channel(channel: &str, params: RpcParam) {
+    // no subscribers is ok.
+    let _ = CHANNEL_UPDATES.send((channel_key(channel), params));
+}
+
+/// Unify the channel id, hex with or without 0x and leading zeros.
+fn channel_key(channel: &str) -> String {
+    channel
+        .trim_start_matches("0x")
+        .trim_start_matches("0X")
+        .trim_start_matches('0')
+        .to_lowercase()
+}
 
 pub struct RpcConfig {
     pub addr: SocketAddr,
     pub ws: Option<SocketAddr>,
     pub index: Option<PathBuf>,
+    /// the bearer token of requests, open if none.
+    pub token: Option<String>,
+    /// capacity of the message queues, the messages over it are dropped (ws push) or refused as busy (request).
+    pub capacity: usize,
+    /// called when a message dropped, with the kind `ws` or `busy`.
+    pub dropped: fn(&str),
+}
+
+/// Check the `Authorization: Bearer <token>` of request, always pass if no token configured.
+fn authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    match token {
+        Some(token) => authorization
+            .and_then(|auth| auth.trim().strip_prefix("Bearer "))
+            .map(|auth| auth.trim() == token)
+            .unwrap_or(false),
+        None => true,
+    }
+}
+
+/// Check the binds of JSON-RPC before start, it controls the node and anyone reaching it can
+/// drive the node, so a non-loopback bind must have a token.
+pub fn check_bind(addr: SocketAddr, ws: Option<SocketAddr>, token: Option<&str>) -> Result<()> {
+    for addr in std::iter::once(addr).chain(ws) {
+        if !addr.ip().is_loopback() {
+            if token.is_none() {
+                let msg = format!("P2P JSON-RPC binds on non-loopback {} without token", addr);
+                return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, msg));
+            }
+            warn!("P2P JSON-RPC binds on non-loopback {}, never expose it publicly", addr);
+        }
+    }
+    Ok(())
 }
 
 /// packaging the rpc message. not open to ouside.
 #[derive(Debug)]
 pub struct RpcMessage(pub u64, pub RpcParam, pub bool);
 
-pub fn rpc_channel() -> (Sender<RpcMessage>, Receiver<RpcMessage>) {
-    mpsc::channel(128)
+pub fn rpc_channel(capacity: usize) -> (Sender<RpcMessage>, Receiver<RpcMessage>) {
+    mpsc::channel(capacity.max(1))
 }
 
 pub async fn start(config: RpcConfig, send: Sender<RpcMessage>) -> Result<Sender<RpcMessage>> {
-    let (out_send, out_recv) = rpc_channel();
+    let _ = ON_DROPPED.set(config.dropped);
+    let (out_send, out_recv) = rpc_channel(config.capacity);
 
-    let (self_send, self_recv) = rpc_inner_channel();
+    let (self_send, self_recv) = rpc_inner_channel(config.capacity);
 
     server(self_send, config).await?;
     listen(send, out_recv, self_recv).await?;
@@ -65,13 +138,21 @@ enum RpcInnerMessage {
     Response(RpcParam),
 }
 
-fn rpc_inner_channel() -> (Sender<RpcInnerMessage>, Receiver<RpcInnerMessage>) {
-    mpsc::channel(128)
+fn rpc_inner_channel(capacity: usize) -> (Sender<RpcInnerMessage>, Receiver<RpcInnerMessage>) {
+    mpsc::channel(capacity.max(1))
+}
+
+/// Push the response to the connection without waiting, dropped if its queue is full.
+fn push(sender: &Sender<RpcInnerMessage>, params: RpcParam) {
+    if let Err(TrySendError::Full(_)) = sender.try_send(RpcInnerMessage::Response(params)) {
+        dropped(DROPPED_WS);
+    }
 }
 
 enum FutureResult {
     Out(RpcMessage),
     Stream(RpcInnerMessage),
+    Channel(String, RpcParam),
 }
 
 async fn listen(
@@ -81,33 +162,46 @@ async fn listen(
 ) -> Result<()> {
     tokio::spawn(async move {
         let mut connections: HashMap<u64, (Sender<RpcInnerMessage>, bool)> = HashMap::new();
+        // channel id => subscribed ws connections.
+        let mut subscriptions: HashMap<String, HashSet<u64>> = HashMap::new();
+        let mut updates = CHANNEL_UPDATES.subscribe();
 
         loop {
             let res = select! {
                 v = async { out_recv.recv().await.map(|msg| FutureResult::Out(msg)) } => v,
-                v = async { self_recv.recv().await.map(|msg| FutureResult::Stream(msg)) } => v
+                v = async { self_recv.recv().await.map(|msg| FutureResult::Stream(msg)) } => v,
+                v = async {
+                    loop {
+                        match updates.recv().await {
+                            Ok((channel, params)) => break Some(FutureResult::Channel(channel, params)),
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break None,
+                        }
+                    }
+                } => v
             };
 
             match res {
                 Some(FutureResult::Out(msg)) => {
                     let RpcMessage(id, params, is_ws) = msg;
+                    // never wait the connections, a slow client must not block the others.
                     if is_ws {
                         if id == 0 {
                             // default send to all ws.
                             for (_, (s, iw)) in &connections {
                                 if *iw {
-                                    let _ = s.send(RpcInnerMessage::Response(params.clone())).await;
+                                    push(s, params.clone());
                                 }
                             }
                         } else {
                             if let Some((s, _)) = connections.get(&id) {
-                                let _ = s.send(RpcInnerMessage::Response(params)).await;
+                                push(s, params);
                             }
                         }
                     } else {
                         let s = connections.remove(&id);
                         if s.is_some() {
-                            let _ = s.unwrap().0.send(RpcInnerMessage::Response(params)).await;
+                            push(&s.unwrap().0, params);
                         }
                     }
                 }
@@ -115,22 +209,62 @@ async fn listen(
                     match msg {
                         RpcInnerMessage::Request(uid, params, sender) => {
                             let is_ws = sender.is_none();
+                            if is_ws && params["method"].as_str() == Some(SUBSCRIBE_CHANNEL) {
+                                let id = params["id"].as_u64().unwrap_or(0);
+                                let res = match params["params"][0].as_str() {
+                                    Some(channel) => {
+                                        subscriptions.entry(channel_key(channel)).or_default().insert(uid);
+                                        rpc_response(id, SUBSCRIBE_CHANNEL, RpcParam::from(true))
+                                    }
+                                    None => RpcError::InvalidRequest.json(id),
+                                };
+                                if let Some((s, _)) = connections.get(&uid) {
+                                    push(s, res);
+                                }
+                                continue;
+                            }
                             if !is_ws {
                                 connections.insert(uid, (sender.unwrap(), false));
                             }
-                            send.send(RpcMessage(uid, params, is_ws))
-                                .await
-                                .expect("Rpc to Outside channel closed");
+                            let id = params["id"].as_u64().unwrap_or(0);
+                            match send.try_send(RpcMessage(uid, params, is_ws)) {
+                                Ok(()) => {}
+                                Err(TrySendError::Full(_)) => {
+                                    dropped(DROPPED_BUSY);
+                                    let busy = connections.get(&uid).map(|(s, _)| s.clone());
+                                    if !is_ws {
+                                        connections.remove(&uid);
+                                    }
+                                    if let Some(s) = busy {
+                                        push(&s, RpcError::Busy.json(id));
+                                    }
+                                }
+                                Err(TrySendError::Closed(_)) => panic!("Rpc to Outside channel closed"),
+                            }
                         }
                         RpcInnerMessage::Open(id, sender) => {
                             connections.insert(id, (sender, true));
                         }
                         RpcInnerMessage::Close(id) => {
                             connections.remove(&id);
+                            subscriptions.retain(|_, uids| {
+                                uids.remove(&id);
+                                !uids.is_empty()
+                            });
                         }
                         _ => {} // others not handle
                     }
                 }
+                Some(FutureResult::Channel(channel, params)) => {
+                    if let Some(uids) = subscriptions.get(&channel) {
+                        let res = rpc_response(0, SUBSCRIBE_CHANNEL, params);
+                        for uid in uids {
+                            if let Some((s, _)) = connections.get(uid) {
+                                push(s, res.clone());
+                            }
+                        }
+                    }
+                }
                 None => break,
             }
         }
@@ -142,6 +276,7 @@ async fn listen(
 async fn server(send: Sender<RpcInnerMessage>, config: RpcConfig) -> Result<()> {
     tokio::spawn(http::http_listen(
         config.index.clone(),
+        config.token.clone(),
         send.clone(),
         TcpListener::bind(config.addr).await.map_err(|e| {
             error!("RPC HTTP listen {:?}", e);
@@ -152,6 +287,8 @@ async fn server(send: Sender<RpcInnerMessage>, config: RpcConfig) -> Result<()>
     // ws
     if config.ws.is_some() {
         tokio::spawn(ws::ws_listen(
+            config.capacity,
+            config.token.clone(),
             send,
             TcpListener::bind(config.ws.unwrap()).await.map_err(|e| {
                 error!("RPC WS listen {:?}", e);
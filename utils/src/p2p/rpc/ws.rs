@@ -26,16 +26,28 @@ use std::net::SocketAddr;
 use tokio::{
     net::{TcpListener, TcpStream},
     select,
-    sync::mpsc::Sender,
+    sync::mpsc::{error::TrySendError, Sender},
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        protocol::Message as WsMessage,
+    },
 };
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message as WsMessage};
 
-use super::helper::parse_jsonrpc;
-use super::{rpc_inner_channel, RpcInnerMessage};
+use super::helper::{parse_jsonrpc, RpcError};
+use super::{authorized, dropped, rpc_inner_channel, RpcInnerMessage, DROPPED_BUSY};
 
-pub(super) async fn ws_listen(send: Sender<RpcInnerMessage>, listener: TcpListener) -> Result<()> {
+pub(super) async fn ws_listen(
+    capacity: usize,
+    token: Option<String>,
+    send: Sender<RpcInnerMessage>,
+    listener: TcpListener,
+) -> Result<()> {
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(ws_connection(send.clone(), stream, addr));
+        tokio::spawn(ws_connection(capacity, token.clone(), send.clone(), stream, addr));
     }
 
     Ok(())
@@ -46,15 +58,38 @@ enum FutureResult {
     Stream(WsMessage),
 }
 
-async fn ws_connection(send: Sender<RpcInnerMessage>, raw_stream: TcpStream, addr: SocketAddr) -> Result<()> {
-    let ws_stream = accept_async(raw_stream)
+async fn ws_connection(
+    capacity: usize,
+    token: Option<String>,
+    send: Sender<RpcInnerMessage>,
+    raw_stream: TcpStream,
+    addr: SocketAddr,
+) -> Result<()> {
+    // the browsers cannot set the header of ws, the `?token=` is accepted too.
+    let check = |req: &Request, res: Response| {
+        let header = req.headers().get("authorization").and_then(|h| h.to_str().ok());
+        let query = req
+            .uri()
+            .query()
+            .and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix("token=")))
+            .map(|t| format!("Bearer {}", t));
+        if authorized(token.as_deref(), header) || authorized(token.as_deref(), query.as_deref()) {
+            Ok(res)
+        } else {
+            let err = RpcError::Custom("Invalid or missing bearer token".to_owned()).json(0);
+            let mut res = ErrorResponse::new(Some(err.to_string()));
+            *res.status_mut() = StatusCode::UNAUTHORIZED;
+            Err(res)
+        }
+    };
+    let ws_stream = accept_hdr_async(raw_stream, check)
         .await
         .map_err(|_e| Error::new(ErrorKind::Other, "Accept WebSocket Failure!"))?;
     debug!("DEBUG: WebSocket connection established: {}", addr);
 
     let mut rng = ChaChaRng::from_entropy();
     let id: u64 = rng.next_u64();
-    let (s_send, mut s_recv) = rpc_inner_channel();
+    let (s_send, mut s_recv) = rpc_inner_channel(capacity);
     send.send(RpcInnerMessage::Open(id, s_send))
         .await
         .expect("Ws to Rpc channel closed");
@@ -86,9 +121,16 @@ async fn ws_connection(send: Sender<RpcInnerMessage>, raw_stream: TcpStream, add
                 let msg = msg.to_text().unwrap();
                 match parse_jsonrpc(msg.to_owned()) {
                     Ok(rpc_param) => {
-                        send.send(RpcInnerMessage::Request(id, rpc_param, None))
-                            .await
-                            .expect("Ws to Rpc channel closed");
+                        let rpc_id = rpc_param["id"].as_u64().unwrap_or(0);
+                        match send.try_send(RpcInnerMessage::Request(id, rpc_param, None)) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                dropped(DROPPED_BUSY);
+                                let s = WsMessage::from(RpcError::Busy.json(rpc_id).to_string());
+                                let _ = writer.send(s).await;
+                            }
+                            Err(TrySendError::Closed(_)) => panic!("Ws to Rpc channel closed"),
+                        }
                     }
                     Err((err, id)) => {
                         let s = WsMessage::from(err.json(id).to_string());
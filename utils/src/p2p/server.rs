@@ -16,39 +16,56 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use chrono::prelude::Utc;
 use futures::StreamExt;
 use libp2p::{
     core::either::EitherError,
     identity::Keypair,
-    ping::Failure,
+    ping::{Failure, PingEvent, Success},
     swarm::{handler::ConnectionHandlerUpgrErr, Swarm, SwarmBuilder, SwarmEvent},
     Multiaddr, PeerId,
 };
-use std::{collections::HashMap, error::Error, net::SocketAddr};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 use tokio::{
     select,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{self, Receiver, Sender},
+    time::{interval, timeout},
 };
 
 use super::behaviour::{
     behaviour,
     group::{GroupEvent, GroupId, GroupMessage},
-    rpc::{Request, RequestId, Response, RpcEvent, RpcMessage as NetworkRpcMessage},
+    rpc::{OutboundFailure, Request, RequestId, Response, RpcEvent, RpcMessage as NetworkRpcMessage},
     Behaviour, Event as NetworkEvent,
 };
 use super::handler::init_rpc_handler;
+use super::router::dispatch;
 use super::rpc::{
+    check_bind,
     helper::{rpc_error, rpc_response, RpcParam},
     rpc_channel, start as rpc_start, RpcConfig, RpcMessage,
 };
 use super::P2pHandler;
 
-pub async fn server<T: P2pHandler>(
+/// The first delay of redialing a persistent address, doubled after every failure.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+/// The max delay of redialing a persistent address.
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+pub async fn server<T: P2pHandler + 'static>(
     p2p_addr: Multiaddr,
     rpc_addr: SocketAddr,
     ws_addr: Option<SocketAddr>,
+    rpc_token: Option<String>,
     _channel: Option<(Sender<ChannelMessage>, Receiver<ChannelMessage>)>,
     key: Keypair,
+    bootstrap: Vec<Multiaddr>,
 ) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
     let peer_id = PeerId::from(key.public());
     info!("Local peer id: {:?}", peer_id);
@@ -65,11 +82,16 @@ pub async fn server<T: P2pHandler>(
     // DEBUG auto join subquery
     swarm.behaviour_mut().group.join(GroupId::new("subquery"));
 
-    let (out_send, mut out_recv) = rpc_channel();
+    check_bind(rpc_addr, ws_addr, rpc_token.as_deref())?;
+
+    let (out_send, mut out_recv) = rpc_channel(T::rpc_capacity());
     let rpc_config = RpcConfig {
         addr: rpc_addr,
         ws: ws_addr,
         index: None,
+        token: rpc_token,
+        capacity: T::rpc_capacity(),
+        dropped: T::rpc_dropped,
     };
     let rpc_send = rpc_start(rpc_config, out_send).await.unwrap();
     let rpc_handler = init_rpc_handler();
@@ -77,9 +99,26 @@ pub async fn server<T: P2pHandler>(
     // store the sync requests. request_id => (rpc_id, is_ws)
     let mut sync_requests: HashMap<RequestId, (u64, bool)> = HashMap::new();
 
+    // the inbound requests handled in background, and the in-flight count of peers.
+    let (handled_send, mut handled_recv) = mpsc::channel::<(PeerId, RequestId, Response)>(1024);
+    let mut inflight = Inflight::default();
+
+    // the addresses redialed automatically until connected, and again after disconnected.
+    let mut persistent: HashMap<Multiaddr, Persistent> = HashMap::new();
+    for addr in bootstrap {
+        redial(&mut swarm, &addr, persistent.entry(addr.clone()).or_default());
+    }
+    let mut reconnect_tick = interval(RECONNECT_MIN);
+    // the last ping rtt of connected peers.
+    let mut rtts: HashMap<PeerId, Duration> = HashMap::new();
+
     loop {
         let res = select! {
+            _ = reconnect_tick.tick() => FutureResult::Reconnect,
             v = async { out_recv.recv().await.map(|rpc| FutureResult::Rpc(rpc)) } => v.unwrap(),
+            v = async {
+                handled_recv.recv().await.map(|(peer, rid, res)| FutureResult::Handled(peer, rid, res))
+            } => v.unwrap(), // safe, the sender is kept in loop.
             v = async {
                 let event = swarm.select_next_some().await;
                 FutureResult::P2p(event)
@@ -91,13 +130,69 @@ pub async fn server<T: P2pHandler>(
                 SwarmEvent::NewListenAddr { address, .. } => {
                     debug!("P2P Listening on {:?}", address);
                 }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    if let Some(p) = persistent.get_mut(endpoint.get_remote_address()) {
+                        p.peer = Some(peer_id);
+                        p.backoff = RECONNECT_MIN;
+                    }
+                    report_peers::<T>(&swarm);
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established,
+                    ..
+                } => {
+                    if num_established == 0 {
+                        for p in persistent.values_mut().filter(|p| p.peer == Some(peer_id)) {
+                            p.peer = None;
+                            p.next = Instant::now();
+                        }
+                        if rtts.remove(&peer_id).is_some() {
+                            T::ping_changed(&peer_id, None);
+                        }
+                    }
+                    report_peers::<T>(&swarm);
+                }
+                SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                    debug!("Dial {:?} failure: {}", peer_id, error);
+                }
                 SwarmEvent::Behaviour(event) => match event {
                     NetworkEvent::Rpc(msg) => match msg {
-                        RpcEvent::Message { peer: _, message } => match message {
+                        RpcEvent::Message { peer, message } => match message {
                             NetworkRpcMessage::Request { request_id, request } => {
                                 debug!("Got request: {:?}", request);
-                                let res = T::request(request).await;
-                                let _ = swarm.behaviour_mut().rpc.response(request_id, res);
+                                if request.deadline().map(|d| d < Utc::now().timestamp()).unwrap_or(false) {
+                                    // the requester gave up already, not worth the upstream query.
+                                    let res = Response::Error("deadline exceeded".to_owned());
+                                    let _ = swarm.behaviour_mut().rpc.response(request_id, res);
+                                } else if !inflight.acquire(peer, T::max_inflight()) {
+                                    let res = Response::Error("too many concurrent requests".to_owned());
+                                    let _ = swarm.behaviour_mut().rpc.response(request_id, res);
+                                } else {
+                                    let sender = handled_send.clone();
+                                    let budget = handle_budget(&request, T::request_timeout());
+                                    tokio::spawn(async move {
+                                        let mut handle = tokio::spawn(async move {
+                                            match request {
+                                                Request::Custom(method, data) => dispatch(&method, data).await,
+                                                request => T::request(request).await,
+                                            }
+                                        });
+                                        // a stuck or panicked handler must not hold the in-flight slot forever.
+                                        let res = match timeout(budget, &mut handle).await {
+                                            Ok(Ok(res)) => res,
+                                            Ok(Err(err)) => {
+                                                warn!("P2P request handler failure: {}", err);
+                                                Response::Error("internal error".to_owned())
+                                            }
+                                            Err(_) => {
+                                                handle.abort();
+                                                Response::Error("request timeout".to_owned())
+                                            }
+                                        };
+                                        let _ = sender.send((peer, request_id, res)).await;
+                                    });
+                                }
                             }
                             NetworkRpcMessage::Response { request_id, response } => {
                                 debug!("Got response: {:?}", response);
@@ -107,6 +202,12 @@ pub async fn server<T: P2pHandler>(
                                     Response::StateChannel(infos) => {
                                         rpc_response(0, "state-channel", RpcParam::from(infos))
                                     }
+                                    Response::PaidData {
+                                        data,
+                                        state,
+                                        price,
+                                        next_price,
+                                    } => rpc_response(0, "state-channel", json!([data, state, price, next_price])),
                                 };
 
                                 if let Some((uid, is_ws)) = sync_requests.remove(&request_id) {
@@ -118,23 +219,33 @@ pub async fn server<T: P2pHandler>(
                             }
                         },
                         RpcEvent::OutboundFailure {
-                            peer: _,
-                            request_id: _,
-                            error: _,
+                            peer,
+                            request_id,
+                            error,
                         } => {
-                            // handle send request/response error.
+                            debug!("Request {} to {} failure: {}", request_id, peer, error);
+                            fail_sync_request(&mut sync_requests, &rpc_send, request_id, &error).await;
                         }
                         RpcEvent::InboundFailure {
-                            peer: _,
-                            request_id: _,
-                            error: _,
+                            peer,
+                            request_id,
+                            error,
                         } => {
-                            // handle receive request/response error.
+                            // inbound ids are counted apart from sync_requests, nothing to clean.
+                            debug!("Inbound request {} from {} failure: {}", request_id, peer, error);
                         }
                         RpcEvent::ResponseSent { peer: _, request_id: _ } => {
                             // handle send response success.
                         }
                     },
+                    NetworkEvent::Ping(PingEvent { peer, result }) => match result {
+                        Ok(Success::Ping { rtt }) => {
+                            rtts.insert(peer, rtt);
+                            T::ping_changed(&peer, Some(rtt));
+                        }
+                        Ok(Success::Pong) => {}
+                        Err(e) => debug!("Ping {} failure: {}", peer, e),
+                    },
                     NetworkEvent::Group(msg) => {
                         match msg {
                             GroupEvent::Message(GroupMessage {
@@ -149,16 +260,29 @@ pub async fn server<T: P2pHandler>(
                             }
                             GroupEvent::Join { peer: _, group: _ } => {
                                 // handle peer join.
+                                report_peers::<T>(&swarm);
                             }
                             GroupEvent::Leave { peer: _, group: _ } => {
                                 // handle per leave.
+                                report_peers::<T>(&swarm);
                             }
                         }
                     }
-                    _ => {}
                 },
                 _ => {}
             },
+            FutureResult::Reconnect => {
+                let now = Instant::now();
+                for (addr, p) in persistent.iter_mut() {
+                    if p.peer.is_none() && p.next <= now {
+                        redial(&mut swarm, addr, p);
+                    }
+                }
+            }
+            FutureResult::Handled(peer, request_id, res) => {
+                inflight.release(&peer);
+                let _ = swarm.behaviour_mut().rpc.response(request_id, res);
+            }
             FutureResult::Rpc(RpcMessage(uid, params, is_ws)) => {
                 if let Ok(mut events) = rpc_handler.handle(params).await {
                     loop {
@@ -170,6 +294,35 @@ pub async fn server<T: P2pHandler>(
                                 Event::Connect(addr) => {
                                     let _ = swarm.dial(addr);
                                 }
+                                Event::ConnectPersistent(addr) => {
+                                    let p = persistent.entry(addr.clone()).or_default();
+                                    if p.peer.is_none() {
+                                        redial(&mut swarm, &addr, p);
+                                    }
+                                }
+                                Event::Disconnect(pid) => {
+                                    let _ = swarm.disconnect_peer_id(pid);
+                                }
+                                Event::PeerStatus(pid) => {
+                                    let rpc = &swarm.behaviour().rpc;
+                                    let status = if rpc.is_connected(&pid) {
+                                        "connected"
+                                    } else if rpc.is_pending(&pid) {
+                                        "pending"
+                                    } else {
+                                        "disconnected"
+                                    };
+                                    let res = rpc_response(0, "peer-status", RpcParam::from(status));
+                                    let _ = rpc_send.send(RpcMessage(uid, res, is_ws)).await;
+                                }
+                                Event::PingStatus => {
+                                    let peers: Vec<RpcParam> = rtts
+                                        .iter()
+                                        .map(|(pid, rtt)| json!({ "peer": pid.to_base58(), "rtt": rtt.as_millis() as u64 }))
+                                        .collect();
+                                    let res = rpc_response(0, "ping-status", RpcParam::from(peers));
+                                    let _ = rpc_send.send(RpcMessage(uid, res, is_ws)).await;
+                                }
                                 Event::Request(pid, req) => {
                                     let req_id = swarm.behaviour_mut().rpc.request(pid, req);
                                     let res = rpc_response(0, "request", RpcParam::from(req_id));
@@ -179,15 +332,36 @@ pub async fn server<T: P2pHandler>(
                                     let req_id = swarm.behaviour_mut().rpc.request(pid, req);
                                     sync_requests.insert(req_id, (uid, is_ws));
                                 }
+                                Event::Cancel(pid, rid) => {
+                                    let cancelled = swarm.behaviour_mut().rpc.cancel_request(&pid, rid);
+                                    let res = rpc_response(0, "cancel", RpcParam::from(cancelled));
+                                    let _ = rpc_send.send(RpcMessage(uid, res, is_ws)).await;
+                                }
                                 Event::Response(rid, res) => {
                                     let _ = swarm.behaviour_mut().rpc.response(rid, res);
                                 }
                                 Event::GroupJoin(gid) => {
                                     let _ = swarm.behaviour_mut().group.join(gid);
+                                    report_peers::<T>(&swarm);
                                 }
                                 Event::GroupLeave(gid) => {
+                                    T::group_changed(&gid, 0);
                                     let _ = swarm.behaviour_mut().group.leave(gid);
                                 }
+                                Event::GroupList => {
+                                    let groups: Vec<RpcParam> = swarm
+                                        .behaviour()
+                                        .group
+                                        .groups()
+                                        .into_iter()
+                                        .map(|(gid, peers)| {
+                                            let peers: Vec<String> = peers.iter().map(|p| p.to_base58()).collect();
+                                            json!({ "group": gid.id(), "peers": peers })
+                                        })
+                                        .collect();
+                                    let res = rpc_response(0, "group-list", RpcParam::from(groups));
+                                    let _ = rpc_send.send(RpcMessage(uid, res, is_ws)).await;
+                                }
                                 Event::GroupBroadcast(gid, data) => {
                                     let _ = swarm.behaviour_mut().group.broadcast(gid, data);
                                 }
@@ -208,8 +382,96 @@ pub async fn server<T: P2pHandler>(
     }
 }
 
+/// Report the connected peers and the sizes of joined groups to the handler.
+fn report_peers<T: P2pHandler>(swarm: &Swarm<Behaviour>) {
+    T::peers_changed(swarm.connected_peers().count());
+    for (gid, peers) in swarm.behaviour().group.groups() {
+        T::group_changed(&gid, peers.len());
+    }
+}
+
+/// Drop the failed request from the sync requests, and send the failure to its waiting caller.
+async fn fail_sync_request(
+    sync_requests: &mut HashMap<RequestId, (u64, bool)>,
+    rpc_send: &Sender<RpcMessage>,
+    request_id: RequestId,
+    error: &OutboundFailure,
+) {
+    if let Some((uid, is_ws)) = sync_requests.remove(&request_id) {
+        let res = rpc_error(0, &error.to_string());
+        let _ = rpc_send.send(RpcMessage(uid, res, is_ws)).await;
+    }
+}
+
+/// The time budget of handling the inbound request, bounded by the timeout and the request's deadline.
+fn handle_budget(request: &Request, max_secs: u64) -> Duration {
+    let max = Duration::from_secs(max_secs);
+    match request.deadline() {
+        Some(deadline) => {
+            let left = (deadline - Utc::now().timestamp()).max(0) as u64;
+            std::cmp::min(max, Duration::from_secs(left))
+        }
+        None => max,
+    }
+}
+
+/// Dial the persistent address, and schedule the next retry with exponential backoff.
+fn redial(swarm: &mut Swarm<Behaviour>, addr: &Multiaddr, p: &mut Persistent) {
+    debug!("Dial persistent address {} (backoff {:?})", addr, p.backoff);
+    if let Err(err) = swarm.dial(addr.clone()) {
+        debug!("Dial {} failure: {}", addr, err);
+    }
+    p.next = Instant::now() + p.backoff;
+    p.backoff = std::cmp::min(p.backoff * 2, RECONNECT_MAX);
+}
+
+/// The in-flight count of the inbound requests of peers.
+#[derive(Default)]
+struct Inflight(HashMap<PeerId, usize>);
+
+impl Inflight {
+    /// Take a slot of the peer, false if the peer has `limit` requests in flight.
+    fn acquire(&mut self, peer: PeerId, limit: usize) -> bool {
+        if self.0.get(&peer).copied().unwrap_or(0) >= limit {
+            return false;
+        }
+        *self.0.entry(peer).or_insert(0) += 1;
+        true
+    }
+
+    /// Free a slot of the peer when its request handled.
+    fn release(&mut self, peer: &PeerId) {
+        if let Some(count) = self.0.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.0.remove(peer);
+            }
+        }
+    }
+}
+
+/// The reconnection state of a persistent address.
+struct Persistent {
+    /// the connected peer of this address.
+    peer: Option<PeerId>,
+    backoff: Duration,
+    next: Instant,
+}
+
+impl Default for Persistent {
+    fn default() -> Self {
+        Persistent {
+            peer: None,
+            backoff: RECONNECT_MIN,
+            next: Instant::now(),
+        }
+    }
+}
+
 enum FutureResult {
+    Reconnect,
     Rpc(RpcMessage),
+    Handled(PeerId, RequestId, Response),
     P2p(
         SwarmEvent<
             NetworkEvent,
@@ -224,14 +486,70 @@ enum FutureResult {
 pub enum Event {
     Rpc(RpcParam),
     Connect(Multiaddr),
+    ConnectPersistent(Multiaddr),
+    Disconnect(PeerId),
+    PeerStatus(PeerId),
+    PingStatus,
     Request(PeerId, Request),
     RequestSync(PeerId, Request),
+    Cancel(PeerId, RequestId),
     Response(RequestId, Response),
     GroupJoin(GroupId),
     GroupLeave(GroupId),
+    GroupList,
     GroupBroadcast(GroupId, Vec<u8>),
     GroupAddNode(GroupId, PeerId),
     GroupDelNode(GroupId, PeerId),
 }
 
 pub struct ChannelMessage(u64, Event);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_inflight_requests_per_peer() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let mut inflight = Inflight::default();
+
+        // the concurrent requests of one peer past the limit are refused.
+        for _ in 0..3 {
+            assert!(inflight.acquire(peer, 3));
+        }
+        assert!(!inflight.acquire(peer, 3));
+        assert!(inflight.acquire(other, 3));
+
+        // the handled request frees its slot.
+        inflight.release(&peer);
+        assert!(inflight.acquire(peer, 3));
+        assert!(!inflight.acquire(peer, 3));
+
+        for _ in 0..3 {
+            inflight.release(&peer);
+        }
+        assert!(!inflight.0.contains_key(&peer));
+        inflight.release(&peer);
+        assert_eq!(inflight.0.get(&other), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn notify_the_caller_of_failed_sync_request() {
+        let (rpc_send, mut rpc_recv) = rpc_channel(8);
+        let mut sync_requests: HashMap<RequestId, (u64, bool)> = HashMap::new();
+        sync_requests.insert(1, (11, false));
+        sync_requests.insert(2, (12, true));
+
+        fail_sync_request(&mut sync_requests, &rpc_send, 1, &OutboundFailure::Timeout).await;
+        assert_eq!(sync_requests.len(), 1);
+        assert!(!sync_requests.contains_key(&1));
+        let RpcMessage(uid, res, is_ws) = rpc_recv.try_recv().unwrap();
+        assert_eq!((uid, is_ws), (11, false));
+        assert_eq!(res["error"]["message"], OutboundFailure::Timeout.to_string());
+
+        // the async request (not waited by a caller) has nothing to notify.
+        fail_sync_request(&mut sync_requests, &rpc_send, 3, &OutboundFailure::ConnectionClosed).await;
+        assert_eq!(sync_requests.len(), 1);
+        assert!(rpc_recv.try_recv().is_err());
+    }
+}
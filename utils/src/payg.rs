@@ -22,29 +22,55 @@ use rand_chacha::{
     rand_core::{RngCore, SeedableRng},
     ChaChaRng,
 };
-use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use web3::{
     contract::tokens::Tokenizable,
-    ethabi::encode,
+    ethabi::{encode, Token},
     signing::{keccak256, recover, Key, SecretKeyRef, Signature},
-    types::{Address, H256, U256},
+    types::{Address, BlockNumber, Bytes, TransactionParameters, H256, U256, U64},
+    Transport, Web3,
 };
 
 use crate::error::Error;
 
+/// State channel contract functions, the state is `(channelId, isFinal, count, price, indexerSign, consumerSign)`.
+const CHECKPOINT_FN: &str = "checkpoint((uint256,bool,uint256,uint256,bytes,bytes))";
+const CHALLENGE_FN: &str = "challenge((uint256,bool,uint256,uint256,bytes,bytes))";
+const RESPOND_FN: &str = "respond((uint256,bool,uint256,uint256,bytes,bytes))";
+const CLAIM_FN: &str = "claim(uint256)";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct OpenState {
+    #[serde(with = "json::hex_u256")]
     pub channel_id: U256,
+    #[serde(with = "json::address")]
     pub indexer: Address,
+    #[serde(with = "json::address")]
     pub consumer: Address,
+    #[serde(with = "json::dec_u256")]
     pub amount: U256,
+    #[serde(with = "json::dec_u256")]
     pub expiration: U256,
+    #[serde(with = "json::deployment")]
     pub deployment_id: [u8; 32],
+    #[serde(with = "json::bytes")]
     pub callback: Vec<u8>,
+    #[serde(with = "json::sign")]
     pub indexer_sign: Signature,
+    #[serde(with = "json::sign")]
     pub consumer_sign: Signature,
+    #[serde(with = "json::dec_u256")]
     pub next_price: U256,
 }
 
+/// Derive the channel id from consumer and nonce, the same inputs always get the same id.
+pub fn derive_channel_id(consumer: Address, nonce: U256) -> U256 {
+    let msg = encode(&[consumer.into_token(), nonce.into_token()]);
+    U256::from_big_endian(&keccak256(&msg))
+}
+
 impl OpenState {
     pub fn consumer_generate(
         channel_id: Option<U256>,
@@ -83,26 +109,34 @@ impl OpenState {
     }
 
     pub fn recover(&self) -> Result<(Address, Address), Error> {
-        let msg = encode(&[
-            self.channel_id.into_token(),
-            self.indexer.into_token(),
-            self.consumer.into_token(),
-            self.amount.into_token(),
-            self.expiration.into_token(),
-            self.deployment_id.into_token(),
-            self.callback.clone().into_token(),
-        ]);
-        let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
-        bytes.extend(keccak256(&msg));
-        let payload = keccak256(&bytes);
-        let (i_sign, i_id) = convert_recovery_sign(&self.indexer_sign);
-        let (c_sign, c_id) = convert_recovery_sign(&self.consumer_sign);
-        let indexer = recover(&payload, &i_sign, i_id).map_err(|_| Error::InvalidSignature)?;
-        let consumer = recover(&payload, &c_sign, c_id).map_err(|_| Error::InvalidSignature)?;
+        let payload = self.payload();
+        let indexer = recover_signer(&payload, &self.indexer_sign)?;
+        let consumer = recover_signer(&payload, &self.consumer_sign)?;
         Ok((indexer, consumer))
     }
 
+    /// Recover the consumer signer only, indexer maybe not signed yet.
+    pub fn recover_consumer(&self) -> Result<Address, Error> {
+        recover_signer(&self.payload(), &self.consumer_sign)
+    }
+
+    pub fn recover_indexer(&self) -> Result<Address, Error> {
+        recover_signer(&self.payload(), &self.indexer_sign)
+    }
+
     pub fn sign(&mut self, key: SecretKeyRef, is_consumer: bool) -> Result<(), Error> {
+        let payload = self.payload();
+        let sign = normalize_sign(key.sign_message(&payload).map_err(|_| Error::InvalidSignature)?);
+        if is_consumer {
+            self.consumer_sign = sign;
+        } else {
+            self.indexer_sign = sign;
+        }
+        Ok(())
+    }
+
+    /// The signed message payload of state.
+    pub fn payload(&self) -> [u8; 32] {
         let msg = encode(&[
             self.channel_id.into_token(),
             self.indexer.into_token(),
@@ -114,90 +148,37 @@ impl OpenState {
         ]);
         let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
         bytes.extend(keccak256(&msg));
-        let payload = keccak256(&bytes);
-        let sign = key.sign_message(&payload).map_err(|_| Error::InvalidSignature)?;
-        if is_consumer {
-            self.consumer_sign = sign;
-        } else {
-            self.indexer_sign = sign;
-        }
-        Ok(())
+        keccak256(&bytes)
     }
 
     pub fn from_json(params: &Value) -> Result<Self, Error> {
-        let channel_id: U256 = params["channelId"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let indexer: Address = params["indexer"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let consumer: Address = params["consumer"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let amount = U256::from_dec_str(params["amount"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let expiration = U256::from_dec_str(params["expiration"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let deployment = hex::decode(params["deploymentId"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        if deployment.len() != 32 {
-            return Err(Error::InvalidSerialize);
-        }
-        let mut deployment_id = [0u8; 32];
-        deployment_id.copy_from_slice(&deployment);
-        let callback = hex::decode(params["callback"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let indexer_sign: Signature =
-            convert_string_to_sign(params["indexerSign"].as_str().ok_or(Error::InvalidSerialize)?);
-        let consumer_sign: Signature =
-            convert_string_to_sign(params["consumerSign"].as_str().ok_or(Error::InvalidSerialize)?);
-        let next_price = U256::from_dec_str(params["nextPrice"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        Ok(Self {
-            channel_id,
-            indexer,
-            consumer,
-            amount,
-            expiration,
-            deployment_id,
-            callback,
-            indexer_sign,
-            consumer_sign,
-            next_price,
-        })
+        Self::deserialize(params).map_err(|_e| Error::InvalidSerialize)
     }
 
     pub fn to_json(&self) -> Value {
-        json!({
-            "channelId": format!("{:#X}", self.channel_id),
-            "indexer": format!("{:?}", self.indexer),
-            "consumer": format!("{:?}", self.consumer),
-            "amount": self.amount.to_string(),
-            "expiration": self.expiration.to_string(),
-            "deploymentId": hex::encode(&self.deployment_id),
-            "callback": hex::encode(&self.callback),
-            "indexerSign": convert_sign_to_string(&self.indexer_sign),
-            "consumerSign": convert_sign_to_string(&self.consumer_sign),
-            "nextPrice": self.next_price.to_string(),
-        })
+        serde_json::to_value(self).unwrap_or_default()
     }
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct QueryState {
+    #[serde(with = "json::hex_u256")]
     pub channel_id: U256,
+    #[serde(with = "json::address")]
     pub indexer: Address,
+    #[serde(with = "json::address")]
     pub consumer: Address,
+    #[serde(with = "json::dec_u256")]
     pub count: U256,
+    #[serde(with = "json::dec_u256")]
     pub price: U256,
     pub is_final: bool,
+    #[serde(with = "json::sign")]
     pub indexer_sign: Signature,
+    #[serde(with = "json::sign")]
     pub consumer_sign: Signature,
+    #[serde(with = "json::dec_u256")]
     pub next_price: U256,
 }
 
@@ -227,6 +208,34 @@ impl QueryState {
     }
 
     pub fn recover(&self) -> Result<(Address, Address), Error> {
+        let payload = self.payload();
+        let indexer = recover_signer(&payload, &self.indexer_sign)?;
+        let consumer = recover_signer(&payload, &self.consumer_sign)?;
+        Ok((indexer, consumer))
+    }
+
+    /// Recover the consumer signer only, indexer maybe not signed yet.
+    pub fn recover_consumer(&self) -> Result<Address, Error> {
+        recover_signer(&self.payload(), &self.consumer_sign)
+    }
+
+    pub fn recover_indexer(&self) -> Result<Address, Error> {
+        recover_signer(&self.payload(), &self.indexer_sign)
+    }
+
+    pub fn sign(&mut self, key: SecretKeyRef, is_consumer: bool) -> Result<(), Error> {
+        let payload = self.payload();
+        let sign = normalize_sign(key.sign_message(&payload).map_err(|_| Error::InvalidSignature)?);
+        if is_consumer {
+            self.consumer_sign = sign;
+        } else {
+            self.indexer_sign = sign;
+        }
+        Ok(())
+    }
+
+    /// The signed message payload of state.
+    pub fn payload(&self) -> [u8; 32] {
         let msg = encode(&[
             self.channel_id.into_token(),
             self.count.into_token(),
@@ -235,96 +244,110 @@ impl QueryState {
         ]);
         let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
         bytes.extend(keccak256(&msg));
-        let payload = keccak256(&bytes);
-        let (i_sign, i_id) = convert_recovery_sign(&self.indexer_sign);
-        let (c_sign, c_id) = convert_recovery_sign(&self.consumer_sign);
-        let indexer = recover(&payload, &i_sign, i_id).map_err(|_| Error::InvalidSignature)?;
-        let consumer = recover(&payload, &c_sign, c_id).map_err(|_| Error::InvalidSignature)?;
-        Ok((indexer, consumer))
+        keccak256(&bytes)
     }
 
-    pub fn sign(&mut self, key: SecretKeyRef, is_consumer: bool) -> Result<(), Error> {
-        let msg = encode(&[
+    /// The state tuple of the state channel contract functions.
+    pub fn to_token(&self) -> Token {
+        Token::Tuple(vec![
             self.channel_id.into_token(),
+            self.is_final.into_token(),
             self.count.into_token(),
             self.price.into_token(),
-            self.is_final.into_token(),
+            convert_sign_to_bytes(&self.indexer_sign).into_token(),
+            convert_sign_to_bytes(&self.consumer_sign).into_token(),
+        ])
+    }
+
+    pub fn from_json(params: &Value) -> Result<Self, Error> {
+        Self::deserialize(params).map_err(|_e| Error::InvalidSerialize)
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// The indexer signed receipt of a served query, the consumer keeps it as proof-of-service.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Receipt {
+    #[serde(rename = "deploymentId")]
+    pub deployment: String,
+    #[serde(with = "json::hex_u256")]
+    pub channel_id: U256,
+    #[serde(with = "json::dec_u256")]
+    pub count: U256,
+    /// keccak256 of the response data as sent (the serialized query result).
+    #[serde(with = "json::hash")]
+    pub response_hash: H256,
+    #[serde(with = "json::sign")]
+    pub indexer_sign: Signature,
+}
+
+impl Receipt {
+    pub fn indexer_generate(
+        deployment: &str,
+        channel_id: U256,
+        count: U256,
+        response_hash: H256,
+        key: SecretKeyRef,
+    ) -> Result<Self, Error> {
+        let mut receipt = Self {
+            deployment: deployment.to_owned(),
+            channel_id,
+            count,
+            response_hash,
+            indexer_sign: default_sign(),
+        };
+        let payload = receipt.payload();
+        receipt.indexer_sign = normalize_sign(key.sign_message(&payload).map_err(|_| Error::InvalidSignature)?);
+        Ok(receipt)
+    }
+
+    pub fn recover(&self) -> Result<Address, Error> {
+        recover_signer(&self.payload(), &self.indexer_sign)
+    }
+
+    /// The signed message payload of receipt.
+    pub fn payload(&self) -> [u8; 32] {
+        let msg = encode(&[
+            self.deployment.clone().into_token(),
+            self.channel_id.into_token(),
+            self.count.into_token(),
+            self.response_hash.into_token(),
         ]);
         let mut bytes = "\x19Ethereum Signed Message:\n32".as_bytes().to_vec();
         bytes.extend(keccak256(&msg));
-        let payload = keccak256(&bytes);
-        let sign = key.sign_message(&payload).map_err(|_| Error::InvalidSignature)?;
-        if is_consumer {
-            self.consumer_sign = sign;
-        } else {
-            self.indexer_sign = sign;
-        }
-        Ok(())
+        keccak256(&bytes)
     }
 
     pub fn from_json(params: &Value) -> Result<Self, Error> {
-        let channel_id: U256 = params["channelId"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let indexer: Address = params["indexer"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let consumer: Address = params["consumer"]
-            .as_str()
-            .ok_or(Error::InvalidSerialize)?
-            .parse()
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let count = U256::from_dec_str(params["count"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let price = U256::from_dec_str(params["price"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        let is_final = params["isFinal"].as_bool().ok_or(Error::InvalidSerialize)?;
-        let indexer_sign: Signature =
-            convert_string_to_sign(params["indexerSign"].as_str().ok_or(Error::InvalidSerialize)?);
-        let consumer_sign: Signature =
-            convert_string_to_sign(params["consumerSign"].as_str().ok_or(Error::InvalidSerialize)?);
-        let next_price = U256::from_dec_str(params["nextPrice"].as_str().ok_or(Error::InvalidSerialize)?)
-            .map_err(|_e| Error::InvalidSerialize)?;
-        Ok(Self {
-            channel_id,
-            indexer,
-            consumer,
-            count,
-            price,
-            is_final,
-            indexer_sign,
-            consumer_sign,
-            next_price,
-        })
+        Self::deserialize(params).map_err(|_e| Error::InvalidSerialize)
     }
 
     pub fn to_json(&self) -> Value {
-        json!({
-            "channelId": format!("{:#X}", self.channel_id),
-            "indexer": format!("{:?}", self.indexer),
-            "consumer": format!("{:?}", self.consumer),
-            "count": self.count.to_string(),
-            "price": self.price.to_string(),
-            "isFinal": self.is_final,
-            "indexerSign": convert_sign_to_string(&self.indexer_sign),
-            "consumerSign": convert_sign_to_string(&self.consumer_sign),
-            "nextPrice": self.next_price.to_string(),
-        })
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Verify the receipt is signed by the indexer.
+pub fn verify_receipt(receipt: &Receipt, indexer: Address) -> Result<(), Error> {
+    if receipt.recover()? != indexer {
+        return Err(Error::InvalidSignature);
     }
+    Ok(())
 }
 
-/// Convert eth signature to string.
+/// Convert eth signature to string, the canonical encoding of signature in HTTP and P2P.
 pub fn convert_sign_to_string(sign: &Signature) -> String {
     let bytes = convert_sign_to_bytes(sign);
     hex::encode(&bytes)
 }
 
-/// Convert string to eth signature.
+/// Convert string (hex of r || s || v, `0x` prefix is optional) to eth signature.
 pub fn convert_string_to_sign(s: &str) -> Signature {
+    let s = s.strip_prefix("0x").unwrap_or(s);
     let mut bytes = hex::decode(s).unwrap_or(vec![0u8; 65]); // 32 + 32 + 1
 
     if bytes.len() < 65 {
@@ -353,6 +376,46 @@ pub fn convert_sign_to_bytes(sign: &Signature) -> Vec<u8> {
     bytes
 }
 
+/// Half of the secp256k1 curve order, the s of signature must not exceed it (EIP-2).
+const SECP256K1_HALF_N: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x5d, 0x57, 0x6e,
+    0x73, 0x57, 0xa4, 0x50, 0x1d, 0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0,
+];
+
+/// Check the s of signature is in the lower half order, the on-chain ECDSA rejects the others.
+pub fn is_low_s(sign: &Signature) -> bool {
+    U256::from_big_endian(sign.s.as_bytes()) <= U256::from_big_endian(&SECP256K1_HALF_N)
+}
+
+/// Normalize the signature to low-s, flip the recovery id when s changed.
+pub fn normalize_sign(mut sign: Signature) -> Signature {
+    if is_low_s(&sign) {
+        return sign;
+    }
+    let half_n = U256::from_big_endian(&SECP256K1_HALF_N);
+    let n = half_n * U256::from(2u64) + U256::one();
+    let s = n - U256::from_big_endian(sign.s.as_bytes());
+    let mut bytes = [0u8; 32];
+    s.to_big_endian(&mut bytes);
+    sign.s = H256::from(bytes);
+    sign.v = match sign.v {
+        27 | 0 => sign.v + 1,
+        28 | 1 => sign.v - 1,
+        v if v >= 35 && (v - 35) % 2 == 0 => v + 1,
+        v => v - 1,
+    };
+    sign
+}
+
+/// Recover the signer of payload, reject the high-s signature.
+pub fn recover_signer(payload: &[u8; 32], sign: &Signature) -> Result<Address, Error> {
+    if !is_low_s(sign) {
+        return Err(Error::InvalidSignature);
+    }
+    let (sign, recovery_id) = convert_recovery_sign(sign);
+    recover(payload, &sign, recovery_id).map_err(|_| Error::InvalidSignature)
+}
+
 pub fn convert_recovery_sign(sign: &Signature) -> ([u8; 64], i32) {
     let recovery_id = match sign.v {
         27 => 0,
@@ -369,6 +432,107 @@ pub fn convert_recovery_sign(sign: &Signature) -> ([u8; 64], i32) {
     (signature, recovery_id)
 }
 
+/// The unsigned transaction to checkpoint the state on the state channel contract,
+/// the caller should estimate the gas, sign and send it.
+pub fn build_checkpoint_tx(state: &QueryState, contract: Address) -> TransactionParameters {
+    build_tx(contract, CHECKPOINT_FN, &[state.to_token()])
+}
+
+/// The unsigned transaction to challenge the channel with the state.
+pub fn build_challenge_tx(state: &QueryState, contract: Address) -> TransactionParameters {
+    build_tx(contract, CHALLENGE_FN, &[state.to_token()])
+}
+
+/// The unsigned transaction to respond the challenge with the latest state.
+pub fn build_respond_tx(state: &QueryState, contract: Address) -> TransactionParameters {
+    build_tx(contract, RESPOND_FN, &[state.to_token()])
+}
+
+/// The unsigned transaction to claim the expired or challenged channel.
+pub fn build_claim_tx(channel_id: U256, contract: Address) -> TransactionParameters {
+    build_tx(contract, CLAIM_FN, &[channel_id.into_token()])
+}
+
+/// Blocks of the fee history to estimate the priority fee from.
+const FEE_HISTORY_BLOCKS: u64 = 10;
+
+/// The EIP-1559 (dynamic fee) transaction type.
+const EIP1559_TX_TYPE: u64 = 2;
+
+/// The gas price of transactions, the unset EIP-1559 fees are estimated from the fee history of node.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GasPrice {
+    /// legacy gas price, the EIP-1559 fees are not used if set.
+    pub gas_price: Option<U256>,
+    pub max_fee_per_gas: Option<U256>,
+    pub max_priority_fee_per_gas: Option<U256>,
+}
+
+impl GasPrice {
+    /// Set the gas price of the transaction, falls back to the legacy gas price of node
+    /// if the chain has no fee history (before EIP-1559).
+    pub async fn apply<T: Transport>(&self, web3: &Web3<T>, tx: &mut TransactionParameters) -> web3::Result<()> {
+        if let Some(gas_price) = self.gas_price {
+            tx.gas_price = Some(gas_price);
+            return Ok(());
+        }
+
+        let history = match (self.max_fee_per_gas, self.max_priority_fee_per_gas) {
+            (Some(_), Some(_)) => None,
+            _ => fee_history(web3).await,
+        };
+        let priority = self.max_priority_fee_per_gas.or(history.map(|(_, reward)| reward));
+        let max_fee = self.max_fee_per_gas.or(history.map(|(base_fee, _)| {
+            // room for the base fee rising in the next blocks.
+            base_fee
+                .saturating_mul(U256::from(2u64))
+                .saturating_add(priority.unwrap_or_default())
+        }));
+
+        match max_fee {
+            Some(max_fee) => {
+                tx.transaction_type = Some(U64::from(EIP1559_TX_TYPE));
+                tx.max_fee_per_gas = Some(max_fee);
+                tx.max_priority_fee_per_gas = Some(priority.unwrap_or(max_fee).min(max_fee));
+            }
+            None => tx.gas_price = Some(web3.eth().gas_price().await?),
+        }
+        Ok(())
+    }
+}
+
+/// The (base fee of next block, median priority fee of recent blocks), none if no EIP-1559.
+async fn fee_history<T: Transport>(web3: &Web3<T>) -> Option<(U256, U256)> {
+    let history = web3
+        .eth()
+        .fee_history(U256::from(FEE_HISTORY_BLOCKS), BlockNumber::Latest, Some(vec![50.0]))
+        .await
+        .ok()?;
+    let base_fee = *history.base_fee_per_gas.last()?;
+    if base_fee.is_zero() {
+        return None;
+    }
+    let mut rewards: Vec<U256> = history
+        .reward
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|r| r.first().copied())
+        .collect();
+    rewards.sort();
+    let reward = rewards.get(rewards.len() / 2).copied().unwrap_or_default();
+    Some((base_fee, reward))
+}
+
+fn build_tx(contract: Address, function: &str, params: &[Token]) -> TransactionParameters {
+    let mut data = keccak256(function.as_bytes())[..4].to_vec();
+    data.extend(encode(params));
+    TransactionParameters {
+        to: Some(contract),
+        data: Bytes(data),
+        ..Default::default()
+    }
+}
+
 pub fn default_sign() -> Signature {
     Signature {
         v: 0,
@@ -376,3 +540,337 @@ pub fn default_sign() -> Signature {
         s: H256::from([0u8; 32]),
     }
 }
+
+/// The JSON encoding of state fields, same as the HTTP and P2P wire format.
+mod json {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    fn parse<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| D::Error::custom("invalid value"))
+    }
+
+    /// `0x` hex string of U256, e.g. channel id.
+    pub mod hex_u256 {
+        use super::*;
+        use web3::types::U256;
+
+        pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&format!("{:#X}", value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+            parse(deserializer)
+        }
+    }
+
+    /// Decimal string of U256, e.g. amount and price.
+    pub mod dec_u256 {
+        use super::*;
+        use web3::types::U256;
+
+        pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&value.to_string())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            U256::from_dec_str(&s).map_err(|_| D::Error::custom("invalid decimal"))
+        }
+    }
+
+    /// `0x` hex string of address.
+    pub mod address {
+        use super::*;
+        use web3::types::Address;
+
+        pub fn serialize<S: Serializer>(value: &Address, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&format!("{:?}", value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+            parse(deserializer)
+        }
+    }
+
+    /// `0x` hex string of hash.
+    pub mod hash {
+        use super::*;
+        use web3::types::H256;
+
+        pub fn serialize<S: Serializer>(value: &H256, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&format!("{:?}", value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<H256, D::Error> {
+            parse(deserializer)
+        }
+    }
+
+    /// Hex string of bytes without `0x`.
+    pub mod bytes {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&hex::encode(value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            hex::decode(s).map_err(|_| D::Error::custom("invalid hex"))
+        }
+    }
+
+    /// Hex string of the 32 bytes deployment id without `0x`.
+    pub mod deployment {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(value: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+            bytes::serialize(value, serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+            let bytes = bytes::deserialize(deserializer)?;
+            bytes.try_into().map_err(|_| D::Error::custom("invalid deployment id"))
+        }
+    }
+
+    /// Hex string of signature `r || s || v`.
+    pub mod sign {
+        use super::*;
+        use crate::payg::{convert_sign_to_string, convert_string_to_sign};
+        use web3::signing::Signature;
+
+        pub fn serialize<S: Serializer>(value: &Signature, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&convert_sign_to_string(value))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signature, D::Error> {
+            let s = String::deserialize(deserializer)?;
+            Ok(convert_string_to_sign(&s))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SecretKey;
+
+    /// The fixed keys of tests.
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    /// The high-s twin of the signature, valid for ECDSA but rejected by the contract.
+    fn high_s(sign: &Signature) -> Signature {
+        let n = U256::from_big_endian(&SECP256K1_HALF_N) * U256::from(2u64) + U256::one();
+        let mut s = [0u8; 32];
+        (n - U256::from_big_endian(sign.s.as_bytes())).to_big_endian(&mut s);
+        Signature {
+            r: sign.r,
+            s: H256::from(s),
+            v: match sign.v {
+                0 | 27 => sign.v + 1,
+                _ => sign.v - 1,
+            },
+        }
+    }
+
+    #[test]
+    fn reject_high_s_signature() {
+        let sk = key(0x11);
+        let payload = keccak256(b"low-s");
+        let sign = normalize_sign(SecretKeyRef::new(&sk).sign_message(&payload).unwrap());
+        assert!(is_low_s(&sign));
+        assert_eq!(
+            recover_signer(&payload, &sign).unwrap(),
+            SecretKeyRef::new(&sk).address()
+        );
+
+        let high = high_s(&sign);
+        assert!(!is_low_s(&high));
+        assert!(matches!(recover_signer(&payload, &high), Err(Error::InvalidSignature)));
+
+        // normalized back to the low-s one.
+        let low = normalize_sign(high);
+        assert_eq!((low.r, low.s, low.v), (sign.r, sign.s, sign.v));
+    }
+
+    /// The open state of fixed params, signed by the consumer key.
+    fn open_state() -> OpenState {
+        OpenState::consumer_generate(
+            Some(U256::from(0x1234u64)),
+            Address::from_low_u64_be(0x1de),
+            Address::from_low_u64_be(0xc0),
+            U256::from(100u64),
+            U256::from(1700000000u64),
+            [0xab; 32],
+            vec![1, 2],
+            SecretKeyRef::new(&key(0x22)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn open_state_vectors() {
+        let mut state = open_state();
+        // keccak256("\x19Ethereum Signed Message:\n32" || keccak256(abi.encode(channelId, indexer, consumer,
+        // amount, expiration, deploymentId, callback))), same as the contract.
+        assert_eq!(
+            hex::encode(state.payload()),
+            "0dd1bff6fdc5cc83a6f3465f9a992e56e07d8b16c68fc536b05489c644418c20"
+        );
+
+        state.sign(SecretKeyRef::new(&key(0x11)), false).unwrap();
+        // RFC6979 signatures of the payload (r || s || v, low-s, v in 27/28), as eth_sign of the keys.
+        assert_eq!(
+            convert_sign_to_string(&state.consumer_sign),
+            "9c68a77486b6e55ae551b9b7fd30527a63f5ed59863485e0a5fce05ebb2051b6\
+             43e93289686c7095623d95d16d4803e8b8d9bb5010eca2ad3422ade4b1f3bb961c"
+        );
+        assert_eq!(
+            convert_sign_to_string(&state.indexer_sign),
+            "6112c8a75c112669cd0f1c4f05675b4cb91a8feff081aae9550f353ff8862ddc\
+             0d01c0fdb1f7149b601a5e9d84cb9bd447c6a2cddfaa8e492877a556836cf4da1b"
+        );
+        let indexer = SecretKeyRef::new(&key(0x11)).address();
+        let consumer = SecretKeyRef::new(&key(0x22)).address();
+        assert_eq!(indexer, "0x19e7e376e7c213b7e7e7e46cc70a5dd086daff2a".parse().unwrap());
+        assert_eq!(consumer, "0x1563915e194d8cfba1943570603f7606a3115508".parse().unwrap());
+        assert_eq!(state.recover().unwrap(), (indexer, consumer));
+        assert!(is_low_s(&state.indexer_sign) && is_low_s(&state.consumer_sign));
+
+        // any change of the params is not signed.
+        state.amount = U256::from(101u64);
+        assert_ne!(state.recover().unwrap(), (indexer, consumer));
+    }
+
+    #[test]
+    fn query_state_vectors() {
+        let mut state = QueryState::consumer_generate(
+            U256::from(0x1234u64),
+            Address::from_low_u64_be(0x1de),
+            Address::from_low_u64_be(0xc0),
+            U256::from(3u64),
+            U256::from(10u64),
+            true,
+            SecretKeyRef::new(&key(0x22)),
+        )
+        .unwrap();
+        // keccak256("\x19Ethereum Signed Message:\n32" || keccak256(abi.encode(channelId, count, price, isFinal))).
+        assert_eq!(
+            hex::encode(state.payload()),
+            "bcf44b3a281ed789ea4a1e5f65e163b1a451e3fcdbacab0ddf87ef9164a317f0"
+        );
+
+        state.sign(SecretKeyRef::new(&key(0x11)), false).unwrap();
+        assert_eq!(
+            convert_sign_to_string(&state.consumer_sign),
+            "d028d4473d839876f99d42c9d7e02349fa107ba054f302e78724dc63a73f3c87\
+             6de223beeef58a1489a2e07b73bdbc740117baf20a5b43b971fd95c468d462791b"
+        );
+        assert_eq!(
+            convert_sign_to_string(&state.indexer_sign),
+            "c95d5808d5161bbb9ee0f3e547ad55799dc686125ff6ba6fc1cb6b04d1646a39\
+             6752ffcf1f611fd4da4b06954bc4540e0b64cb8852546a92aa04ccc90a3ce7181b"
+        );
+        let indexer = SecretKeyRef::new(&key(0x11)).address();
+        let consumer = SecretKeyRef::new(&key(0x22)).address();
+        assert_eq!(state.recover().unwrap(), (indexer, consumer));
+        assert_eq!(state.recover_indexer().unwrap(), indexer);
+        assert_eq!(state.recover_consumer().unwrap(), consumer);
+
+        // the next price is not signed, the count is.
+        state.next_price = U256::from(20u64);
+        assert_eq!(state.recover().unwrap(), (indexer, consumer));
+        state.count = U256::from(4u64);
+        assert_ne!(state.recover_consumer().unwrap(), consumer);
+    }
+
+    #[test]
+    fn sign_string_round_trip() {
+        let state = open_state();
+        let consumer = SecretKeyRef::new(&key(0x22)).address();
+        let canonical = convert_sign_to_string(&state.consumer_sign);
+        let (rs, v) = canonical.split_at(128);
+        assert!(v == "1b" || v == "1c", "{}", v);
+        // v of 0/1 is the raw recovery id of the same signature.
+        let raw = format!("{}{:02x}", rs, u8::from_str_radix(v, 16).unwrap() - 27);
+
+        for s in [
+            canonical.clone(),
+            format!("0x{}", canonical),
+            raw.clone(),
+            format!("0x{}", raw),
+        ] {
+            let sign = convert_string_to_sign(&s);
+            assert_eq!(recover_signer(&state.payload(), &sign).unwrap(), consumer, "{}", s);
+            // always back to the canonical string, and stable from then on.
+            let string = convert_sign_to_string(&sign);
+            assert_eq!(string, canonical, "{}", s);
+            assert_eq!(convert_sign_to_string(&convert_string_to_sign(&string)), canonical);
+        }
+    }
+
+    #[test]
+    fn open_state_json_round_trip() {
+        let mut state = open_state();
+        state.sign(SecretKeyRef::new(&key(0x11)), false).unwrap();
+        state.next_price = U256::from(10u64);
+
+        let value = state.to_json();
+        assert_eq!(value["channelId"], "0x1234");
+        assert_eq!(value["indexer"], format!("{:?}", Address::from_low_u64_be(0x1de)));
+        assert_eq!(value["amount"], "100");
+        assert_eq!(value["expiration"], "1700000000");
+        assert_eq!(value["deploymentId"], hex::encode([0xab; 32]));
+        assert_eq!(value["callback"], "0102");
+        assert_eq!(value["consumerSign"], convert_sign_to_string(&state.consumer_sign));
+        assert_eq!(value["nextPrice"], "10");
+
+        let back = OpenState::from_json(&value).unwrap();
+        assert_eq!(back.to_json(), value);
+        assert_eq!(back.payload(), state.payload());
+        assert_eq!(back.recover().unwrap(), state.recover().unwrap());
+
+        let mut missing = value.clone();
+        missing.as_object_mut().unwrap().remove("amount");
+        assert!(matches!(OpenState::from_json(&missing), Err(Error::InvalidSerialize)));
+        let mut invalid = value;
+        invalid["deploymentId"] = "ab".into();
+        assert!(matches!(OpenState::from_json(&invalid), Err(Error::InvalidSerialize)));
+    }
+
+    #[test]
+    fn query_state_json_round_trip() {
+        let state = QueryState::consumer_generate(
+            U256::from(0x1234u64),
+            Address::from_low_u64_be(0x1de),
+            Address::from_low_u64_be(0xc0),
+            U256::from(3u64),
+            U256::from(10u64),
+            false,
+            SecretKeyRef::new(&key(0x22)),
+        )
+        .unwrap();
+
+        let value = state.to_json();
+        assert_eq!(value["channelId"], "0x1234");
+        assert_eq!(value["count"], "3");
+        assert_eq!(value["price"], "10");
+        assert_eq!(value["isFinal"], false);
+
+        let back = QueryState::from_json(&value).unwrap();
+        assert_eq!(back.to_json(), value);
+        assert_eq!(
+            back.recover_consumer().unwrap(),
+            SecretKeyRef::new(&key(0x22)).address()
+        );
+
+        let mut invalid = value;
+        invalid["count"] = 3.into();
+        assert!(matches!(QueryState::from_json(&invalid), Err(Error::InvalidSerialize)));
+    }
+}
@@ -16,6 +16,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::error::Error;
+
 pub const METADATA_QUERY: &str = "query { \
     _metadata \
     { \
@@ -32,3 +37,387 @@ pub const METADATA_QUERY: &str = "query { \
       chain \
     } \
   }";
+
+/// The limits of GraphQL query, 0 means no limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryLimits {
+    /// Max number of aliases.
+    pub max_aliases: usize,
+    /// Max size of the query body in bytes.
+    pub max_bytes: usize,
+    /// Max nesting depth of selection sets.
+    pub max_depth: usize,
+}
+
+/// Validate the GraphQL query before send to upstream.
+pub fn validate_query(query: &Value, limits: &QueryLimits) -> Result<(), Error> {
+    if limits.max_bytes > 0 && query.to_string().len() > limits.max_bytes {
+        return Err(Error::InvalidRequest);
+    }
+    let document = query
+        .get("query")
+        .and_then(|q| q.as_str())
+        .ok_or(Error::InvalidRequest)?;
+    // query nodes are read-only, subscriptions are not served on http.
+    if operation_types(document)
+        .iter()
+        .any(|op| op == "mutation" || op == "subscription")
+    {
+        return Err(Error::InvalidRequest);
+    }
+    if limits.max_depth > 0 && query_depth(document) > limits.max_depth {
+        return Err(Error::InvalidRequest);
+    }
+    if limits.max_aliases > 0 && count_aliases(document) > limits.max_aliases {
+        return Err(Error::TooManyAliases);
+    }
+    Ok(())
+}
+
+/// The max nesting depth of selection sets in GraphQL document, the fragment spreads are resolved
+/// to the depth of their fragments. The object values in arguments are inside parentheses, not counted.
+pub fn query_depth(document: &str) -> usize {
+    let (operations, fragments) = definitions(document);
+    let mut resolved = HashMap::new();
+    operations
+        .iter()
+        .map(|operation| resolved_depth(operation, &fragments, &mut resolved, &mut HashSet::new()))
+        .max()
+        .unwrap_or(0)
+}
+
+/// The selection sets of top-level definition, the max depth and the fragment spreads (name, depth).
+#[derive(Default)]
+struct Definition {
+    depth: usize,
+    spreads: Vec<(String, usize)>,
+}
+
+/// The depth of definition with the spreads replaced by their fragments, a spread at depth `d`
+/// nests the fragment's selection set at the same level, so its depth adds `d - 1`.
+fn resolved_depth<'a>(
+    definition: &'a Definition,
+    fragments: &'a HashMap<String, Definition>,
+    resolved: &mut HashMap<&'a str, usize>,
+    visiting: &mut HashSet<&'a str>,
+) -> usize {
+    let mut depth = definition.depth;
+    for (name, at) in &definition.spreads {
+        let inner = match (resolved.get(name.as_str()), fragments.get_key_value(name)) {
+            (Some(inner), _) => *inner,
+            (None, Some((name, fragment))) if visiting.insert(name) => {
+                let inner = resolved_depth(fragment, fragments, resolved, visiting);
+                visiting.remove(name.as_str());
+                resolved.insert(name, inner);
+                inner
+            }
+            // undefined or cyclic fragment, rejected by upstream.
+            _ => 0,
+        };
+        depth = depth.max((at + inner).saturating_sub(1));
+    }
+    depth
+}
+
+/// Split GraphQL document into the operations and the fragments (by name).
+fn definitions(document: &str) -> (Vec<Definition>, HashMap<String, Definition>) {
+    let mut chars = document.chars().peekable();
+    let (mut braces, mut parens) = (0usize, 0usize);
+    let (mut operations, mut fragments) = (vec![], HashMap::new());
+    let mut current = Definition::default();
+    // the names before the selection set of top-level definition, `fragment Name on Type`.
+    let mut head: Vec<String> = vec![];
+    let mut dots = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                // comment until end of line.
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '"' => {
+                // string value, skip escaped chars.
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' => parens += 1,
+            ')' => parens = parens.saturating_sub(1),
+            _ if parens > 0 => {}
+            '{' => {
+                braces += 1;
+                current.depth = current.depth.max(braces);
+            }
+            '}' => {
+                braces = braces.saturating_sub(1);
+                if braces == 0 {
+                    let definition = std::mem::take(&mut current);
+                    match head.as_slice() {
+                        [keyword, name, ..] if keyword == "fragment" => {
+                            fragments.insert(name.clone(), definition);
+                        }
+                        _ => operations.push(definition),
+                    }
+                    head.clear();
+                }
+            }
+            '.' => {
+                dots += 1;
+                continue;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_alphanumeric() || n == '_' {
+                        name.push(n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if braces == 0 {
+                    head.push(name);
+                } else if dots == 3 && name != "on" {
+                    // `...Name` spread, the `... on Type` inline fragment is counted as is.
+                    current.spreads.push((name, braces));
+                }
+            }
+            c if c.is_whitespace() || c == ',' => continue,
+            _ => {}
+        }
+        dots = 0;
+    }
+
+    (operations, fragments)
+}
+
+/// The types of top-level definitions in GraphQL document, the shorthand `{ ... }` is `query`.
+pub fn operation_types(document: &str) -> Vec<String> {
+    let mut chars = document.chars().peekable();
+    let (mut braces, mut parens) = (0usize, 0usize);
+    let mut types = vec![];
+    let mut expect_type = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                // comment until end of line.
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '"' => {
+                // string value, skip escaped chars.
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '{' => {
+                if braces == 0 && expect_type {
+                    types.push("query".to_owned());
+                }
+                braces += 1;
+                expect_type = false;
+            }
+            '}' => {
+                braces = braces.saturating_sub(1);
+                expect_type = braces == 0;
+            }
+            '(' => parens += 1,
+            ')' => parens = parens.saturating_sub(1),
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(&n) = chars.peek() {
+                    if n.is_alphanumeric() || n == '_' {
+                        name.push(n);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if braces == 0 && parens == 0 && expect_type {
+                    types.push(name);
+                    expect_type = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    types
+}
+
+/// Count the aliases (`alias: field`) in the selection sets of GraphQL document.
+/// The `name: value` in arguments and variable definitions are inside parentheses.
+pub fn count_aliases(document: &str) -> usize {
+    let mut chars = document.chars().peekable();
+    let (mut braces, mut parens, mut count) = (0usize, 0usize, 0usize);
+    let mut last_is_name = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                // comment until end of line.
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '"' => {
+                // string value, skip escaped chars.
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                last_is_name = false;
+                continue;
+            }
+            '{' => braces += 1,
+            '}' => braces = braces.saturating_sub(1),
+            '(' => parens += 1,
+            ')' => parens = parens.saturating_sub(1),
+            ':' => {
+                if last_is_name && braces > 0 && parens == 0 {
+                    count += 1;
+                }
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                while let Some(&n) = chars.peek() {
+                    if n.is_alphanumeric() || n == '_' {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                last_is_name = true;
+                continue;
+            }
+            c if c.is_whitespace() || c == ',' => continue,
+            _ => {}
+        }
+        last_is_name = false;
+    }
+
+    count
+}
+
+/// Copy of the query with the values of `keys` in variables replaced by `***`, for logging.
+pub fn redact_variables(query: &Value, keys: &[String]) -> Value {
+    let mut query = query.clone();
+    if let Some(variables) = query.get_mut("variables") {
+        redact(variables, keys);
+    }
+    query
+}
+
+fn redact(value: &mut Value, keys: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if keys.iter().any(|key| key.eq_ignore_ascii_case(k)) {
+                    *v = Value::String("***".to_owned());
+                } else {
+                    redact(v, keys);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact(v, keys)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn aliased(n: usize) -> Value {
+        let fields: Vec<String> = (0..n).map(|i| format!("a{}: _metadata {{ chain }}", i)).collect();
+        json!({ "query": format!("query {{ {} }}", fields.join(" ")) })
+    }
+
+    #[test]
+    fn reject_alias_heavy_query() {
+        let limits = QueryLimits {
+            max_aliases: 10,
+            ..Default::default()
+        };
+        assert!(validate_query(&aliased(10), &limits).is_ok());
+        assert!(matches!(
+            validate_query(&aliased(11), &limits),
+            Err(Error::TooManyAliases)
+        ));
+        assert!(validate_query(&aliased(1000), &QueryLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn count_aliases_in_selection_sets() {
+        assert_eq!(
+            count_aliases("query { a: _metadata { chain } b: _metadata { chain } }"),
+            2
+        );
+        // arguments, variables, strings and comments are not aliases.
+        assert_eq!(
+            count_aliases(
+                "query Q($first: Int) { # x: y\n items(first: $first, filter: { id: { equalTo: \"a: b\" } }) { id } }"
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn reject_deep_query_in_fragments() {
+        let limits = QueryLimits {
+            max_depth: 4,
+            ..Default::default()
+        };
+        let query = |document: &str| json!({ "query": document });
+        // the same nesting of 5 selection sets, inline or split into fragments.
+        let inline = "query { a { b { c { d { e } } } } }";
+        let fragments = "query { a { ...B } } \
+                         fragment B on T { b { ...C } } \
+                         fragment C on T { c { d { e } } }";
+        assert_eq!(query_depth(inline), 5);
+        assert_eq!(query_depth(fragments), 5);
+        assert!(matches!(
+            validate_query(&query(fragments), &limits),
+            Err(Error::InvalidRequest)
+        ));
+        assert!(validate_query(
+            &query("query { a { ...B } } fragment B on T { b { c { d } } }"),
+            &limits
+        )
+        .is_ok());
+
+        // inline fragments and directives are not spreads, undefined and cyclic spreads not loop.
+        assert_eq!(query_depth("{ a { ... on T { b } ... @include(if: true) { c } } }"), 3);
+        assert_eq!(query_depth("{ a { ...X } }"), 2);
+        assert_eq!(query_depth("{ a { ...A } } fragment A on T { b { ...A } }"), 3);
+    }
+}
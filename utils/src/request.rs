@@ -16,22 +16,114 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use reqwest::{
     header::{CONNECTION, CONTENT_TYPE},
-    Client,
+    Certificate, Client, Response,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use serde_with::skip_serializing_none;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 use crate::{
     constants::{APPLICATION_JSON, AUTHORIZATION, KEEP_ALIVE},
     error::GraphQLServerError,
 };
 
+/// Seconds waiting for the jsonrpc service response.
+const JSONRPC_TIMEOUT: u64 = 30;
+
 pub static REQUEST_CLIENT: Lazy<Client> = Lazy::new(|| reqwest::Client::new());
 
+/// Client for the upstream query services, only trust the pinned CA when configured.
+static UPSTREAM_CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// Pin the CA certificate (PEM) of upstream query services,
+/// connections to upstream presenting other certificates will be refused.
+pub fn init_upstream_ca(pem: &[u8]) -> Result<(), reqwest::Error> {
+    let _ = UPSTREAM_CLIENT.set(pinned_client(pem)?);
+    Ok(())
+}
+
+/// Client only trusting the CA certificate (PEM), not the built-in roots.
+fn pinned_client(pem: &[u8]) -> Result<Client, reqwest::Error> {
+    let cert = Certificate::from_pem(pem)?;
+    Client::builder()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(cert)
+        .build()
+}
+
+/// Default (attempts, deadline) of the upstream request budget.
+static RETRY_BUDGET: OnceCell<(u32, Option<Duration>)> = OnceCell::new();
+
+/// Set the default budget of upstream requests, the total attempts and the seconds of deadline,
+/// deadline 0 is no deadline.
+pub fn init_retry_budget(attempts: u32, deadline: u64) {
+    let deadline = if deadline == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(deadline))
+    };
+    let _ = RETRY_BUDGET.set((attempts.max(1), deadline));
+}
+
+/// The retry budget shared by all the upstream attempts of one request,
+/// bounds the total attempts and the total time of them.
+#[derive(Debug)]
+pub struct RetryBudget {
+    attempts: u32,
+    deadline: Option<Instant>,
+}
+
+impl RetryBudget {
+    pub fn new(attempts: u32, deadline: Option<Duration>) -> Self {
+        RetryBudget {
+            attempts: attempts.max(1),
+            deadline: deadline.map(|d| Instant::now() + d),
+        }
+    }
+
+    /// The remaining time before the deadline, None if no deadline.
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline.map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    fn exhausted(&self) -> bool {
+        self.attempts == 0 || self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Run the request until success, non-retryable error or the budget exhausted.
+    async fn run<T, F, Fut>(&mut self, request: F) -> Result<T, GraphQLServerError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, GraphQLServerError>>,
+    {
+        loop {
+            let res = match self.remaining() {
+                Some(remaining) => tokio::time::timeout(remaining, request())
+                    .await
+                    .unwrap_or_else(|_| Err(GraphQLServerError::Timeout("upstream deadline exceeded".to_owned()))),
+                None => request().await,
+            };
+            self.attempts = self.attempts.saturating_sub(1);
+            match res {
+                Err(e) if e.retryable() && !self.exhausted() => debug!("Upstream attempt failure, retry: {}", e),
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        let (attempts, deadline) = RETRY_BUDGET.get().cloned().unwrap_or((1, None));
+        RetryBudget::new(attempts, deadline)
+    }
+}
+
 #[skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GraphQLQuery {
@@ -46,8 +138,74 @@ pub struct GraphQLQuery {
 
 // Request to graphql service.
 pub async fn graphql_request(uri: &str, query: &Value) -> Result<Value, GraphQLServerError> {
-    let response_result = REQUEST_CLIENT
-        .post(uri)
+    client_graphql_request(&REQUEST_CLIENT, uri, query, &[]).await
+}
+
+// Request to upstream graphql service, use the pinned CA client if configured.
+pub async fn upstream_request(uri: &str, query: &Value) -> Result<Value, GraphQLServerError> {
+    upstream_request_with_headers(uri, query, &[]).await
+}
+
+// Request to upstream graphql service with the extra headers.
+pub async fn upstream_request_with_headers(
+    uri: &str,
+    query: &Value,
+    headers: &[(String, String)],
+) -> Result<Value, GraphQLServerError> {
+    upstream_request_with_budget(uri, query, headers, &mut RetryBudget::default()).await
+}
+
+// Request to upstream graphql service, the attempts are taken from the request's budget.
+pub async fn upstream_request_with_budget(
+    uri: &str,
+    query: &Value,
+    headers: &[(String, String)],
+    budget: &mut RetryBudget,
+) -> Result<Value, GraphQLServerError> {
+    let client = UPSTREAM_CLIENT.get().unwrap_or(&REQUEST_CLIENT);
+    budget.run(|| client_graphql_request(client, uri, query, headers)).await
+}
+
+// Request to upstream graphql service, the body is not read, so it can be streamed.
+pub async fn upstream_response(
+    uri: &str,
+    query: &Value,
+    headers: &[(String, String)],
+) -> Result<Response, GraphQLServerError> {
+    let client = UPSTREAM_CLIENT.get().unwrap_or(&REQUEST_CLIENT);
+    RetryBudget::default()
+        .run(|| client_graphql_response(client, uri, query, headers))
+        .await
+}
+
+async fn client_graphql_request(
+    client: &Client,
+    uri: &str,
+    query: &Value,
+    headers: &[(String, String)],
+) -> Result<Value, GraphQLServerError> {
+    let res = client_graphql_response(client, uri, query, headers).await?;
+    let json_result = res.json().await;
+    let json_data: Value = match json_result {
+        Ok(res) => res,
+        Err(e) => return Err(GraphQLServerError::InternalError(format!("Parse result error:{}", e))),
+    };
+
+    Ok(json_data)
+}
+
+async fn client_graphql_response(
+    client: &Client,
+    uri: &str,
+    query: &Value,
+    headers: &[(String, String)],
+) -> Result<Response, GraphQLServerError> {
+    debug!("GraphQL request to: {}", uri);
+    let mut request = client.post(uri);
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let response_result = request
         .header(CONTENT_TYPE, APPLICATION_JSON)
         .header(CONNECTION, KEEP_ALIVE)
         .body(query.to_string())
@@ -56,16 +214,24 @@ pub async fn graphql_request(uri: &str, query: &Value) -> Result<Value, GraphQLS
 
     let res = match response_result {
         Ok(res) => res,
-        Err(e) => return Err(GraphQLServerError::QueryError(format!("{}", e))),
+        Err(e) => {
+            debug!("GraphQL request failure: {}", e);
+            return Err(GraphQLServerError::QueryError(format!("{}", e)));
+        }
     };
 
-    let json_result = res.json().await;
-    let json_data: Value = match json_result {
-        Ok(res) => res,
-        Err(e) => return Err(GraphQLServerError::InternalError(format!("Parse result error:{}", e))),
-    };
+    let status = res.status();
+    if status.is_client_error() || status.is_server_error() {
+        let body = res.text().await.unwrap_or_default();
+        debug!("GraphQL request status: {}", status);
+        return if status.is_client_error() {
+            Err(GraphQLServerError::BadRequest(body))
+        } else {
+            Err(GraphQLServerError::UpstreamError(body))
+        };
+    }
 
-    Ok(json_data)
+    Ok(res)
 }
 
 // Request to indexer/consumer proxy
@@ -114,8 +280,22 @@ pub async fn proxy_request(
 
 // Request to jsonrpc service.(P2P RPC)
 pub async fn jsonrpc_request(id: u64, url: &str, method: &str, params: Vec<Value>) -> Result<Value, Value> {
-    let res = REQUEST_CLIENT
-        .post(url)
+    jsonrpc_request_with_token(id, url, None, method, params).await
+}
+
+/// Same as `jsonrpc_request`, with the bearer token of the jsonrpc service.
+pub async fn jsonrpc_request_with_token(
+    id: u64,
+    url: &str,
+    token: Option<&str>,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, Value> {
+    let mut req = REQUEST_CLIENT.post(url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let res = req
         .header("content-type", "application/json")
         .json(&json!({
             "jsonrpc": "2.0",
@@ -123,9 +303,14 @@ pub async fn jsonrpc_request(id: u64, url: &str, method: &str, params: Vec<Value
             "method": method,
             "params": params
         }))
+        .timeout(Duration::from_secs(JSONRPC_TIMEOUT))
         .send()
-        .await
-        .unwrap();
+        .await;
+
+    let res = match res {
+        Ok(res) => res,
+        Err(err) => return Err(json!(err.to_string())),
+    };
 
     match res.error_for_status() {
         Ok(res) => match res.json::<Value>().await {
@@ -166,3 +351,191 @@ pub async fn jsonrpc_request(id: u64, url: &str, method: &str, params: Vec<Value
         Err(err) => Err(json!(err.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::handle_rejection;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        ssl::{SslAcceptor, SslMethod},
+        x509::{
+            extension::{BasicConstraints, KeyUsage, SubjectAlternativeName},
+            X509Builder, X509NameBuilder, X509,
+        },
+    };
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use warp::{http::StatusCode, Filter, Reply};
+
+    /// Upstream answers `/bad` with 400 and `/down` with 500, returns the address and the hits.
+    fn upstream() -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let (bad_hits, down_hits) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (bad, down) = (bad_hits.clone(), down_hits.clone());
+        let bad = warp::path!("bad").map(move || {
+            bad.fetch_add(1, Ordering::SeqCst);
+            let body = warp::reply::json(&json!({ "errors": [{ "message": "Syntax Error" }] }));
+            warp::reply::with_status(body, StatusCode::BAD_REQUEST)
+        });
+        let down = warp::path!("down").map(move || {
+            down.fetch_add(1, Ordering::SeqCst);
+            warp::reply::with_status("down", StatusCode::INTERNAL_SERVER_ERROR)
+        });
+        let (addr, server) = warp::serve(bad.or(down)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}", addr), bad_hits, down_hits)
+    }
+
+    /// The self-signed CA of the name, or the certificate of `127.0.0.1` issued by the CA.
+    fn certificate(cn: &str, issuer: Option<&(X509, PKey<Private>)>) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        match issuer {
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .ip("127.0.0.1")
+                    .build(&builder.x509v3_context(Some(ca), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder.set_issuer_name(&name).unwrap();
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder
+                    .append_extension(KeyUsage::new().critical().key_cert_sign().build().unwrap())
+                    .unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    /// Upstream serving TLS with the certificate, answers every request with an empty data.
+    fn tls_upstream(cert: X509, key: PKey<Private>) -> String {
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // the refused clients fail the handshake.
+                let mut stream = match acceptor.accept(stream) {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                // read the whole request before answer, the unread data resets the connection.
+                let mut buf = vec![];
+                let mut chunk = [0u8; 1024];
+                loop {
+                    let size = match stream.read(&mut chunk) {
+                        Ok(0) | Err(_) => break,
+                        Ok(size) => size,
+                    };
+                    buf.extend_from_slice(&chunk[..size]);
+                    let mut headers = [httparse::EMPTY_HEADER; 16];
+                    let mut req = httparse::Request::new(&mut headers);
+                    if let Ok(httparse::Status::Complete(offset)) = req.parse(&buf) {
+                        let length = req
+                            .headers
+                            .iter()
+                            .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                            .and_then(|h| std::str::from_utf8(h.value).ok()?.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if buf.len() >= offset + length {
+                            break;
+                        }
+                    }
+                }
+                let body = r#"{"data":{}}"#;
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.shutdown();
+            }
+        });
+        format!("https://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn refuse_upstream_of_other_ca() {
+        let ca = certificate("Upstream CA", None);
+        let other_ca = certificate("Other CA", None);
+        let (cert, key) = certificate("127.0.0.1", Some(&ca));
+        let url = tls_upstream(cert, key);
+        let query = json!({ "query": "query { _metadata { chain } }" });
+
+        let pinned = pinned_client(&ca.0.to_pem().unwrap()).unwrap();
+        let res = client_graphql_request(&pinned, &url, &query, &[]).await.unwrap();
+        assert_eq!(res, json!({ "data": {} }));
+
+        let mismatched = pinned_client(&other_ca.0.to_pem().unwrap()).unwrap();
+        let err = client_graphql_request(&mismatched, &url, &query, &[])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GraphQLServerError::QueryError(_)), "{}", err);
+    }
+
+    async fn status(err: GraphQLServerError) -> StatusCode {
+        let res = handle_rejection(warp::reject::custom(err), false).await.unwrap();
+        res.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn map_upstream_status_class() {
+        let (url, bad_hits, down_hits) = upstream();
+        let query = json!({ "query": "query { _metadata { chain } }" });
+
+        // 400 is the consumer's fault, not retried and answered 400.
+        let mut budget = RetryBudget::new(3, None);
+        let err = upstream_request_with_budget(&format!("{}/bad", url), &query, &[], &mut budget)
+            .await
+            .unwrap_err();
+        match &err {
+            GraphQLServerError::BadRequest(body) => assert!(body.contains("Syntax Error")),
+            e => panic!("unexpected error: {}", e),
+        }
+        assert!(!err.retryable());
+        assert_eq!(bad_hits.load(Ordering::SeqCst), 1);
+        assert_eq!(status(err).await, StatusCode::BAD_REQUEST);
+
+        // 500 is the upstream fault, retried in the budget and answered 502.
+        let mut budget = RetryBudget::new(3, None);
+        let err = upstream_request_with_budget(&format!("{}/down", url), &query, &[], &mut budget)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, GraphQLServerError::UpstreamError(_)));
+        assert_eq!(down_hits.load(Ordering::SeqCst), 3);
+        assert_eq!(status(err).await, StatusCode::BAD_GATEWAY);
+    }
+}
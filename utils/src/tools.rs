@@ -16,6 +16,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaChaRng,
+};
+
 use crate::traits::Hash;
 
 impl Hash for String {
@@ -23,3 +28,9 @@ impl Hash for String {
         blake3::hash(self.as_bytes()).to_string()
     }
 }
+
+/// Generate a random request id, for correlating the logs of a query.
+pub fn request_id() -> String {
+    let mut rng = ChaChaRng::from_entropy();
+    format!("{:016x}", rng.next_u64())
+}